@@ -0,0 +1,81 @@
+//! `--follow-aliases`: resolve hosts through the CNAME records found in the
+//! same dataset.
+//!
+//! When a dataset holds both PTR records (IP to host) and CNAME records
+//! (alias to target), the host a PTR points at may itself be an alias, and
+//! the domain worth reporting is that of the final target.  The CNAME
+//! records are collected in a first pass over the input.
+
+use crate::{normalize_host, EmptyLabels, RdnsRecord};
+use std::collections::HashMap;
+use std::io::BufRead;
+use vfb_tldextract::TRACE_TARGET;
+
+pub struct Aliases {
+    targets: HashMap<String, String>,
+}
+
+/// Returns whether `record` is an alias record rather than an IP mapping.
+pub fn is_alias(record: &RdnsRecord) -> bool {
+    return record.kind.eq_ignore_ascii_case("cname");
+}
+
+/// Reads every alias record from `rdr`.  Lines that cannot be parsed are
+/// skipped here; the main pass reports them.
+pub fn collect<R: BufRead>(rdr: &mut R, policy: EmptyLabels) -> anyhow::Result<Aliases> {
+    let mut targets = HashMap::new();
+    let mut line = String::with_capacity(4096);
+    loop {
+        line.clear();
+        if rdr.read_line(&mut line)? == 0 {
+            break;
+        }
+        let record: RdnsRecord = match serde_json::from_str(&line) {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+        if !is_alias(&record) {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            normalize_host(&record.name, policy),
+            normalize_host(&record.value, policy),
+        ) {
+            targets.insert(name.into_owned(), value.into_owned());
+        }
+    }
+    return Ok(Aliases { targets });
+}
+
+impl Aliases {
+    /// Follows `host` to the end of its alias chain, returning the final
+    /// target and the number of aliases followed, or `None` if `host` is not
+    /// an alias.  Chains longer than `max_depth` (including loops) are an
+    /// error, whose value is the reject reason.
+    pub fn resolve(
+        &self,
+        host: &str,
+        max_depth: usize,
+        trace: bool,
+    ) -> Result<Option<(&str, usize)>, &'static str> {
+        let mut target = match self.targets.get(host) {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        let mut depth = 1;
+        if trace {
+            log::info!(target: TRACE_TARGET, "trace {}: alias of {}", host, target);
+        }
+        while let Some(next) = self.targets.get(target) {
+            if depth == max_depth {
+                return Err("alias-chain-too-long");
+            }
+            if trace {
+                log::info!(target: TRACE_TARGET, "trace {}: alias of {}", target, next);
+            }
+            target = next;
+            depth += 1;
+        }
+        return Ok(Some((target, depth)));
+    }
+}
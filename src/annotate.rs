@@ -0,0 +1,102 @@
+//! `--annotate-ips` and `--annotate-domains`: what the `--state-db`
+//! database built by earlier runs knows about a list of addresses or
+//! domains.
+//!
+//! Each address or registrable domain is written back as given, followed
+//! by what is known about it separated by spaces, so
+//! `1.2.3.4,example.com example.net` or `1.2.3.5,` for an address no run
+//! has seen.  With `--subdomains`, a domain
+//! gets a `domain,host,addresses` row per host instead.
+
+use crate::fields::quoted;
+use crate::state::StateDb;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+/// The entries of a list file with their line numbers, skipping blank lines
+/// and `#` comments.
+fn entries(path: &Path) -> anyhow::Result<Vec<(usize, String)>> {
+    let rdr = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for (i, line) in rdr.lines().enumerate() {
+        let line = line?;
+        let entry = line.trim();
+        if !entry.is_empty() && !entry.starts_with('#') {
+            entries.push((i + 1, entry.to_string()));
+        }
+    }
+    return Ok(entries);
+}
+
+pub fn ips<W: Write>(ips_file: &Path, state: &StateDb, out: &mut W) -> anyhow::Result<()> {
+    let (mut num_ips, mut num_known) = (0, 0);
+    for (line_no, ip) in entries(ips_file)? {
+        let addr: Ipv4Addr = match ip.parse() {
+            Ok(addr) => addr,
+            Err(_) => anyhow::bail!(
+                "{}:{}: {:?} is not an IPv4 address",
+                ips_file.display(),
+                line_no,
+                ip
+            ),
+        };
+        let domains = state.domains(addr)?;
+        num_ips += 1;
+        if !domains.is_empty() {
+            num_known += 1;
+        }
+        writeln!(out, "{},{}", ip, quoted(&domains.join(" "), b','))?;
+    }
+    out.flush()?;
+    log::info!("{} of {} addresses have known domains", num_known, num_ips);
+    return Ok(());
+}
+
+pub fn domains<W: Write>(
+    domains_file: &Path,
+    state: &StateDb,
+    subdomains: bool,
+    out: &mut W,
+) -> anyhow::Result<()> {
+    let (mut num_domains, mut num_known) = (0, 0);
+    for (_, domain) in entries(domains_file)? {
+        let addrs = state.addrs(&domain)?;
+        num_domains += 1;
+        if !addrs.is_empty() {
+            num_known += 1;
+        }
+        if !subdomains {
+            let addrs: Vec<String> = addrs.iter().map(|(addr, _)| addr.to_string()).collect();
+            writeln!(out, "{},{}", quoted(&domain, b','), addrs.join(" "))?;
+            continue;
+        }
+        let mut by_host: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for (addr, hosts) in &addrs {
+            for host in hosts {
+                by_host.entry(host).or_default().push(addr.to_string());
+            }
+        }
+        if by_host.is_empty() {
+            writeln!(out, "{},,", quoted(&domain, b','))?;
+        }
+        for (host, addrs) in by_host {
+            writeln!(
+                out,
+                "{},{},{}",
+                quoted(&domain, b','),
+                quoted(host, b','),
+                addrs.join(" ")
+            )?;
+        }
+    }
+    out.flush()?;
+    log::info!(
+        "{} of {} domains have known addresses",
+        num_known,
+        num_domains
+    );
+    return Ok(());
+}
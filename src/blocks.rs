@@ -0,0 +1,201 @@
+//! `--format blocks`: ranges of consecutive addresses whose hosts follow
+//! the same template, such as the `host-1-2-3-4.dyn.example.net` records of
+//! an ISP's address pool, collapsed into one row per range.
+//!
+//! The template of a host is the host with each run of digits before its
+//! registrable domain replaced by `#`, so `host-#-#-#-#.dyn.example.net`.  A
+//! run of at least `--min-block` consecutive addresses with the same
+//! template and domain becomes a `start,end,domain,pattern` row; every other
+//! pair is written as a range of one address with an empty pattern.  Rows
+//! are sorted by their first address.
+//!
+//! The input need not be sorted, so every pair is held until the end of the
+//! file.  Only IPv4 addresses are grouped; records of IPv6 ones are
+//! rejected.
+
+use crate::fields::quoted;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use vfb_tldextract::ip::{self, Endianness, IpFormat};
+
+#[derive(Default)]
+pub struct Blocks {
+    /// The addresses seen for each `(template, domain)`.
+    addrs: HashMap<(String, String), Vec<u32>>,
+}
+
+/// The template of `host`, whose last `registrable_len` bytes are its
+/// registrable domain and are kept as they are.
+fn template(host: &str, registrable_len: usize) -> String {
+    let split = host.len().saturating_sub(registrable_len);
+    let (sub, registrable) = host.split_at(split);
+    let mut template = String::with_capacity(host.len());
+    let mut in_digits = false;
+    for c in sub.chars() {
+        if c.is_ascii_digit() {
+            if !in_digits {
+                template.push('#');
+            }
+            in_digits = true;
+        } else {
+            template.push(c);
+            in_digits = false;
+        }
+    }
+    template.push_str(registrable);
+    return template;
+}
+
+impl Blocks {
+    /// Records the pair of `addr` and `domain` seen through `host`, whose
+    /// registrable domain is `label` under `suffix`.
+    pub fn add(&mut self, addr: Ipv4Addr, host: &str, domain: &str, label: &str, suffix: &str) {
+        let registrable_len = if label.is_empty() {
+            suffix.len()
+        } else {
+            label.len() + 1 + suffix.len()
+        };
+        let key = (template(host, registrable_len), domain.to_string());
+        self.addrs.entry(key).or_default().push(u32::from(addr));
+    }
+
+    /// Writes the rows, returning how many ranges of `min_block` or more
+    /// addresses were found.
+    pub fn write<W: Write>(
+        &mut self,
+        out: &mut W,
+        min_block: u64,
+        format: IpFormat,
+        endianness: Endianness,
+        signed: bool,
+    ) -> io::Result<u64> {
+        let mut rows: Vec<(u32, u32, &str, &str)> = Vec::new();
+        let mut num_blocks = 0;
+        for ((template, domain), addrs) in &mut self.addrs {
+            addrs.sort_unstable();
+            addrs.dedup();
+            let mut start = 0;
+            while start < addrs.len() {
+                let mut end = start;
+                while end + 1 < addrs.len() && addrs[end + 1] == addrs[end] + 1 {
+                    end += 1;
+                }
+                if (end - start + 1) as u64 >= min_block {
+                    rows.push((addrs[start], addrs[end], domain, template));
+                    num_blocks += 1;
+                } else {
+                    for &addr in &addrs[start..=end] {
+                        rows.push((addr, addr, domain, ""));
+                    }
+                }
+                start = end + 1;
+            }
+        }
+        rows.sort_unstable();
+        for (start, end, domain, pattern) in rows {
+            let start = ip::ip_value(
+                IpAddr::V4(Ipv4Addr::from(start)),
+                format,
+                endianness,
+                signed,
+            );
+            let end = ip::ip_value(IpAddr::V4(Ipv4Addr::from(end)), format, endianness, signed);
+            writeln!(
+                out,
+                "{},{},{},{}",
+                start,
+                end,
+                quoted(domain, b','),
+                quoted(pattern, b',')
+            )?;
+        }
+        return Ok(num_blocks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(blocks: &mut Blocks, min_block: u64) -> (String, u64) {
+        let mut out = Vec::new();
+        let n = blocks
+            .write(
+                &mut out,
+                min_block,
+                IpFormat::Dotted,
+                Endianness::Big,
+                false,
+            )
+            .unwrap();
+        return (String::from_utf8(out).unwrap(), n);
+    }
+
+    #[test]
+    fn templates() {
+        assert_eq!(
+            template("host-1-2-3-4.dyn.example.net", "example.net".len()),
+            "host-#-#-#-#.dyn.example.net"
+        );
+        // Digits in the registrable domain are kept.
+        assert_eq!(
+            template("a12b.example1.net", "example1.net".len()),
+            "a#b.example1.net"
+        );
+        assert_eq!(template("co.uk", "co.uk".len()), "co.uk");
+        assert_eq!(template("123", 10), "123");
+    }
+
+    #[test]
+    fn consecutive_addresses_collapse() {
+        let mut blocks = Blocks::default();
+        // Out of order, with a duplicate and a gap.
+        for last in [12, 10, 11, 13, 11, 20] {
+            let host = format!("host-192-0-2-{}.dyn.example.net", last);
+            blocks.add(
+                Ipv4Addr::new(192, 0, 2, last),
+                &host,
+                "example",
+                "example",
+                "net",
+            );
+        }
+        blocks.add(
+            Ipv4Addr::new(192, 0, 2, 14),
+            "mail.example.net",
+            "example",
+            "example",
+            "net",
+        );
+        let (out, n) = write(&mut blocks, 3);
+        assert_eq!(
+            out,
+            "192.0.2.10,192.0.2.13,example,host-#-#-#-#.dyn.example.net\n\
+             192.0.2.14,192.0.2.14,example,\n\
+             192.0.2.20,192.0.2.20,example,\n"
+        );
+        assert_eq!(n, 1);
+    }
+
+    #[test]
+    fn short_runs_stay_single() {
+        let mut blocks = Blocks::default();
+        for last in [1, 2] {
+            let host = format!("h{}.example.com", last);
+            blocks.add(
+                Ipv4Addr::new(192, 0, 2, last),
+                &host,
+                "example",
+                "example",
+                "com",
+            );
+        }
+        let (out, n) = write(&mut blocks, 3);
+        assert_eq!(
+            out,
+            "192.0.2.1,192.0.2.1,example,\n192.0.2.2,192.0.2.2,example,\n"
+        );
+        assert_eq!(n, 0);
+    }
+}
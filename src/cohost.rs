@@ -0,0 +1,127 @@
+//! `--format cohosting`: the addresses that many registrable domains point
+//! back to, such as shared hosting and sinkholes, instead of the pairs.
+//!
+//! A row is written for each address with at least `--cohosting-min`
+//! distinct registrable domains (`example.co.uk`, not just `example`), the
+//! busiest first: `ip,count,domains` with the domains sorted and separated
+//! by spaces, or just `ip,count` with `--cohosting-counts`.
+
+use crate::fields::quoted;
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use vfb_tldextract::ip::{self, Endianness, IpFormat};
+
+#[derive(Default)]
+pub struct CoHosting {
+    domains: HashMap<IpAddr, BTreeSet<String>>,
+}
+
+impl CoHosting {
+    /// Records that `addr` points to the domain `label` under `suffix`; the
+    /// label is empty for a suffix-only host.
+    pub fn add(&mut self, addr: IpAddr, label: &str, suffix: &str) {
+        let domain = if label.is_empty() {
+            suffix.to_string()
+        } else {
+            format!("{}.{}", label, suffix)
+        };
+        self.domains.entry(addr).or_default().insert(domain);
+    }
+
+    /// Writes the report, returning how many addresses made it.
+    pub fn write<W: Write>(
+        &self,
+        out: &mut W,
+        min_domains: usize,
+        counts_only: bool,
+        format: IpFormat,
+        endianness: Endianness,
+        signed: bool,
+    ) -> io::Result<u64> {
+        let mut busy: Vec<(&IpAddr, &BTreeSet<String>)> = self
+            .domains
+            .iter()
+            .filter(|(_, domains)| domains.len() >= min_domains)
+            .collect();
+        busy.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(b.0)));
+        for (&addr, domains) in &busy {
+            let ip = ip::ip_value(addr, format, endianness, signed);
+            if counts_only {
+                writeln!(out, "{},{}", ip, domains.len())?;
+            } else {
+                let domains: Vec<&str> = domains.iter().map(String::as_str).collect();
+                writeln!(
+                    out,
+                    "{},{},{}",
+                    ip,
+                    domains.len(),
+                    quoted(&domains.join(" "), b',')
+                )?;
+            }
+        }
+        return Ok(busy.len() as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(cohosting: &CoHosting, min_domains: usize, counts_only: bool) -> (String, u64) {
+        let mut out = Vec::new();
+        let n = cohosting
+            .write(
+                &mut out,
+                min_domains,
+                counts_only,
+                IpFormat::Dotted,
+                Endianness::Big,
+                false,
+            )
+            .unwrap();
+        return (String::from_utf8(out).unwrap(), n);
+    }
+
+    fn cohosting() -> CoHosting {
+        let (a, b, c): (IpAddr, IpAddr, IpAddr) = (
+            "192.0.2.1".parse().unwrap(),
+            "192.0.2.2".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+        );
+        let mut cohosting = CoHosting::default();
+        for (addr, label, suffix) in [
+            (b, "zeta", "com"),
+            (b, "alpha", "co.uk"),
+            (b, "zeta", "com"),
+            (a, "alpha", "com"),
+            (a, "alpha", "net"),
+            (a, "beta", "com"),
+            (c, "", "co.uk"),
+            (c, "alpha", "com"),
+        ] {
+            cohosting.add(addr, label, suffix);
+        }
+        return cohosting;
+    }
+
+    #[test]
+    fn busiest_first_with_sorted_domains() {
+        let (out, n) = report(&cohosting(), 2, false);
+        assert_eq!(
+            out,
+            "192.0.2.1,3,alpha.com alpha.net beta.com\n\
+             192.0.2.2,2,alpha.co.uk zeta.com\n\
+             2001:db8::1,2,alpha.com co.uk\n"
+        );
+        assert_eq!(n, 3);
+    }
+
+    #[test]
+    fn min_domains_and_counts_only() {
+        let (out, n) = report(&cohosting(), 3, true);
+        assert_eq!(out, "192.0.2.1,3\n");
+        assert_eq!(n, 1);
+        assert_eq!(report(&cohosting(), 4, false), (String::new(), 0));
+    }
+}
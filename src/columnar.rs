@@ -0,0 +1,208 @@
+//! `--format parquet`: the fields of the rows as the columns of a Parquet
+//! file, for loading into Spark or DuckDB without a conversion step.  The
+//! IP is an INT32, unsigned unless --ip-signed, or a UTF8 string with
+//! --ip-format dotted, idn-roundtrip a BOOLEAN and the other fields UTF8
+//! strings; the columns are named as in ndjson rows and compressed with
+//! Snappy.
+//!
+//! Rows are gathered a row group (--row-group-size rows) at a time, and
+//! each row group is encoded and written out as soon as it is full, so that
+//! the memory used does not grow with the input and the output can be
+//! streamed.  Only IPv4 records fit the IP column.
+//!
+//! This needs a build with the `parquet` feature.
+
+#[cfg(feature = "parquet")]
+use crate::fields;
+use crate::fields::{Field, Row};
+#[cfg(feature = "parquet")]
+use parquet::basic::Compression;
+#[cfg(feature = "parquet")]
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, Int32Type};
+#[cfg(feature = "parquet")]
+use parquet::file::properties::WriterProperties;
+#[cfg(feature = "parquet")]
+use parquet::file::writer::SerializedFileWriter;
+#[cfg(feature = "parquet")]
+use parquet::schema::parser::parse_message_type;
+use std::io::Write;
+#[cfg(feature = "parquet")]
+use std::sync::Arc;
+#[cfg(feature = "parquet")]
+use vfb_tldextract::ip;
+use vfb_tldextract::ip::IpFormat;
+
+/// The values of one column in the current row group.
+#[cfg(feature = "parquet")]
+enum Column {
+    Int32(Vec<i32>),
+    Bool(Vec<bool>),
+    Utf8(Vec<ByteArray>),
+}
+
+#[cfg(feature = "parquet")]
+pub struct Writer {
+    fields: Vec<Field>,
+    columns: Vec<Column>,
+    rows: usize,
+    row_group_size: usize,
+    /// Encodes into a buffer that is emptied into the output after
+    /// each row group.
+    file: SerializedFileWriter<Vec<u8>>,
+}
+
+/// The schema line of the column for `field`.
+#[cfg(feature = "parquet")]
+fn column(field: Field, ip_format: IpFormat, signed: bool) -> String {
+    let (physical, logical) = match field {
+        Field::Ip if ip_format == IpFormat::Dotted => ("BYTE_ARRAY", " (STRING)"),
+        Field::Ip if signed => ("INT32", ""),
+        Field::Ip => ("INT32", " (INTEGER(32, false))"),
+        Field::IdnRoundtrip => ("BOOLEAN", ""),
+        _ => ("BYTE_ARRAY", " (STRING)"),
+    };
+    return format!("REQUIRED {} {}{};", physical, field.key(), logical);
+}
+
+#[cfg(feature = "parquet")]
+impl Writer {
+    pub fn new(
+        fields: &[Field],
+        ip_format: IpFormat,
+        signed: bool,
+        row_group_size: usize,
+    ) -> anyhow::Result<Writer> {
+        let columns: Vec<String> = fields
+            .iter()
+            .map(|&field| column(field, ip_format, signed))
+            .collect();
+        let schema = parse_message_type(&format!("message row {{ {} }}", columns.join(" ")))?;
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(row_group_size)
+            .build();
+        let file = SerializedFileWriter::new(Vec::new(), Arc::new(schema), Arc::new(props))?;
+        let columns = fields
+            .iter()
+            .map(|field| match field {
+                Field::Ip if ip_format == IpFormat::Dotted => Column::Utf8(Vec::new()),
+                Field::Ip => Column::Int32(Vec::new()),
+                Field::IdnRoundtrip => Column::Bool(Vec::new()),
+                _ => Column::Utf8(Vec::new()),
+            })
+            .collect();
+        return Ok(Writer {
+            fields: fields.to_vec(),
+            columns,
+            rows: 0,
+            row_group_size,
+            file,
+        });
+    }
+
+    /// Adds a row, writing the row group to `out` once it is full.
+    pub fn add<W: Write>(&mut self, row: &Row, out: &mut W) -> anyhow::Result<()> {
+        for (field, column) in self.fields.iter().zip(&mut self.columns) {
+            match column {
+                // Only the low 32 bits are set for an IPv4 address.
+                Column::Int32(values) => values.push(match row.ip {
+                    ip::IpValue::Unsigned(n) | ip::IpValue::Hex(n, _) => n as u32 as i32,
+                    ip::IpValue::Signed(n) => n as i32,
+                    ip::IpValue::Dotted(_) => unreachable!("a string column"),
+                }),
+                Column::Bool(values) => values.push(fields::idn_roundtrips(row)),
+                Column::Utf8(values) => {
+                    let value = match field {
+                        Field::Ip => row.ip.to_string(),
+                        Field::Host => row.host.to_string(),
+                        Field::Subdomain => row.subdomain.to_string(),
+                        Field::Domain => row.domain.to_string(),
+                        Field::Suffix => row.suffix.to_string(),
+                        Field::Registrable if row.label.is_empty() => row.suffix.to_string(),
+                        Field::Registrable => format!("{}.{}", row.label, row.suffix),
+                        Field::RawValue => row.raw_value.to_string(),
+                        Field::Timestamp => row.timestamp.to_string(),
+                        Field::Rule => row.rule.map(|rule| rule.to_string()).unwrap_or_default(),
+                        Field::RuleSection if row.private_rule => "private".to_string(),
+                        Field::RuleSection => "icann".to_string(),
+                        Field::IdnRoundtrip => unreachable!("not a string column"),
+                    };
+                    values.push(ByteArray::from(value.into_bytes()));
+                }
+            }
+        }
+        self.rows += 1;
+        if self.rows == self.row_group_size {
+            self.write_row_group(out)?;
+        }
+        return Ok(());
+    }
+
+    fn write_row_group<W: Write>(&mut self, out: &mut W) -> anyhow::Result<()> {
+        let mut row_group = self.file.next_row_group()?;
+        for column in &mut self.columns {
+            let mut writer = match row_group.next_column()? {
+                Some(writer) => writer,
+                None => anyhow::bail!("more columns than in the schema"),
+            };
+            match column {
+                Column::Int32(values) => {
+                    writer
+                        .typed::<Int32Type>()
+                        .write_batch(values, None, None)?;
+                    values.clear();
+                }
+                Column::Bool(values) => {
+                    writer.typed::<BoolType>().write_batch(values, None, None)?;
+                    values.clear();
+                }
+                Column::Utf8(values) => {
+                    writer
+                        .typed::<ByteArrayType>()
+                        .write_batch(values, None, None)?;
+                    values.clear();
+                }
+            }
+            writer.close()?;
+        }
+        row_group.close()?;
+        self.rows = 0;
+        self.file.flush()?;
+        out.write_all(&std::mem::take(self.file.inner_mut()))?;
+        return Ok(());
+    }
+
+    /// Writes the last row group and the footer.
+    pub fn finish<W: Write>(mut self, out: &mut W) -> anyhow::Result<()> {
+        if self.rows > 0 {
+            self.write_row_group(out)?;
+        }
+        out.write_all(&self.file.into_inner()?)?;
+        return Ok(());
+    }
+}
+
+/// Without the feature there is no way to create a writer, so no value of
+/// this type can exist.
+#[cfg(not(feature = "parquet"))]
+pub enum Writer {}
+
+#[cfg(not(feature = "parquet"))]
+impl Writer {
+    pub fn new(
+        _fields: &[Field],
+        _ip_format: IpFormat,
+        _signed: bool,
+        _row_group_size: usize,
+    ) -> anyhow::Result<Writer> {
+        anyhow::bail!("--format parquet needs a build with the parquet feature");
+    }
+
+    pub fn add<W: Write>(&mut self, _row: &Row, _out: &mut W) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    pub fn finish<W: Write>(self, _out: &mut W) -> anyhow::Result<()> {
+        match self {}
+    }
+}
@@ -0,0 +1,116 @@
+//! `--compare` mode: check this matcher against another extractor.
+//!
+//! HOSTS has one hostname per line.  EXPECTED has one `host,domain,suffix`
+//! (or `host,subdomain,domain,suffix`, as printed from Python's tldextract)
+//! line per host; a host that the other extractor found no domain for has
+//! an empty domain.  Disagreements are grouped by the rule that this matcher
+//! applied, since a single missing or extra rule usually explains a whole
+//! group.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use vfb_tldextract::{extract, ExtractResult, Psl};
+
+/// How many example hosts are listed per rule.
+const EXAMPLES_PER_RULE: usize = 5;
+
+struct Expected {
+    domain: String,
+    suffix: String,
+}
+
+fn parse_expected(filename: &Path) -> anyhow::Result<HashMap<String, Expected>> {
+    let text = fs::read_to_string(filename)?;
+    let mut expected = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').collect();
+        let (host, domain, suffix) = match fields.as_slice() {
+            [host, domain, suffix] => (host, domain, suffix),
+            [host, _subdomain, domain, suffix] => (host, domain, suffix),
+            _ => anyhow::bail!(
+                "{}:{}: expected host,domain,suffix or host,subdomain,domain,suffix",
+                filename.display(),
+                i + 1
+            ),
+        };
+        expected.insert(
+            host.to_string(),
+            Expected {
+                domain: domain.to_string(),
+                suffix: suffix.to_string(),
+            },
+        );
+    }
+    return Ok(expected);
+}
+
+struct Disagreement {
+    host: String,
+    got: String,
+    expected: String,
+}
+
+fn describe(domain: &str, suffix: &str) -> String {
+    return format!("domain {:?} suffix {:?}", domain, suffix);
+}
+
+pub fn run(hosts: &Path, expected: &Path, tld_set: &Psl) -> anyhow::Result<()> {
+    let expected = parse_expected(expected)?;
+    let hosts = fs::read_to_string(hosts)?;
+
+    let mut num_hosts: u64 = 0;
+    let mut num_agree: u64 = 0;
+    let mut num_missing: u64 = 0;
+    let mut by_rule: BTreeMap<String, Vec<Disagreement>> = BTreeMap::new();
+
+    for host in hosts.lines().map(str::trim).filter(|h| !h.is_empty()) {
+        num_hosts += 1;
+        let exp = match expected.get(host) {
+            Some(exp) => exp,
+            None => {
+                num_missing += 1;
+                continue;
+            }
+        };
+        let (domain, suffix) = match extract(host, tld_set) {
+            ExtractResult::Matched { domain, suffix, .. } => (domain, suffix),
+            ExtractResult::SuffixOnly { suffix } => ("", suffix),
+            ExtractResult::NoMatch | ExtractResult::Invalid(_) => ("", ""),
+        };
+        if domain == exp.domain && suffix == exp.suffix {
+            num_agree += 1;
+            continue;
+        }
+        let rule = if suffix.is_empty() {
+            "(no rule)".to_string()
+        } else {
+            suffix.to_string()
+        };
+        by_rule.entry(rule).or_default().push(Disagreement {
+            host: host.to_string(),
+            got: describe(domain, suffix),
+            expected: describe(&exp.domain, &exp.suffix),
+        });
+    }
+
+    // Largest groups first.
+    let mut groups: Vec<(String, Vec<Disagreement>)> = by_rule.into_iter().collect();
+    groups.sort_by_key(|(_, d)| std::cmp::Reverse(d.len()));
+    for (rule, disagreements) in &groups {
+        println!("rule {}: {} disagreements", rule, disagreements.len());
+        for d in disagreements.iter().take(EXAMPLES_PER_RULE) {
+            println!("  {}: got {}, expected {}", d.host, d.got, d.expected);
+        }
+    }
+
+    let num_disagree: usize = groups.iter().map(|(_, d)| d.len()).sum();
+    println!(
+        "{} hosts: {} agree, {} disagree, {} without an expected result",
+        num_hosts, num_agree, num_disagree, num_missing
+    );
+    return Ok(());
+}
@@ -0,0 +1,316 @@
+//! The binary form of a [`Psl`], written by `--compile-psl` so that a list
+//! can be checked once and distributed as a single blob.
+//!
+//! All integers are little-endian:
+//!
+//! ```text
+//! magic     8 bytes   "VFBPSL\r\n"
+//! version   u32       FORMAT_VERSION
+//! rules     u64       number of rules
+//! length    u64       length of the rule data in bytes
+//! list      u32 + n   the VERSION of the published list as a u32 length
+//!                     and its UTF-8 bytes, empty if unknown
+//! data      length    each rule as a u32 length and its UTF-8 bytes,
+//!                     in byte order; the top bit of the length is set
+//!                     for a rule from the PRIVATE section
+//! checksum  32 bytes  SHA-256 of the list version bytes and the data
+//! ```
+//!
+//! The rules are sorted, so the same list always compiles to the same
+//! bytes.  The line ending in the magic catches blobs mangled by a text-mode
+//! transfer.  Older blobs are still read: version 2 has no PRIVATE marks,
+//! and version 1 has no list version either (and its checksum covers the
+//! data alone).
+
+use crate::Psl;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+
+pub const MAGIC: &[u8; 8] = b"VFBPSL\r\n";
+pub const FORMAT_VERSION: u32 = 3;
+
+/// The bit of a rule's length that marks it as a PRIVATE rule.
+const PRIVATE_BIT: u32 = 1 << 31;
+
+/// The oldest format version still read.
+const MIN_FORMAT_VERSION: u32 = 1;
+
+fn invalid(msg: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg);
+}
+
+/// Reads exactly `buf.len()` bytes, reporting a short read as a truncated
+/// blob rather than a bare end of file.
+fn read_field<R: Read>(rdr: &mut R, buf: &mut [u8], field: &str) -> io::Result<()> {
+    return rdr.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => {
+            invalid(format!("compiled PSL is truncated in its {}", field))
+        }
+        _ => e,
+    });
+}
+
+pub fn write<W: Write>(psl: &Psl, out: &mut W) -> io::Result<()> {
+    let mut rules: Vec<&str> = psl.rules().collect();
+    rules.sort_unstable();
+    let mut data = Vec::new();
+    for rule in &rules {
+        let mut len = rule.len() as u32;
+        if psl.is_private(rule) {
+            len |= PRIVATE_BIT;
+        }
+        data.extend_from_slice(&len.to_le_bytes());
+        data.extend_from_slice(rule.as_bytes());
+    }
+    out.write_all(MAGIC)?;
+    out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    out.write_all(&(rules.len() as u64).to_le_bytes())?;
+    out.write_all(&(data.len() as u64).to_le_bytes())?;
+    let list_version = psl.version().unwrap_or("").as_bytes();
+    out.write_all(&(list_version.len() as u32).to_le_bytes())?;
+    out.write_all(list_version)?;
+    out.write_all(&data)?;
+    let mut checksum = Sha256::new();
+    checksum.update(list_version);
+    checksum.update(&data);
+    out.write_all(&checksum.finalize())?;
+    return Ok(());
+}
+
+pub fn read<R: Read>(rdr: &mut R) -> io::Result<Psl> {
+    let mut magic = [0u8; 8];
+    read_field(rdr, &mut magic, "header")?;
+    if &magic != MAGIC {
+        return Err(invalid("not a compiled PSL".to_string()));
+    }
+    let mut word = [0u8; 4];
+    read_field(rdr, &mut word, "header")?;
+    let version = u32::from_le_bytes(word);
+    if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(invalid(format!(
+            "compiled PSL is format version {}, but this build reads versions {} to {}; \
+             compile it again from the text list",
+            version, MIN_FORMAT_VERSION, FORMAT_VERSION
+        )));
+    }
+    let mut long = [0u8; 8];
+    read_field(rdr, &mut long, "header")?;
+    let num_rules = u64::from_le_bytes(long);
+    read_field(rdr, &mut long, "header")?;
+    let length = u64::from_le_bytes(long);
+    let mut list_version = Vec::new();
+    if version >= 2 {
+        read_field(rdr, &mut word, "header")?;
+        let list_len = u32::from_le_bytes(word) as u64;
+        rdr.take(list_len).read_to_end(&mut list_version)?;
+        if (list_version.len() as u64) < list_len {
+            return Err(invalid(
+                "compiled PSL is truncated in its header".to_string(),
+            ));
+        }
+    }
+
+    let mut data = Vec::new();
+    rdr.take(length).read_to_end(&mut data)?;
+    if (data.len() as u64) < length {
+        return Err(invalid(
+            "compiled PSL is truncated in its rules".to_string(),
+        ));
+    }
+    let mut checksum = [0u8; 32];
+    read_field(rdr, &mut checksum, "checksum")?;
+    let mut expected = Sha256::new();
+    expected.update(&list_version);
+    expected.update(&data);
+    if expected.finalize()[..] != checksum[..] {
+        return Err(invalid(
+            "compiled PSL checksum mismatch; the blob is corrupt".to_string(),
+        ));
+    }
+
+    let mut rules = Vec::new();
+    let mut private = Vec::new();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(invalid("compiled PSL has a malformed rule".to_string()));
+        }
+        let (len, tail) = rest.split_at(4);
+        let mut len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]);
+        let is_private = version >= 3 && len & PRIVATE_BIT != 0;
+        if version >= 3 {
+            len &= !PRIVATE_BIT;
+        }
+        let len = len as usize;
+        if tail.len() < len {
+            return Err(invalid("compiled PSL has a malformed rule".to_string()));
+        }
+        let (rule, tail) = tail.split_at(len);
+        match std::str::from_utf8(rule) {
+            Ok(rule) => {
+                if is_private {
+                    private.push(rule.to_string());
+                }
+                rules.push(rule.to_string());
+            }
+            Err(_) => {
+                return Err(invalid(
+                    "compiled PSL has a rule that is not UTF-8".to_string(),
+                ))
+            }
+        }
+        rest = tail;
+    }
+    if rules.len() as u64 != num_rules {
+        return Err(invalid(format!(
+            "compiled PSL should have {} rules but has {}",
+            num_rules,
+            rules.len()
+        )));
+    }
+    let list_version = match String::from_utf8(list_version) {
+        Ok(v) if v.is_empty() => None,
+        Ok(v) => Some(v),
+        Err(_) => {
+            return Err(invalid(
+                "compiled PSL has a list version that is not UTF-8".to_string(),
+            ))
+        }
+    };
+    return Ok(rules
+        .into_iter()
+        .collect::<Psl>()
+        .with_private(private)
+        .with_version(list_version));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract;
+
+    const LIST: &str = "// VERSION: 2024-03-01_12-30-15_UTC\n\
+                        // ===BEGIN ICANN DOMAINS===\n\
+                        com\nuk\nco.uk\n*.ck\n!www.ck\n中国\n\
+                        // ===BEGIN PRIVATE DOMAINS===\n\
+                        blogspot.com\n";
+
+    fn compile(psl: &Psl) -> Vec<u8> {
+        let mut blob = Vec::new();
+        write(psl, &mut blob).unwrap();
+        return blob;
+    }
+
+    fn error(blob: &[u8]) -> String {
+        let err = read(&mut &blob[..]).expect_err("the blob should not load");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+        return err.to_string();
+    }
+
+    #[test]
+    fn round_trip() {
+        let psl = Psl::parse(LIST.as_bytes()).unwrap();
+        let blob = compile(&psl);
+        let loaded = read(&mut &blob[..]).unwrap();
+        let mut before: Vec<&str> = psl.rules().collect();
+        let mut after: Vec<&str> = loaded.rules().collect();
+        before.sort_unstable();
+        after.sort_unstable();
+        assert_eq!(before, after);
+        assert_eq!(loaded.version(), psl.version());
+        assert!(loaded.is_private("blogspot.com"));
+        assert!(!loaded.is_private("com"));
+        for host in [
+            "www.example.com",
+            "a.blogspot.com",
+            "www.example.co.uk",
+            "co.uk",
+            "a.b.ck",
+            "www.ck",
+            "例子.中国",
+            "example.invalid",
+        ] {
+            assert_eq!(extract(host, &loaded), extract(host, &psl), "{}", host);
+        }
+        // The same list always compiles to the same bytes.
+        assert_eq!(compile(&loaded), blob);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut blob = compile(&Psl::parse(LIST.as_bytes()).unwrap());
+        blob[6] = b'\n';
+        assert!(error(&blob).contains("not a compiled PSL"));
+        assert!(error(b"com\nnet\n").contains("not a compiled PSL"));
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut blob = compile(&Psl::parse(LIST.as_bytes()).unwrap());
+        for version in [0, FORMAT_VERSION + 1, u32::MAX] {
+            blob[8..12].copy_from_slice(&version.to_le_bytes());
+            let err = error(&blob);
+            assert!(
+                err.contains(&format!("format version {}", version)),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn truncation() {
+        let blob = compile(&Psl::parse(LIST.as_bytes()).unwrap());
+        for len in 0..blob.len() {
+            let err = error(&blob[..len]);
+            assert!(err.contains("truncated"), "{}: {}", len, err);
+        }
+    }
+
+    #[test]
+    fn corruption() {
+        let blob = compile(&Psl::parse(LIST.as_bytes()).unwrap());
+        // Every byte after the counts is either a length that no longer
+        // fits or covered by the checksum.
+        for i in 28..blob.len() {
+            let mut corrupt = blob.clone();
+            corrupt[i] ^= 0x01;
+            assert!(read(&mut &corrupt[..]).is_err(), "byte {}", i);
+        }
+        let mut corrupt = blob.clone();
+        corrupt[blob.len() - 1] ^= 0xff;
+        assert!(error(&corrupt).contains("checksum mismatch"));
+        // A data length beyond the blob is a truncation, not an allocation.
+        let mut corrupt = blob.clone();
+        corrupt[20..28].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(error(&corrupt).contains("truncated"));
+    }
+
+    #[test]
+    fn wrong_rule_count() {
+        let mut blob = compile(&Psl::parse(LIST.as_bytes()).unwrap());
+        blob[12..20].copy_from_slice(&1u64.to_le_bytes());
+        assert!(error(&blob).contains("should have 1 rules"));
+    }
+
+    #[test]
+    fn version_1_blobs() {
+        let data = [
+            &3u32.to_le_bytes()[..],
+            b"com",
+            &5u32.to_le_bytes()[..],
+            b"co.uk",
+        ]
+        .concat();
+        let mut blob = MAGIC.to_vec();
+        blob.extend_from_slice(&1u32.to_le_bytes());
+        blob.extend_from_slice(&2u64.to_le_bytes());
+        blob.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        blob.extend_from_slice(&data);
+        blob.extend_from_slice(&Sha256::digest(&data));
+        let psl = read(&mut &blob[..]).unwrap();
+        assert_eq!(psl.len(), 2);
+        assert_eq!(psl.version(), None);
+        assert!(psl.contains("co.uk"));
+    }
+}
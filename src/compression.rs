@@ -0,0 +1,124 @@
+//! `--compression`: how INPUT_FILE is decompressed.  By default it is told
+//! from the first bytes of the input, so gzipped and plain JSON lines both
+//! work, and inputs in a format this build cannot read are reported as such
+//! rather than as unparseable records.
+//!
+//! zstd (`.json.zst`), xz (`.json.xz`) and bzip2 (`.json.bz2`) are read when
+//! built with the `zstd`, `xz` and `bzip2` features, which link the C
+//! libraries.  xz and bzip2 inputs made of several concatenated streams,
+//! as written by pxz and pbzip2, are read in full.
+
+use clap::ValueEnum;
+use flate2::read::GzDecoder;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Compression {
+    /// Tell from the magic number at the start of the input
+    Auto,
+    Gzip,
+    /// Needs the zstd feature
+    Zstd,
+    /// Needs the xz feature
+    Xz,
+    /// Needs the bzip2 feature
+    Bzip2,
+    /// Plain JSON lines
+    None,
+}
+
+/// The magic numbers of the compressed formats.
+const MAGIC: &[(&[u8], Compression)] = &[
+    (&[0x1f, 0x8b], Compression::Gzip),
+    (&[0x28, 0xb5, 0x2f, 0xfd], Compression::Zstd),
+    (&[0xfd, b'7', b'z', b'X', b'Z', 0x00], Compression::Xz),
+    (b"BZh", Compression::Bzip2),
+];
+
+/// Tells the compression of an input from its first bytes.
+fn sniff(head: &[u8]) -> Compression {
+    for (magic, compression) in MAGIC {
+        if head.starts_with(magic) {
+            return *compression;
+        }
+    }
+    return Compression::None;
+}
+
+/// Whether `head`, the first bytes of a file, look like those of an input
+/// rather than a suffix list: compressed, or a JSON object.
+pub fn looks_like_input(head: &[u8]) -> bool {
+    if sniff(head) != Compression::None {
+        return true;
+    }
+    return head
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .is_some_and(|&b| b == b'{');
+}
+
+#[cfg(not(all(feature = "zstd", feature = "xz", feature = "bzip2")))]
+fn missing_feature<'a>(path: &Path, feature: &str) -> anyhow::Result<Box<dyn Read + 'a>> {
+    anyhow::bail!(
+        "{}: {}-compressed, which needs a build with the {} feature; \
+         decompress it first, e.g. into a pipe read as -",
+        path.display(),
+        feature,
+        feature
+    );
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decoder<'a>(rdr: Box<dyn Read + 'a>, _path: &Path) -> anyhow::Result<Box<dyn Read + 'a>> {
+    return Ok(Box::new(zstd::stream::read::Decoder::new(rdr)?));
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decoder<'a>(_rdr: Box<dyn Read + 'a>, path: &Path) -> anyhow::Result<Box<dyn Read + 'a>> {
+    return missing_feature(path, "zstd");
+}
+
+#[cfg(feature = "xz")]
+fn xz_decoder<'a>(rdr: Box<dyn Read + 'a>, _path: &Path) -> anyhow::Result<Box<dyn Read + 'a>> {
+    return Ok(Box::new(xz2::read::XzDecoder::new_multi_decoder(rdr)));
+}
+
+#[cfg(not(feature = "xz"))]
+fn xz_decoder<'a>(_rdr: Box<dyn Read + 'a>, path: &Path) -> anyhow::Result<Box<dyn Read + 'a>> {
+    return missing_feature(path, "xz");
+}
+
+#[cfg(feature = "bzip2")]
+fn bzip2_decoder<'a>(rdr: Box<dyn Read + 'a>, _path: &Path) -> anyhow::Result<Box<dyn Read + 'a>> {
+    return Ok(Box::new(bzip2::read::MultiBzDecoder::new(rdr)));
+}
+
+#[cfg(not(feature = "bzip2"))]
+fn bzip2_decoder<'a>(_rdr: Box<dyn Read + 'a>, path: &Path) -> anyhow::Result<Box<dyn Read + 'a>> {
+    return missing_feature(path, "bzip2");
+}
+
+/// Wraps `rdr`, read from `path`, in the decoder for `compression`,
+/// sniffing it first for `Auto`.
+pub fn decoder<'a>(
+    rdr: Box<dyn Read + 'a>,
+    path: &Path,
+    compression: Compression,
+) -> anyhow::Result<Box<dyn Read + 'a>> {
+    let (rdr, compression): (Box<dyn Read + 'a>, Compression) = match compression {
+        Compression::Auto => {
+            let mut rdr = BufReader::new(rdr);
+            let compression = sniff(rdr.fill_buf()?);
+            (Box::new(rdr), compression)
+        }
+        _ => (rdr, compression),
+    };
+    match compression {
+        Compression::Gzip => return Ok(Box::new(GzDecoder::new(rdr))),
+        Compression::Zstd => return zstd_decoder(rdr, path),
+        Compression::Xz => return xz_decoder(rdr, path),
+        Compression::Bzip2 => return bzip2_decoder(rdr, path),
+        Compression::Auto | Compression::None => return Ok(rdr),
+    }
+}
@@ -0,0 +1,450 @@
+//! `--dedup`: the rows already written by the run are left out, as RDNS
+//! dumps repeat many mappings.  A row is known by the bytes written for it,
+//! with the `--fields` chosen (in CSV form for Parquet), so that rows that
+//! differ in any column written are all kept, and for the formats that do
+//! not write rows (`--cdc`, graph, cohosting and blocks) by its address and
+//! registrable domain.  The rows are remembered across all the input files
+//! of the run, which are read in the order given, so that shards that
+//! overlap, such as a dataset and its corrections, merge into one output
+//! the same way every time.
+//!
+//! The keys are kept as they are, so that two distinct rows are never
+//! taken for each other, in a set bounded by `--dedup-memory`.  When the
+//! set is full, its keys are sorted and spilled to a file in `--temp-dir`
+//! (`TMPDIR` by default), and the set starts again.  A key new to the set
+//! is then looked up in each spilled run, through a Bloom filter and a
+//! sparse index of the run kept in memory (about two bytes per spilled
+//! key, beyond --dedup-memory), so that duplicates are caught however far
+//! apart they are in the input.  A run is merged with the one spilled
+//! before it when that one is less than twice its size, so that there are
+//! only about as many runs as the logarithm of the spilled keys, and each
+//! key is rewritten as many times at most.
+//!
+//! Runs are only written when the temporary directory's volume has room
+//! for them and keeps `--min-free` free besides, else the run fails.  They
+//! are unlinked as soon as they are created, and so go away with the
+//! process however it ends, even killed by a signal.
+
+use crate::diskspace;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bytes taken by each key in the set besides the bytes of the key
+/// itself: the box, its allocation and the table's spare capacity.
+const OVERHEAD_PER_KEY: u64 = 48;
+
+/// Keys in each block of a spilled run, of which the index has the first.
+const BLOCK_KEYS: usize = 64;
+
+/// Bits of a run's Bloom filter per key, and the hashes set for each,
+/// for about one lookup in a hundred reading the run in vain.
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u64 = 7;
+
+/// Numbers the run files of the process, which may have several sets at
+/// once with --manifest --jobs.
+static RUN_FILES: AtomicU64 = AtomicU64::new(0);
+
+pub struct Seen {
+    keys: HashSet<Box<[u8]>>,
+    /// The memory taken by `keys`, as estimated, and the most it may take.
+    used: u64,
+    memory: u64,
+    /// Where runs are written, and the space to leave free there.
+    dir: PathBuf,
+    min_free: u64,
+    /// The runs, oldest and largest first.
+    runs: Vec<Run>,
+    /// Keys written to the runs.
+    spilled: u64,
+}
+
+/// A set of keys spilled to disk: the keys sorted, each as a 32-bit
+/// length and the key, in blocks of `BLOCK_KEYS`.
+struct Run {
+    file: File,
+    /// The first key of each block and where the block starts; the last
+    /// entry is the end of the file, with an empty key.
+    index: Vec<(Box<[u8]>, u64)>,
+    bloom: Vec<u64>,
+    /// The keys in the run.
+    len: u64,
+}
+
+/// Sets `key` to the key of an address and a registrable domain, for the
+/// formats that do not write rows: the address, tagged by family, then the
+/// domain.
+pub fn pair_key(key: &mut Vec<u8>, addr: IpAddr, domain: &str) {
+    key.clear();
+    match addr {
+        IpAddr::V4(addr) => {
+            key.push(4);
+            key.extend_from_slice(&addr.octets());
+        }
+        IpAddr::V6(addr) => {
+            key.push(6);
+            key.extend_from_slice(&addr.octets());
+        }
+    }
+    key.extend_from_slice(domain.as_bytes());
+}
+
+/// The bits of a Bloom filter of `bits` bits that `key` sets, by double
+/// hashing.
+fn bloom_bits(key: &[u8], bits: u64) -> impl Iterator<Item = u64> {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let hash = hasher.finish();
+    let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+    return (0..BLOOM_HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % bits);
+}
+
+/// A new file in `dir` for a run, already unlinked.
+fn run_file(dir: &Path) -> io::Result<File> {
+    let path = dir.join(format!(
+        ".{}-dedup.{}.{}",
+        crate::PROG,
+        std::process::id(),
+        RUN_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    return Ok(file);
+}
+
+/// Fails unless the volume holding `dir` has room for `bytes` more and
+/// still `min_free` free.
+fn check_space(dir: &Path, bytes: u64, min_free: u64) -> io::Result<()> {
+    let free = diskspace::free_bytes(dir)?;
+    if free < bytes.saturating_add(min_free) {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "--dedup: {}: {} bytes free, too few to spill {} bytes{}",
+                dir.display(),
+                free,
+                bytes,
+                match min_free {
+                    0 => String::new(),
+                    n => format!(" and keep --min-free {} bytes", n),
+                }
+            ),
+        ));
+    }
+    return Ok(());
+}
+
+/// Reads the keys of a run in order.
+struct Keys<'a> {
+    rdr: BufReader<&'a File>,
+}
+
+impl Keys<'_> {
+    fn next(&mut self) -> io::Result<Option<Box<[u8]>>> {
+        let mut len = [0u8; 4];
+        match self.rdr.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut key = vec![0; u32::from_le_bytes(len) as usize];
+        self.rdr.read_exact(&mut key)?;
+        return Ok(Some(key.into_boxed_slice()));
+    }
+}
+
+/// Writes keys, given in order, to a new run.
+struct RunWriter {
+    out: BufWriter<File>,
+    index: Vec<(Box<[u8]>, u64)>,
+    bloom: Vec<u64>,
+    len: u64,
+    offset: u64,
+}
+
+impl RunWriter {
+    /// A writer to `file` sized for `len` keys.
+    fn new(file: File, len: u64) -> RunWriter {
+        let bits = (len as usize * BLOOM_BITS_PER_KEY).max(64);
+        return RunWriter {
+            out: BufWriter::new(file),
+            index: Vec::with_capacity(len as usize / BLOCK_KEYS + 2),
+            bloom: vec![0u64; bits / 64],
+            len: 0,
+            offset: 0,
+        };
+    }
+
+    fn add(&mut self, key: Box<[u8]>) -> io::Result<()> {
+        let bits = self.bloom.len() as u64 * 64;
+        for bit in bloom_bits(&key, bits) {
+            self.bloom[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        // A row holding a raw value can be long, but not 4G.
+        let len = u32::try_from(key.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "row too long"))?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(&key)?;
+        if self.len.is_multiple_of(BLOCK_KEYS as u64) {
+            self.index.push((key, self.offset));
+        }
+        self.offset += 4 + u64::from(len);
+        self.len += 1;
+        return Ok(());
+    }
+
+    fn finish(mut self) -> io::Result<Run> {
+        let file = self
+            .out
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?;
+        self.index.push((Box::default(), self.offset));
+        return Ok(Run {
+            file,
+            index: self.index,
+            bloom: self.bloom,
+            len: self.len,
+        });
+    }
+}
+
+impl Run {
+    fn write(mut keys: Vec<Box<[u8]>>, dir: &Path) -> io::Result<Run> {
+        keys.sort_unstable();
+        let mut writer = RunWriter::new(run_file(dir)?, keys.len() as u64);
+        for key in keys {
+            writer.add(key)?;
+        }
+        return writer.finish();
+    }
+
+    /// The bytes of the run on disk.
+    fn size(&self) -> u64 {
+        return self.index.last().map_or(0, |(_, end)| *end);
+    }
+
+    fn keys(&self) -> io::Result<Keys<'_>> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(Keys {
+            rdr: BufReader::new(file),
+        });
+    }
+
+    /// Merges two runs into one; their keys are distinct, as a key is only
+    /// spilled if no run has it.
+    fn merge(a: &Run, b: &Run, dir: &Path) -> io::Result<Run> {
+        let mut writer = RunWriter::new(run_file(dir)?, a.len + b.len);
+        let (mut a, mut b) = (a.keys()?, b.keys()?);
+        let (mut next_a, mut next_b) = (a.next()?, b.next()?);
+        loop {
+            match (next_a.take(), next_b.take()) {
+                (None, None) => break,
+                (Some(key), None) => {
+                    writer.add(key)?;
+                    next_a = a.next()?;
+                }
+                (None, Some(key)) => {
+                    writer.add(key)?;
+                    next_b = b.next()?;
+                }
+                (Some(ka), Some(kb)) if ka <= kb => {
+                    writer.add(ka)?;
+                    next_a = a.next()?;
+                    next_b = Some(kb);
+                }
+                (ka, Some(kb)) => {
+                    writer.add(kb)?;
+                    next_a = ka;
+                    next_b = b.next()?;
+                }
+            }
+        }
+        return writer.finish();
+    }
+
+    fn contains(&self, key: &[u8]) -> io::Result<bool> {
+        let bits = self.bloom.len() as u64 * 64;
+        if !bloom_bits(key, bits)
+            .all(|bit| self.bloom[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+        {
+            return Ok(false);
+        }
+        let blocks = &self.index[..self.index.len() - 1];
+        // The block whose first key is the last not after `key`.
+        let block = match blocks.partition_point(|(first, _)| &**first <= key) {
+            0 => return Ok(false),
+            n => n - 1,
+        };
+        let (start, end) = (self.index[block].1, self.index[block + 1].1);
+        let mut buf = vec![0; (end - start) as usize];
+        self.file.read_exact_at(&mut buf, start)?;
+        let mut rest = &buf[..];
+        while rest.len() >= 4 {
+            let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let stored = &rest[4..4 + len];
+            if stored == key {
+                return Ok(true);
+            }
+            if stored > key {
+                break;
+            }
+            rest = &rest[4 + len..];
+        }
+        return Ok(false);
+    }
+}
+
+impl Seen {
+    /// A set that holds `memory` bytes of keys, then spills to `dir`,
+    /// keeping `min_free` bytes free on its volume.
+    pub fn new(memory: u64, dir: PathBuf, min_free: u64) -> Seen {
+        return Seen {
+            keys: HashSet::new(),
+            used: 0,
+            memory,
+            dir,
+            min_free,
+            runs: Vec::new(),
+            spilled: 0,
+        };
+    }
+
+    /// Records the key of a row, returning whether the run has not seen it
+    /// before.
+    pub fn insert(&mut self, key: &[u8]) -> io::Result<bool> {
+        if self.keys.contains(key) {
+            return Ok(false);
+        }
+        for run in &self.runs {
+            if run.contains(key)? {
+                return Ok(false);
+            }
+        }
+        let cost = key.len() as u64 + OVERHEAD_PER_KEY;
+        if self.used + cost > self.memory && !self.keys.is_empty() {
+            self.spill()?;
+        }
+        self.used += cost;
+        self.keys.insert(key.into());
+        return Ok(true);
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        if self.runs.is_empty() {
+            log::info!(
+                "--dedup: more than {} distinct rows, the most --dedup-memory holds; \
+                 spilling them to {}",
+                self.keys.len(),
+                self.dir.display()
+            );
+        }
+        let n = self.keys.len();
+        let bytes = self.keys.iter().map(|key| 4 + key.len() as u64).sum();
+        check_space(&self.dir, bytes, self.min_free)?;
+        let run = Run::write(self.keys.drain().collect(), &self.dir)?;
+        self.runs.push(run);
+        self.spilled += n as u64;
+        self.used = 0;
+        // Merging the last two runs while the older is less than twice the
+        // newer keeps their sizes doubling from the newest to the oldest.
+        while let [.., older, newer] = &self.runs[..] {
+            if older.len >= 2 * newer.len {
+                break;
+            }
+            check_space(&self.dir, older.size() + newer.size(), self.min_free)?;
+            let merged = Run::merge(older, newer, &self.dir)?;
+            self.runs.truncate(self.runs.len() - 2);
+            self.runs.push(merged);
+        }
+        return Ok(());
+    }
+
+    /// The keys written to disk so far.
+    pub fn spilled(&self) -> u64 {
+        return self.spilled;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pair_key, Seen};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn seen(memory: u64) -> Seen {
+        return Seen::new(memory, std::env::temp_dir(), 0);
+    }
+
+    fn insert(seen: &mut Seen, addr: &str, domain: &str) -> bool {
+        let mut key = Vec::new();
+        pair_key(&mut key, addr.parse().unwrap(), domain);
+        return seen.insert(&key).unwrap();
+    }
+
+    #[test]
+    fn exact_pairs() {
+        let mut seen = seen(1 << 20);
+        assert!(insert(&mut seen, "192.0.2.1", "example.com"));
+        assert!(!insert(&mut seen, "192.0.2.1", "example.com"));
+        assert!(insert(&mut seen, "192.0.2.1", "example.net"));
+        assert!(insert(&mut seen, "192.0.2.2", "example.com"));
+        assert!(insert(&mut seen, "::ffff:192.0.2.1", "example.com"));
+        assert_eq!(seen.spilled(), 0);
+    }
+
+    #[test]
+    fn rows() {
+        let mut seen = seen(1 << 20);
+        assert!(seen.insert(b"16909060,example.com\n").unwrap());
+        assert!(seen.insert(b"16909060,example.net\n").unwrap());
+        assert!(!seen.insert(b"16909060,example.com\n").unwrap());
+    }
+
+    #[test]
+    fn spilled_keys_stay_seen() {
+        // Room for ten keys, so that 1000 are spread over many runs.
+        let mut seen = seen(10 * (super::OVERHEAD_PER_KEY + 16));
+        let addr = |i: u32| IpAddr::V4(Ipv4Addr::from(0xc000_0200 + i)).to_string();
+        for i in 0..1000 {
+            assert!(insert(&mut seen, &addr(i), "example.com"));
+        }
+        assert!(seen.spilled() >= 990);
+        // Merged as they are spilled, down to about log2(100) runs.
+        assert!(seen.runs.len() <= 8, "{} runs", seen.runs.len());
+        assert_eq!(
+            seen.runs.iter().map(|run| run.len).sum::<u64>(),
+            seen.spilled()
+        );
+        for pair in seen.runs.windows(2) {
+            assert!(pair[0].len >= 2 * pair[1].len);
+        }
+        for i in 0..1000 {
+            assert!(!insert(&mut seen, &addr(i), "example.com"), "{}", i);
+        }
+        assert!(insert(&mut seen, &addr(1000), "example.com"));
+        assert!(insert(&mut seen, &addr(0), "example.net"));
+    }
+
+    #[test]
+    fn spills_keep_free_space() {
+        let mut seen = Seen::new(super::OVERHEAD_PER_KEY + 16, std::env::temp_dir(), u64::MAX);
+        assert!(insert(&mut seen, "192.0.2.1", "example.com"));
+        let mut key = Vec::new();
+        super::pair_key(&mut key, "192.0.2.2".parse().unwrap(), "example.com");
+        let e = seen.insert(&key).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::StorageFull);
+        assert_eq!(seen.spilled(), 0);
+    }
+}
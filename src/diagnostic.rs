@@ -0,0 +1,69 @@
+//! Context for records that fail to deserialize.
+//!
+//! Lines can be megabytes long, so rather than printing the whole line, the
+//! diagnostic gives the byte offset of the failure, what the parser
+//! expected, and a short hex/ASCII window around the failure point.
+
+use std::fmt::Write;
+
+/// Bytes shown on each side of the failure point.
+const WINDOW: usize = 16;
+
+/// Renders the bytes around `offset` as hex followed by printable ASCII,
+/// with `…` marking where the line continues beyond the window.
+fn snippet(bytes: &[u8], offset: usize) -> String {
+    let start = offset.saturating_sub(WINDOW);
+    let end = (offset + WINDOW).min(bytes.len());
+    let window = &bytes[start..end];
+
+    let mut hex = String::with_capacity(window.len() * 3);
+    for b in window {
+        let _ = write!(hex, "{:02x} ", b);
+    }
+    let ascii: String = window
+        .iter()
+        .map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    return format!(
+        "{}{}{}|{}{}{}|",
+        if start > 0 { "… " } else { "" },
+        hex,
+        if end < bytes.len() { "… " } else { "" },
+        if start > 0 { "…" } else { "" },
+        ascii,
+        if end < bytes.len() { "…" } else { "" },
+    );
+}
+
+/// Describes why `line` (the `line_no`th line of the input) could not be
+/// deserialized.
+pub fn describe(line_no: u64, line: &str, err: &serde_json::Error) -> String {
+    let line = line.trim_end();
+    // Records are a single line, so the column is the byte offset (1-based);
+    // an error past the newline means the record ended too early.
+    let offset = if err.line() > 1 {
+        line.len()
+    } else {
+        err.column().saturating_sub(1).min(line.len())
+    };
+    let message = err.to_string();
+    // serde_json appends the position, which is reported separately here.
+    let message = match message.rfind(" at line ") {
+        Some(idx) => &message[..idx],
+        None => &message,
+    };
+    return format!(
+        "line {}: cannot deserialize record at byte {} of {}: {}; near {}",
+        line_no,
+        offset,
+        line.len(),
+        message,
+        snippet(line.as_bytes(), offset)
+    );
+}
@@ -0,0 +1,116 @@
+//! `--direct-io`: output files written with `O_DIRECT`, bypassing the page
+//! cache, so that writing a large output to a dedicated volume does not
+//! evict the cached data of the other services on the host.
+//!
+//! `O_DIRECT` writes must start at an aligned offset and be made of whole
+//! aligned blocks from an aligned buffer.  Rows are gathered in such a
+//! buffer and written a full buffer at a time; on a flush, the partial
+//! block at the end is written with `O_DIRECT` turned off for that one
+//! write and kept in the buffer, so that the next write starts from the
+//! same aligned offset and overwrites it.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+/// The alignment of offsets, lengths and buffers, enough for the logical
+/// block size of any disk in use.
+const ALIGN: usize = 4096;
+
+#[repr(C, align(4096))]
+struct Block([u8; ALIGN]);
+
+pub struct DirectWriter {
+    file: File,
+    blocks: Vec<Block>,
+    /// Bytes buffered, from the start of the first block.
+    len: usize,
+    /// The offset in the file of the first buffered byte, always aligned.
+    offset: u64,
+}
+
+fn set_direct(file: &File, on: bool) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if on {
+        flags | libc::O_DIRECT
+    } else {
+        flags & !libc::O_DIRECT
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(());
+}
+
+impl DirectWriter {
+    /// Writes to `file`, an empty file opened for writing, from a buffer of
+    /// `capacity` bytes rounded up to whole blocks.  Fails if the
+    /// filesystem does not support `O_DIRECT`.
+    pub fn new(file: File, capacity: usize) -> io::Result<DirectWriter> {
+        set_direct(&file, true)?;
+        let n = capacity.div_ceil(ALIGN).max(1);
+        let blocks = (0..n).map(|_| Block([0; ALIGN])).collect();
+        return Ok(DirectWriter {
+            file,
+            blocks,
+            len: 0,
+            offset: 0,
+        });
+    }
+
+    fn buf(&self) -> &[u8] {
+        let len = self.blocks.len() * ALIGN;
+        return unsafe { std::slice::from_raw_parts(self.blocks.as_ptr().cast(), len) };
+    }
+
+    fn buf_mut(&mut self) -> &mut [u8] {
+        let len = self.blocks.len() * ALIGN;
+        return unsafe { std::slice::from_raw_parts_mut(self.blocks.as_mut_ptr().cast(), len) };
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // A full buffer is written out before taking more, so that a failed
+        // write consumes nothing and can be retried.
+        if self.len == self.buf().len() {
+            self.file.write_all_at(self.buf(), self.offset)?;
+            self.offset += self.len as u64;
+            self.len = 0;
+        }
+        let len = self.len;
+        let n = data.len().min(self.buf().len() - len);
+        self.buf_mut()[len..len + n].copy_from_slice(&data[..n]);
+        self.len += n;
+        return Ok(n);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let full = self.len / ALIGN * ALIGN;
+        if full > 0 {
+            self.file.write_all_at(&self.buf()[..full], self.offset)?;
+            let len = self.len;
+            self.buf_mut().copy_within(full..len, 0);
+            self.offset += full as u64;
+            self.len -= full;
+        }
+        if self.len > 0 {
+            set_direct(&self.file, false)?;
+            let result = self.file.write_all_at(&self.buf()[..self.len], self.offset);
+            set_direct(&self.file, true)?;
+            result?;
+        }
+        return Ok(());
+    }
+}
+
+impl Drop for DirectWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
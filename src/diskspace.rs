@@ -0,0 +1,73 @@
+//! `--min-free`: the free space left on the volumes a run writes to, checked
+//! before it starts and every so often while it reads, so that a run on a
+//! filling volume stops cleanly, as at --max-runtime, instead of failing
+//! halfway through a write and leaving a truncated file for the next stage.
+//! The volume of `--temp-dir` is watched as well when `--dedup` may spill
+//! to it.
+
+use crate::rusage::human_bytes;
+use std::collections::BTreeSet;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Bytes available to unprivileged users on the filesystem holding `dir`.
+pub fn free_bytes(dir: &Path) -> io::Result<u64> {
+    let path = CString::new(dir.as_os_str().as_bytes())?;
+    let mut st = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), st.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let st = unsafe { st.assume_init() };
+    // The field types vary between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    return Ok(st.f_bavail as u64 * st.f_frsize as u64);
+}
+
+/// The volumes written to by a run and the space each must keep free.
+pub struct Guard {
+    dirs: Vec<PathBuf>,
+    min_free: u64,
+}
+
+impl Guard {
+    /// Watches the volumes holding `files` and the directories `dirs`, each
+    /// checked once however many of them are on it.
+    pub fn new(files: &[&Path], dirs: &[&Path], min_free: u64) -> io::Result<Guard> {
+        let parents = files.iter().map(|file| match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        });
+        let mut devices = BTreeSet::new();
+        let mut watched = Vec::new();
+        for dir in parents.chain(dirs.iter().copied()) {
+            if devices.insert(fs::metadata(dir)?.dev()) {
+                watched.push(dir.to_path_buf());
+            }
+        }
+        return Ok(Guard {
+            dirs: watched,
+            min_free,
+        });
+    }
+
+    /// Describes the first volume with less than the minimum free, if any.
+    pub fn low(&self) -> io::Result<Option<String>> {
+        for dir in &self.dirs {
+            let free = free_bytes(dir)?;
+            if free < self.min_free {
+                return Ok(Some(format!(
+                    "{}: {} free, below --min-free {}",
+                    dir.display(),
+                    human_bytes(free),
+                    human_bytes(self.min_free)
+                )));
+            }
+        }
+        return Ok(None);
+    }
+}
@@ -0,0 +1,54 @@
+//! The `embedded-psl` feature: a compiled list (see `--compile-psl`) built
+//! into the binary, for hosts with no network and nowhere to bring a list
+//! from.  The blob is read at build time from the absolute path in the
+//! `VFB_EMBEDDED_PSL` environment variable, and is used when no
+//! TLD_DATA_FILE is given, in place of the --psl-update cache.
+//!
+//! The embedded list stands in wherever a TLD_DATA_FILE path is expected
+//! under the name [`NAME`], which can also be given as TLD_DATA_FILE.
+
+use crate::lineage::{self, Checksum};
+use std::io;
+use std::path::Path;
+use vfb_tldextract::Psl;
+
+pub const NAME: &str = "(embedded)";
+
+#[cfg(feature = "embedded-psl")]
+const BLOB: &[u8] = include_bytes!(env!(
+    "VFB_EMBEDDED_PSL",
+    "the embedded-psl feature needs VFB_EMBEDDED_PSL set to the absolute path of a compiled list"
+));
+
+#[cfg(not(feature = "embedded-psl"))]
+const BLOB: &[u8] = &[];
+
+/// The name of the embedded list, if this build has one.
+pub fn path() -> Option<&'static Path> {
+    if cfg!(feature = "embedded-psl") {
+        return Some(Path::new(NAME));
+    }
+    return None;
+}
+
+/// Whether `path` names the embedded list rather than a file.
+pub fn is(path: &Path) -> bool {
+    return path.as_os_str() == NAME;
+}
+
+pub fn load() -> anyhow::Result<Psl> {
+    if !cfg!(feature = "embedded-psl") {
+        anyhow::bail!("{}: this build has no embedded list", NAME);
+    }
+    match Psl::read_compiled(&mut &BLOB[..]) {
+        Ok(psl) => return Ok(psl),
+        Err(e) => anyhow::bail!("{}: {}", NAME, e),
+    }
+}
+
+/// The digest of the blob, as `lineage::file_digest` gives for a file.
+pub fn digest() -> io::Result<lineage::FileDigest> {
+    let checksum = Checksum::new();
+    io::copy(&mut checksum.reader(BLOB), &mut io::sink())?;
+    return Ok(checksum.finish());
+}
@@ -0,0 +1,300 @@
+//! `--fields`: the columns of the output rows, `ip,domain` unless chosen
+//! otherwise, so that the whole structure of the host can be kept, as with
+//! Python's tldextract: `--fields ip,subdomain,domain,suffix`.  They are
+//! separated by commas, or by the `--delimiter` given, e.g. a tab.
+//!
+//! With `--format ndjson` the same fields are written as one JSON object per
+//! row, keyed by the camelCase field name: `{"ip":3232243713,"domain":"example"}`.
+
+use crate::idn;
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::io::{self, Write};
+use vfb_tldextract::{ip, MatchedRule};
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Field {
+    /// The address, as configured by --ip-format, --ip-endianness and
+    /// --ip-signed.
+    Ip,
+    /// The whole host, e.g. `www.example.co.uk`.
+    #[value(alias = "hostname")]
+    Host,
+    /// What precedes the registrable domain, e.g. `www`.
+    Subdomain,
+    /// The domain column as usual, e.g. `example`.
+    Domain,
+    /// The public suffix, e.g. `co.uk`.
+    Suffix,
+    /// The registrable domain, e.g. `example.co.uk`.
+    Registrable,
+    /// Whether the `xn--` labels of the registrable domain decode from
+    /// punycode and encode back to the same label: `true` or `false`.
+    IdnRoundtrip,
+    /// The record's value exactly as given, before normalization, quoted
+    /// when it holds the delimiter, a quote or a line break.
+    RawValue,
+    /// The record's timestamp as given, e.g. `1700000000`.
+    Timestamp,
+    /// The suffix rule that matched, e.g. `co.uk`, `*.ck` or `!www.ck`.
+    Rule,
+    /// The section of the list the rule is from: `icann` or `private`.
+    RuleSection,
+}
+
+impl Field {
+    /// The key of the field in an NDJSON row, and its Parquet column name.
+    pub fn key(self) -> &'static str {
+        match self {
+            Field::Ip => return "ip",
+            Field::Host => return "host",
+            Field::Subdomain => return "subdomain",
+            Field::Domain => return "domain",
+            Field::Suffix => return "suffix",
+            Field::Registrable => return "registrable",
+            Field::IdnRoundtrip => return "idnRoundtrip",
+            Field::RawValue => return "rawValue",
+            Field::Timestamp => return "timestamp",
+            Field::Rule => return "rule",
+            Field::RuleSection => return "ruleSection",
+        }
+    }
+}
+
+/// The values of one row.
+pub struct Row<'a> {
+    pub ip: ip::IpValue,
+    pub host: &'a str,
+    pub subdomain: &'a str,
+    pub domain: &'a str,
+    /// The label of the registrable domain; empty for a suffix-only host.
+    pub label: &'a str,
+    pub suffix: &'a str,
+    pub raw_value: &'a str,
+    pub timestamp: &'a str,
+    /// The rule that matched, if any.
+    pub rule: Option<MatchedRule<'a>>,
+    pub private_rule: bool,
+}
+
+/// `value` as a CSV field, quoted if need be, for the rows written with
+/// `write!` rather than [`write_row`].
+pub fn quoted(value: &str, delimiter: u8) -> Cow<'_, str> {
+    if !value.contains([char::from(delimiter), '"', '\n', '\r']) {
+        return Cow::Borrowed(value);
+    }
+    return Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")));
+}
+
+/// Writes `value` as a CSV field, quoted if need be.
+fn write_quoted<W: Write>(out: &mut W, value: &str, delimiter: u8) -> io::Result<()> {
+    return out.write_all(quoted(value, delimiter).as_bytes());
+}
+
+/// Whether the `xn--` labels of the row's registrable domain round-trip.
+pub fn idn_roundtrips(row: &Row) -> bool {
+    return idn::punycode_roundtrips(row.label) && idn::punycode_roundtrips(row.suffix);
+}
+
+pub fn write_row<W: Write>(
+    out: &mut W,
+    fields: &[Field],
+    delimiter: u8,
+    row: &Row,
+) -> io::Result<()> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.write_all(&[delimiter])?;
+        }
+        match field {
+            Field::Ip => write!(out, "{}", row.ip)?,
+            // The names come from JSON strings, which may hold quotes or the
+            // delimiter once unescaped.
+            Field::Host => write_quoted(out, row.host, delimiter)?,
+            Field::Subdomain => write_quoted(out, row.subdomain, delimiter)?,
+            Field::Domain => write_quoted(out, row.domain, delimiter)?,
+            Field::Suffix => write_quoted(out, row.suffix, delimiter)?,
+            Field::Registrable if row.label.is_empty() => write_quoted(out, row.suffix, delimiter)?,
+            Field::Registrable => {
+                write_quoted(out, &format!("{}.{}", row.label, row.suffix), delimiter)?
+            }
+            Field::IdnRoundtrip => write!(out, "{}", idn_roundtrips(row))?,
+            Field::RawValue => write_quoted(out, row.raw_value, delimiter)?,
+            Field::Timestamp => write_quoted(out, row.timestamp, delimiter)?,
+            Field::Rule => {
+                if let Some(rule) = row.rule {
+                    write!(out, "{}", rule)?;
+                }
+            }
+            Field::RuleSection if row.private_rule => out.write_all(b"private")?,
+            Field::RuleSection => out.write_all(b"icann")?,
+        }
+    }
+    return out.write_all(b"\n");
+}
+
+/// Writes `value` as a JSON string.
+fn write_json_str<W: Write>(out: &mut W, value: &str) -> io::Result<()> {
+    return serde_json::to_writer(out, value).map_err(io::Error::from);
+}
+
+/// Writes the row as a JSON object on a line of its own.  The IP is a
+/// number, unless written in hex or dotted, and idn-roundtrip a boolean.
+pub fn write_json_row<W: Write>(out: &mut W, fields: &[Field], row: &Row) -> io::Result<()> {
+    out.write_all(b"{")?;
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b",")?;
+        }
+        write!(out, "\"{}\":", field.key())?;
+        match field {
+            Field::Ip => match row.ip {
+                ip::IpValue::Hex(..) | ip::IpValue::Dotted(_) => write!(out, "\"{}\"", row.ip)?,
+                _ => write!(out, "{}", row.ip)?,
+            },
+            Field::Host => write_json_str(out, row.host)?,
+            Field::Subdomain => write_json_str(out, row.subdomain)?,
+            Field::Domain => write_json_str(out, row.domain)?,
+            Field::Suffix => write_json_str(out, row.suffix)?,
+            Field::Registrable if row.label.is_empty() => write_json_str(out, row.suffix)?,
+            Field::Registrable => write_json_str(out, &format!("{}.{}", row.label, row.suffix))?,
+            Field::IdnRoundtrip => write!(out, "{}", idn_roundtrips(row))?,
+            Field::RawValue => write_json_str(out, row.raw_value)?,
+            Field::Timestamp => write_json_str(out, row.timestamp)?,
+            Field::Rule => match row.rule {
+                Some(rule) => write_json_str(out, &rule.to_string())?,
+                None => out.write_all(b"\"\"")?,
+            },
+            Field::RuleSection if row.private_rule => out.write_all(b"\"private\"")?,
+            Field::RuleSection => out.write_all(b"\"icann\"")?,
+        }
+    }
+    return out.write_all(b"}\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row<'a>(host: &'a str, label: &'a str, suffix: &'a str) -> Row<'a> {
+        return Row {
+            ip: ip::IpValue::Unsigned(3221225985),
+            host,
+            subdomain: "www",
+            domain: label,
+            label,
+            suffix,
+            raw_value: host,
+            timestamp: "1700000000",
+            rule: Some(MatchedRule {
+                marker: "",
+                name: suffix,
+            }),
+            private_rule: false,
+        };
+    }
+
+    fn csv(fields: &[Field], delimiter: u8, row: &Row) -> String {
+        let mut out = Vec::new();
+        write_row(&mut out, fields, delimiter, row).unwrap();
+        return String::from_utf8(out).unwrap();
+    }
+
+    fn json(fields: &[Field], row: &Row) -> String {
+        let mut out = Vec::new();
+        write_json_row(&mut out, fields, row).unwrap();
+        return String::from_utf8(out).unwrap();
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quoted("example", b','), "example");
+        assert!(matches!(quoted("example", b','), Cow::Borrowed(_)));
+        assert_eq!(quoted("a,b", b','), "\"a,b\"");
+        assert_eq!(quoted("a\"b", b','), "\"a\"\"b\"");
+        assert_eq!(quoted("a\nb", b','), "\"a\nb\"");
+        assert_eq!(quoted("a\rb", b','), "\"a\rb\"");
+        // Only the delimiter in use needs quoting.
+        assert_eq!(quoted("a,b", b'\t'), "a,b");
+        assert_eq!(quoted("a\tb", b'\t'), "\"a\tb\"");
+        assert_eq!(quoted("a|b", b'|'), "\"a|b\"");
+    }
+
+    #[test]
+    fn csv_rows() {
+        let row = row("www.example.co.uk", "example", "co.uk");
+        assert_eq!(
+            csv(&[Field::Ip, Field::Domain], b',', &row),
+            "3221225985,example\n"
+        );
+        assert_eq!(
+            csv(
+                &[
+                    Field::Subdomain,
+                    Field::Registrable,
+                    Field::Rule,
+                    Field::RuleSection
+                ],
+                b'\t',
+                &row
+            ),
+            "www\texample.co.uk\tco.uk\ticann\n"
+        );
+        let suffix_only = Row {
+            private_rule: true,
+            ..self::row("co.uk", "", "co.uk")
+        };
+        assert_eq!(
+            csv(
+                &[Field::Registrable, Field::RuleSection],
+                b',',
+                &suffix_only
+            ),
+            "co.uk,private\n"
+        );
+    }
+
+    #[test]
+    fn csv_rows_quote_names() {
+        let row = Row {
+            raw_value: "Www.Example.CO.UK.,\"x\"\r\n",
+            ..row("a,b.example.co.uk", "ex\"ample", "co.uk")
+        };
+        assert_eq!(
+            csv(&[Field::Host, Field::Domain, Field::RawValue], b',', &row),
+            "\"a,b.example.co.uk\",\"ex\"\"ample\",\"Www.Example.CO.UK.,\"\"x\"\"\r\n\"\n"
+        );
+        assert_eq!(
+            csv(&[Field::Host, Field::Domain], b'|', &row),
+            "a,b.example.co.uk|\"ex\"\"ample\"\n"
+        );
+    }
+
+    #[test]
+    fn json_rows() {
+        let row = row("www.example.co.uk", "example", "co.uk");
+        assert_eq!(
+            json(&[Field::Ip, Field::Domain, Field::IdnRoundtrip], &row),
+            "{\"ip\":3221225985,\"domain\":\"example\",\"idnRoundtrip\":true}\n"
+        );
+        let hex = Row {
+            ip: ip::IpValue::Hex(0xc0000201, 8),
+            rule: None,
+            ..row
+        };
+        let out = json(&[Field::Ip, Field::Rule, Field::RuleSection], &hex);
+        let value: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert!(value["ip"].is_string());
+        assert_eq!(value["rule"], "");
+        assert_eq!(value["ruleSection"], "icann");
+        let odd = Row {
+            raw_value: "a\"b\\c\nd",
+            ..self::row("www.example.co.uk", "example", "co.uk")
+        };
+        let out = json(&[Field::RawValue, Field::Registrable], &odd);
+        let value: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(value["rawValue"], "a\"b\\c\nd");
+        assert_eq!(value["registrable"], "example.co.uk");
+        assert!(out.ends_with("}\n") && !out[..out.len() - 1].contains('\n'));
+    }
+}
@@ -0,0 +1,141 @@
+//! `--format graph`: the pairs as a GraphML document of a bipartite graph,
+//! with a node per address and per domain and an edge for each pair, ready
+//! for NetworkX (`read_graphml`), Gephi or Neo4j (`apoc.import.graphml`).
+//!
+//! Node ids are derived from the values themselves, `ip:1.2.3.4` (or `ip:2001:db8::1`)
+//! and `domain:example`, so they are stable across runs and files.  Each node
+//! and edge is written once, the first time it is seen.
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::net::IpAddr;
+
+const HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns">
+  <key id="type" for="node" attr.name="type" attr.type="string"/>
+  <key id="value" for="node" attr.name="value" attr.type="string"/>
+  <graph id="rdns" edgedefault="undirected">
+"#;
+
+const FOOTER: &str = "  </graph>\n</graphml>\n";
+
+/// The nodes and edges written so far.
+#[derive(Default)]
+pub struct Graph {
+    addrs: HashSet<IpAddr>,
+    domains: HashSet<String>,
+    edges: HashSet<(IpAddr, String)>,
+}
+
+/// Escapes the characters that are special in XML attribute values.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+fn node<W: Write>(out: &mut W, id: &str, kind: &str, value: &str) -> io::Result<()> {
+    return writeln!(
+        out,
+        "    <node id=\"{}\"><data key=\"type\">{}</data><data key=\"value\">{}</data></node>",
+        id, kind, value
+    );
+}
+
+impl Graph {
+    pub fn header<W: Write>(out: &mut W) -> io::Result<()> {
+        return out.write_all(HEADER.as_bytes());
+    }
+
+    pub fn footer<W: Write>(out: &mut W) -> io::Result<()> {
+        return out.write_all(FOOTER.as_bytes());
+    }
+
+    /// Writes the edge between `addr` and `domain`, and their nodes, unless
+    /// already written.
+    pub fn edge<W: Write>(&mut self, out: &mut W, addr: IpAddr, domain: &str) -> io::Result<()> {
+        if self.edges.contains(&(addr, domain.to_string())) {
+            return Ok(());
+        }
+        let domain_id = format!("domain:{}", escape(domain));
+        if self.addrs.insert(addr) {
+            node(out, &format!("ip:{}", addr), "ip", &addr.to_string())?;
+        }
+        if !self.domains.contains(domain) {
+            node(out, &domain_id, "domain", &escape(domain))?;
+            self.domains.insert(domain.to_string());
+        }
+        writeln!(
+            out,
+            "    <edge source=\"ip:{}\" target=\"{}\"/>",
+            addr, domain_id
+        )?;
+        self.edges.insert((addr, domain.to_string()));
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape("example"), "example");
+        assert_eq!(escape("a&b<c>\"d'"), "a&amp;b&lt;c&gt;&quot;d&apos;");
+    }
+
+    #[test]
+    fn nodes_and_edges_are_written_once() {
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "2001:db8::1".parse().unwrap();
+        let mut graph = Graph::default();
+        let mut out = Vec::new();
+        Graph::header(&mut out).unwrap();
+        graph.edge(&mut out, a, "example").unwrap();
+        graph.edge(&mut out, a, "example").unwrap();
+        graph.edge(&mut out, b, "example").unwrap();
+        graph.edge(&mut out, a, "other").unwrap();
+        Graph::footer(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let body: Vec<&str> = out
+            .lines()
+            .filter(|l| l.contains("<node") || l.contains("<edge"))
+            .collect();
+        assert_eq!(
+            body,
+            vec![
+                "    <node id=\"ip:192.0.2.1\"><data key=\"type\">ip</data><data key=\"value\">192.0.2.1</data></node>",
+                "    <node id=\"domain:example\"><data key=\"type\">domain</data><data key=\"value\">example</data></node>",
+                "    <edge source=\"ip:192.0.2.1\" target=\"domain:example\"/>",
+                "    <node id=\"ip:2001:db8::1\"><data key=\"type\">ip</data><data key=\"value\">2001:db8::1</data></node>",
+                "    <edge source=\"ip:2001:db8::1\" target=\"domain:example\"/>",
+                "    <node id=\"domain:other\"><data key=\"type\">domain</data><data key=\"value\">other</data></node>",
+                "    <edge source=\"ip:192.0.2.1\" target=\"domain:other\"/>",
+            ]
+        );
+        assert!(out.starts_with("<?xml") && out.ends_with("</graphml>\n"));
+    }
+
+    #[test]
+    fn domains_are_escaped() {
+        let mut graph = Graph::default();
+        let mut out = Vec::new();
+        graph
+            .edge(&mut out, "192.0.2.1".parse().unwrap(), "a\"b")
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("id=\"domain:a&quot;b\""));
+        assert!(out.contains("target=\"domain:a&quot;b\""));
+        assert!(!out.contains("a\"b"));
+    }
+}
@@ -0,0 +1,99 @@
+//! `--iana-tlds`: the top-level domains delegated in the root zone, as
+//! published by IANA in `tlds-alpha-by-domain.txt`, to flag hosts whose last
+//! label is not one (internal names such as `host.corp` or `printer.local`,
+//! or typos).
+//!
+//! This is a check of the data, separate from matching against the suffix
+//! list: the records are still processed as usual, and only counted by
+//! their undelegated TLD.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+pub struct TldList {
+    tlds: HashSet<String>,
+}
+
+impl TldList {
+    /// Reads the list, one TLD per line in any case, skipping `#` comments
+    /// such as the version line at the top.
+    pub fn load(path: &Path) -> anyhow::Result<TldList> {
+        let rdr = BufReader::new(File::open(path)?);
+        let mut tlds = HashSet::new();
+        for line in rdr.lines() {
+            let line = line?;
+            let tld = line.trim();
+            if !tld.is_empty() && !tld.starts_with('#') {
+                tlds.insert(tld.to_ascii_lowercase());
+            }
+        }
+        if tlds.is_empty() {
+            anyhow::bail!("{}: no TLDs in the list", path.display());
+        }
+        return Ok(TldList { tlds });
+    }
+
+    pub fn len(&self) -> usize {
+        return self.tlds.len();
+    }
+
+    /// The last label of `host` if it is not a delegated TLD.
+    pub fn undelegated<'a>(&self, host: &'a str) -> Option<&'a str> {
+        let tld = host.rsplit('.').next().unwrap_or(host);
+        if self.tlds.contains(tld) {
+            return None;
+        }
+        // Only allocate for the hosts that are not already in lower case.
+        if tld.bytes().any(|b| b.is_ascii_uppercase())
+            && self.tlds.contains(&tld.to_ascii_lowercase())
+        {
+            return None;
+        }
+        return Some(tld);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROG;
+
+    fn load(name: &str, text: &str) -> anyhow::Result<TldList> {
+        let path =
+            std::env::temp_dir().join(format!(".{}-iana-{}.{}", PROG, name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let list = TldList::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        return list;
+    }
+
+    #[test]
+    fn loads_the_published_form() {
+        let list = load(
+            "published",
+            "# Version 2024010100, Last Updated Mon Jan  1 07:07:01 2024 UTC\nCOM\nNET\n\nXN--FIQS8S\n",
+        )
+        .unwrap();
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.undelegated("www.example.com"), None);
+        assert_eq!(list.undelegated("example.xn--fiqs8s"), None);
+    }
+
+    #[test]
+    fn undelegated_tlds() {
+        let list = load("undelegated", "com\nnet\n").unwrap();
+        assert_eq!(list.undelegated("printer.local"), Some("local"));
+        assert_eq!(list.undelegated("host.corp"), Some("corp"));
+        assert_eq!(list.undelegated("localhost"), Some("localhost"));
+        assert_eq!(list.undelegated("Example.COM"), None);
+        assert_eq!(list.undelegated("com"), None);
+    }
+
+    #[test]
+    fn empty_list_is_refused() {
+        let err = load("empty", "# Version 1\n\n").err().unwrap();
+        assert!(err.to_string().contains("no TLDs"));
+    }
+}
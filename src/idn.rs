@@ -0,0 +1,230 @@
+//! `--idna-strictness`: what to do with internationalized hosts, those with
+//! non-ASCII characters or `xn--` labels, that fail validation.
+//!
+//! A host is valid when it converts to ASCII under the strict IDNA rules
+//! (with the STD3 character rules and DNS lengths) and none of its labels
+//! mixes Latin, Greek and Cyrillic letters, the usual makeup of homograph
+//! look-alikes such as `pаypal` with a Cyrillic `а`.  Valid hosts are
+//! matched in their ASCII form.  An invalid one is rejected (`strict`),
+//! passed through unchanged (`transitional`), or converted to ASCII as far
+//! as the lenient rules allow, falling back to passing it through
+//! (`lenient`).
+//!
+//! Without `--idna-strictness`, hosts with non-ASCII characters are
+//! converted with [`to_ascii`], and rejected if they do not convert.
+//!
+//! The conversion maps hosts by UTS #46 first, which puts them in NFC,
+//! folds case and maps compatibility characters (`ｅｘａｍｐｌｅ` to
+//! `example`), so that the composed `bücher` and the decomposed
+//! `bu\u{308}cher` both become `xn--bcher-kva`.  `--unicode-normalization`
+//! does the same for the hosts kept as they are: `nfc` composes them and
+//! `nfkc` also maps compatibility characters, both lowercasing them too.
+
+use clap::ValueEnum;
+use std::borrow::Cow;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum IdnaStrictness {
+    Strict,
+    Transitional,
+    Lenient,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Normalization {
+    Nfc,
+    Nfkc,
+}
+
+/// `host` in the normalization form `form`, lowercased.
+pub fn normalize(host: &str, form: Normalization) -> String {
+    let normalizer = match form {
+        Normalization::Nfc => icu_normalizer::ComposingNormalizerBorrowed::new_nfc(),
+        Normalization::Nfkc => icu_normalizer::ComposingNormalizerBorrowed::new_nfkc(),
+    };
+    // Lowercasing can decompose (`İ` to `i̇`), so it comes first.
+    return normalizer.normalize(&host.to_lowercase()).into_owned();
+}
+
+/// What became of an internationalized host.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Valid,
+    Rejected,
+    Raw,
+    Converted,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+}
+
+fn script(c: char) -> Option<Script> {
+    match c {
+        'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' => Some(Script::Latin),
+        '\u{0370}'..='\u{03FF}' => Some(Script::Greek),
+        '\u{0400}'..='\u{04FF}' => Some(Script::Cyrillic),
+        _ => None,
+    }
+}
+
+fn is_mixed_script(label: &str) -> bool {
+    let mut seen = None;
+    for c in label.chars() {
+        match (seen, script(c)) {
+            (_, None) => {}
+            (None, Some(s)) => seen = Some(s),
+            (Some(a), Some(b)) if a != b => return true,
+            _ => {}
+        }
+    }
+    return false;
+}
+
+/// The ASCII form of `host` if it is valid.
+fn valid_ascii(host: &str) -> Option<String> {
+    let ascii = idna::domain_to_ascii_strict(host).ok()?;
+    let (unicode, result) = idna::domain_to_unicode(host);
+    if result.is_err() || unicode.split('.').any(is_mixed_script) {
+        return None;
+    }
+    return Some(ascii);
+}
+
+/// Converts a host with non-ASCII characters to its ASCII (punycode) form,
+/// `bücher.example` to `xn--bcher-kva.example`, after the UTS #46 mapping
+/// (which also lowercases and normalizes it).  Hosts that map to spaces or
+/// the other characters forbidden in URL hosts do not convert.
+pub fn to_ascii(host: &str) -> Option<String> {
+    return idna::domain_to_ascii_cow(host.as_bytes(), idna::AsciiDenyList::URL)
+        .ok()
+        .map(Cow::into_owned);
+}
+
+/// Whether every `xn--` label of `name` decodes from punycode to non-ASCII
+/// text that encodes back to the same label (in any case); malformed
+/// labels such as `xn--abc-` or `xn--zz` do not.
+pub fn punycode_roundtrips(name: &str) -> bool {
+    for label in name.split('.') {
+        // By `get`, as a label can have non-ASCII text in its first bytes.
+        if !label
+            .get(..4)
+            .is_some_and(|p| p.eq_ignore_ascii_case("xn--"))
+        {
+            continue;
+        }
+        let encoded = &label[4..];
+        let ok = match idna::punycode::decode_to_string(encoded) {
+            Some(decoded) if !decoded.is_ascii() => idna::punycode::encode_str(&decoded)
+                .is_some_and(|again| again.eq_ignore_ascii_case(encoded)),
+            _ => false,
+        };
+        if !ok {
+            return false;
+        }
+    }
+    return true;
+}
+
+/// Checks `host` if it is internationalized, returning nothing for a plain
+/// ASCII host, and otherwise the outcome and the host to match (unless
+/// rejected).
+pub fn check(host: &str, strictness: IdnaStrictness) -> Option<(Outcome, Option<Cow<'_, str>>)> {
+    if host.is_ascii()
+        && !host
+            .split('.')
+            .any(|l| l.len() >= 4 && l[..4].eq_ignore_ascii_case("xn--"))
+    {
+        return None;
+    }
+    if let Some(ascii) = valid_ascii(host) {
+        if ascii == host {
+            return Some((Outcome::Valid, Some(Cow::Borrowed(host))));
+        }
+        return Some((Outcome::Valid, Some(Cow::Owned(ascii))));
+    }
+    return Some(match strictness {
+        IdnaStrictness::Strict => (Outcome::Rejected, None),
+        IdnaStrictness::Transitional => (Outcome::Raw, Some(Cow::Borrowed(host))),
+        IdnaStrictness::Lenient => match idna::domain_to_ascii(host) {
+            Ok(ascii) if ascii != host => (Outcome::Converted, Some(Cow::Owned(ascii))),
+            Ok(_) => (Outcome::Raw, Some(Cow::Borrowed(host))),
+            Err(_) => (Outcome::Raw, Some(Cow::Borrowed(host))),
+        },
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, normalize, punycode_roundtrips, to_ascii, IdnaStrictness, Normalization};
+    use std::borrow::Cow;
+
+    const COMPOSED: &str = "b\u{fc}cher.example";
+    const DECOMPOSED: &str = "bu\u{308}cher.example";
+
+    #[test]
+    fn equivalent_forms_convert_alike() {
+        assert_eq!(to_ascii(COMPOSED).as_deref(), Some("xn--bcher-kva.example"));
+        assert_eq!(
+            to_ascii(DECOMPOSED).as_deref(),
+            Some("xn--bcher-kva.example")
+        );
+        assert_eq!(
+            to_ascii("B\u{dc}CHER.example").as_deref(),
+            Some("xn--bcher-kva.example")
+        );
+        // Fullwidth letters are compatibility forms of ASCII.
+        let fullwidth = "\u{ff45}\u{ff58}\u{ff41}\u{ff4d}\u{ff50}\u{ff4c}\u{ff45}.com";
+        assert_eq!(to_ascii(fullwidth).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn normalization_forms() {
+        assert_eq!(normalize(DECOMPOSED, Normalization::Nfc), COMPOSED);
+        assert_eq!(normalize(COMPOSED, Normalization::Nfc), COMPOSED);
+        assert_eq!(
+            normalize("BU\u{308}CHER.example", Normalization::Nfc),
+            COMPOSED
+        );
+        // Only NFKC maps the ligature.
+        assert_eq!(
+            normalize("\u{fb01}.example", Normalization::Nfc),
+            "\u{fb01}.example"
+        );
+        assert_eq!(
+            normalize("\u{fb01}.example", Normalization::Nfkc),
+            "fi.example"
+        );
+    }
+
+    #[test]
+    fn kept_hosts_match_once_normalized() {
+        // Mixed scripts fail the check, so transitional keeps the host.
+        let host = "pa\u{0443}pal-bu\u{308}cher.example";
+        let kept = |host: &str| match check(host, IdnaStrictness::Transitional) {
+            Some((_, Some(Cow::Borrowed(kept)))) => kept.to_string(),
+            _ => panic!("{:?} not kept", host),
+        };
+        assert_ne!(kept(host), kept(&host.replace("u\u{308}", "\u{fc}")));
+        let nfc = normalize(host, Normalization::Nfc);
+        assert_eq!(kept(&nfc), "pa\u{0443}pal-b\u{fc}cher.example");
+    }
+
+    #[test]
+    fn roundtrips() {
+        assert!(punycode_roundtrips("www.xn--bcher-kva.example"));
+        assert!(punycode_roundtrips("XN--BCHER-KVA.example"));
+        assert!(!punycode_roundtrips("xn--abc-.example"));
+        assert!(!punycode_roundtrips("xn--zz.example"));
+    }
+
+    #[test]
+    fn non_ascii_labels() {
+        assert!(punycode_roundtrips("www.aaaüb.com"));
+        assert!(punycode_roundtrips("ü.example"));
+        assert!(punycode_roundtrips("bücher"));
+    }
+}
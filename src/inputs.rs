@@ -0,0 +1,74 @@
+//! `--input`: further input files read after INPUT_FILE, so that the
+//! shards of a dataset go through one run, with one output, one rejected
+//! file and one stats line.
+//!
+//! The inputs are read one after the other as a single stream of records,
+//! each decompressed on its own, and opened only once the one before is
+//! done.  A file whose last line has no newline gets one, so that its last
+//! record does not run into the first of the next file.
+
+use std::io::{self, Read};
+use std::path::Path;
+
+pub struct Chain<'a, F> {
+    paths: &'a [&'a Path],
+    open: F,
+    next: usize,
+    current: Option<Box<dyn Read + 'a>>,
+    at_line_start: bool,
+}
+
+impl<'a, F> Chain<'a, F>
+where
+    F: FnMut(&Path) -> anyhow::Result<Box<dyn Read + 'a>>,
+{
+    /// Reads `paths` in turn, opening each with `open`.
+    pub fn new(paths: &'a [&'a Path], open: F) -> Chain<'a, F> {
+        return Chain {
+            paths,
+            open,
+            next: 0,
+            current: None,
+            at_line_start: true,
+        };
+    }
+}
+
+impl<'a, F> Read for Chain<'a, F>
+where
+    F: FnMut(&Path) -> anyhow::Result<Box<dyn Read + 'a>>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let current = match &mut self.current {
+                Some(current) => current,
+                None => {
+                    let path = match self.paths.get(self.next) {
+                        Some(path) => path,
+                        None => return Ok(0),
+                    };
+                    if !self.at_line_start {
+                        self.at_line_start = true;
+                        buf[0] = b'\n';
+                        return Ok(1);
+                    }
+                    self.next += 1;
+                    log::debug!("reading {}", path.display());
+                    let file =
+                        (self.open)(path).map_err(|e| io::Error::other(format!("{:#}", e)))?;
+                    self.current.insert(file)
+                }
+            };
+            let n = current.read(buf)?;
+            if n == 0 {
+                self.current = None;
+                continue;
+            }
+            self.at_line_start = buf[n - 1] == b'\n';
+            return Ok(n);
+        }
+    }
+}
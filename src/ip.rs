@@ -0,0 +1,319 @@
+//! Parsing of the `name` field of a record into an address, and the
+//! conversion of the address to the integer written in the output.
+//!
+//! By default the address is written as the unsigned 32-bit integer whose
+//! big-endian (network order) bytes are the four octets: `1.2.3.4` is
+//! `0x01020304`, i.e. `16909060`.  `--ip-endianness little` reverses the
+//! octets (`0x04030201`, `67305985`) and `--ip-signed` reinterprets the same
+//! 32 bits as a two's complement integer, so `255.255.255.255` is `-1`.
+//!
+//! A `name` with a colon is read as an IPv6 address, written in the same
+//! way as a 128-bit integer: `::1` is `1`.  `--ip-format hex` writes the
+//! bits in hexadecimal instead, zero-padded to 8 digits for IPv4 and 32 for
+//! IPv6 (`01020304`); the sign does not apply to it.  `--ip-format dotted`
+//! writes the address as text (`1.2.3.4`, `::1`), to which neither the byte
+//! order nor the sign applies.
+//!
+//! With `--in-addr-arpa`, a `name` that is a PTR owner name such as
+//! `4.3.2.1.in-addr.arpa` is read as the address it stands for, `1.2.3.4`.
+//!
+//! With `--lenient-ips`, the historical `inet_aton(3)` forms are accepted
+//! too: hexadecimal (`0x0A000001`), a single 32-bit decimal (`167772161`),
+//! and fewer than four parts (`10.1` is `10.0.0.1`).  Parts with a leading
+//! zero are octal to `inet_aton` but decimal to most people, so they are only
+//! accepted when both readings agree.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// The byte order the bits of an address are read in.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Endianness {
+    /// The first octet is the most significant byte (network order).
+    #[value(alias = "network")]
+    Big,
+    /// The first octet is the least significant byte.
+    Little,
+}
+
+/// How an address is written.
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum IpFormat {
+    /// A decimal integer, of 32 bits for IPv4 and 128 bits for IPv6
+    #[value(alias = "u32")]
+    Decimal,
+    /// The bits in hexadecimal, 8 digits for IPv4 and 32 for IPv6
+    Hex,
+    /// The address as text: dotted quad for IPv4, RFC 5952 form for IPv6
+    Dotted,
+}
+
+/// The value written for an address.
+#[derive(Clone, Copy)]
+pub enum IpValue {
+    Unsigned(u128),
+    Signed(i128),
+    /// The bits and the number of digits to pad them to.
+    Hex(u128, usize),
+    Dotted(IpAddr),
+}
+
+impl fmt::Display for IpValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IpValue::Unsigned(n) => return write!(f, "{}", n),
+            IpValue::Signed(n) => return write!(f, "{}", n),
+            IpValue::Hex(n, width) => return write!(f, "{:0width$x}", n, width = width),
+            IpValue::Dotted(addr) => return write!(f, "{}", addr),
+        }
+    }
+}
+
+const IN_ADDR_ARPA: &str = ".in-addr.arpa";
+
+/// Parses a decimal octet, without the sign or surrounding whitespace that
+/// `u8::from_str` tolerates.
+fn parse_octet(s: &str) -> Option<u8> {
+    if s.is_empty() || s.len() > 3 || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    return s.parse().ok();
+}
+
+/// Parses an `in-addr.arpa` name (with or without the trailing root dot)
+/// into the address it stands for; the octets are stored in reverse order.
+pub fn parse_in_addr_arpa(name: &str) -> Option<Ipv4Addr> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    let split = name.len().checked_sub(IN_ADDR_ARPA.len())?;
+    let (octets, suffix) = (name.get(..split)?, name.get(split..)?);
+    if !suffix.eq_ignore_ascii_case(IN_ADDR_ARPA) {
+        return None;
+    }
+    let octets: Vec<&str> = octets.split('.').collect();
+    if octets.len() != 4 {
+        return None;
+    }
+    let mut addr = [0u8; 4];
+    for (i, octet) in octets.iter().rev().enumerate() {
+        addr[i] = parse_octet(octet)?;
+    }
+    return Some(Ipv4Addr::from(addr));
+}
+
+/// Parses one part of a lenient address: hexadecimal after `0x`, octal or
+/// decimal when both agree, decimal otherwise.
+fn parse_lenient_part(s: &str) -> Result<u32, &'static str> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        if hex.is_empty() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err("invalid-ip");
+        }
+        return u32::from_str_radix(hex, 16).map_err(|_| "invalid-ip");
+    }
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return Err("invalid-ip");
+    }
+    let decimal = s.parse::<u32>().map_err(|_| "invalid-ip")?;
+    if s.len() > 1 && s.starts_with('0') && u32::from_str_radix(s, 8) != Ok(decimal) {
+        return Err("ambiguous-ip");
+    }
+    return Ok(decimal);
+}
+
+/// Parses `name` in any of the forms accepted by `inet_aton(3)`.
+pub fn parse_lenient(name: &str) -> Result<Ipv4Addr, &'static str> {
+    let parts = name
+        .split('.')
+        .map(parse_lenient_part)
+        .collect::<Result<Vec<u32>, &'static str>>()?;
+    // All parts but the last are single octets; the last fills the rest.
+    let (last, octets) = match parts.split_last() {
+        Some(split) => split,
+        None => return Err("invalid-ip"),
+    };
+    if octets.len() > 3 || octets.iter().any(|&o| o > 0xff) {
+        return Err("invalid-ip");
+    }
+    let last_bits = 32 - 8 * octets.len() as u32;
+    if last_bits < 32 && *last >= 1 << last_bits {
+        return Err("invalid-ip");
+    }
+    let mut value: u32 = *last;
+    for (i, &octet) in octets.iter().enumerate() {
+        value |= octet << (24 - 8 * i);
+    }
+    return Ok(Ipv4Addr::from(value));
+}
+
+/// Parses the `name` field of a record into an IP address; the error is
+/// the reject reason.
+pub fn parse_name(name: &str, in_addr_arpa: bool, lenient: bool) -> Result<IpAddr, &'static str> {
+    if name.contains(':') {
+        return Ipv6Addr::from_str(name)
+            .map(IpAddr::V6)
+            .map_err(|_| "invalid-ip");
+    }
+    if in_addr_arpa {
+        if let Some(addr) = parse_in_addr_arpa(name) {
+            return Ok(IpAddr::V4(addr));
+        }
+    }
+    if lenient {
+        return parse_lenient(name).map(IpAddr::V4);
+    }
+    return Ipv4Addr::from_str(name)
+        .map(IpAddr::V4)
+        .map_err(|_| "invalid-ip");
+}
+
+/// Returns the value written for `addr`; that of `1.2.3.4` as an unsigned
+/// big-endian decimal displays as `16909060`.
+pub fn ip_value(addr: IpAddr, format: IpFormat, endianness: Endianness, signed: bool) -> IpValue {
+    if format == IpFormat::Dotted {
+        return IpValue::Dotted(addr);
+    }
+    let (bits, width) = match (addr, endianness) {
+        (IpAddr::V4(a), Endianness::Big) => (u32::from_be_bytes(a.octets()) as u128, 8),
+        (IpAddr::V4(a), Endianness::Little) => (u32::from_le_bytes(a.octets()) as u128, 8),
+        (IpAddr::V6(a), Endianness::Big) => (u128::from_be_bytes(a.octets()), 32),
+        (IpAddr::V6(a), Endianness::Little) => (u128::from_le_bytes(a.octets()), 32),
+    };
+    if format == IpFormat::Hex {
+        return IpValue::Hex(bits, width);
+    }
+    if signed {
+        return match addr {
+            IpAddr::V4(_) => IpValue::Signed(bits as u32 as i32 as i128),
+            IpAddr::V6(_) => IpValue::Signed(bits as i128),
+        };
+    }
+    return IpValue::Unsigned(bits);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(s: &str) -> IpAddr {
+        return IpAddr::V4(s.parse().unwrap());
+    }
+
+    fn value(name: &str, format: IpFormat, endianness: Endianness, signed: bool) -> String {
+        return ip_value(
+            parse_name(name, false, false).unwrap(),
+            format,
+            endianness,
+            signed,
+        )
+        .to_string();
+    }
+
+    #[test]
+    fn dotted() {
+        assert_eq!(parse_name("1.2.3.4", false, false), Ok(v4("1.2.3.4")));
+        assert_eq!(parse_name("::1", false, false), Ok("::1".parse().unwrap()));
+        assert_eq!(parse_name("1.2.3", false, false), Err("invalid-ip"));
+        assert_eq!(parse_name("0x01020304", false, false), Err("invalid-ip"));
+        assert_eq!(parse_name("1:2:3", false, false), Err("invalid-ip"));
+    }
+
+    #[test]
+    fn out_of_range_octets() {
+        assert_eq!(parse_name("256.1.1.1", false, false), Err("invalid-ip"));
+        assert_eq!(parse_name("1.1.1.256", false, false), Err("invalid-ip"));
+        assert_eq!(parse_lenient("256.1.1.1"), Err("invalid-ip"));
+        assert_eq!(parse_lenient("1.1.1.256"), Err("invalid-ip"));
+        assert_eq!(parse_lenient("1.0x100.1.1"), Err("invalid-ip"));
+        assert_eq!(parse_lenient("1.2.65536"), Err("invalid-ip"));
+        assert_eq!(parse_lenient("4294967296"), Err("invalid-ip"));
+        assert_eq!(parse_in_addr_arpa("256.3.2.1.in-addr.arpa"), None);
+    }
+
+    #[test]
+    fn integer() {
+        assert_eq!(parse_lenient("16909060"), Ok(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(parse_lenient("0x01020304"), Ok(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(parse_lenient("4294967295"), Ok(Ipv4Addr::BROADCAST));
+        assert_eq!(parse_name("167772161", false, true), Ok(v4("10.0.0.1")));
+        assert_eq!(parse_name("167772161", false, false), Err("invalid-ip"));
+    }
+
+    #[test]
+    fn lenient() {
+        assert_eq!(parse_lenient("10.1"), Ok(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(parse_lenient("10.1.2"), Ok(Ipv4Addr::new(10, 1, 0, 2)));
+        assert_eq!(
+            parse_lenient("10.65535"),
+            Ok(Ipv4Addr::new(10, 0, 255, 255))
+        );
+        assert_eq!(parse_lenient("0xA.0.0.1"), Ok(Ipv4Addr::new(10, 0, 0, 1)));
+        // Leading zeros: both readings agree, or the part is ambiguous.
+        assert_eq!(parse_lenient("01.02.03.04"), Ok(Ipv4Addr::new(1, 2, 3, 4)));
+        assert_eq!(parse_lenient("010.0.0.1"), Err("ambiguous-ip"));
+        assert_eq!(parse_lenient("08.0.0.1"), Err("ambiguous-ip"));
+        assert_eq!(parse_lenient("1.2.3.4.5"), Err("invalid-ip"));
+        assert_eq!(parse_lenient("1..2"), Err("invalid-ip"));
+        assert_eq!(parse_lenient("0x"), Err("invalid-ip"));
+        assert_eq!(parse_lenient("-1"), Err("invalid-ip"));
+    }
+
+    #[test]
+    fn in_addr_arpa() {
+        assert_eq!(
+            parse_name("4.3.2.1.in-addr.arpa.", true, false),
+            Ok(v4("1.2.3.4"))
+        );
+        assert_eq!(
+            parse_in_addr_arpa("4.3.2.1.IN-ADDR.ARPA"),
+            Some(Ipv4Addr::new(1, 2, 3, 4))
+        );
+        assert_eq!(parse_in_addr_arpa("3.2.1.in-addr.arpa"), None);
+        assert_eq!(parse_in_addr_arpa("+4.3.2.1.in-addr.arpa"), None);
+        assert_eq!(
+            parse_name("4.3.2.1.in-addr.arpa", false, false),
+            Err("invalid-ip")
+        );
+    }
+
+    #[test]
+    fn endianness() {
+        use Endianness::{Big, Little};
+        use IpFormat::{Decimal, Dotted, Hex};
+        assert_eq!(value("1.2.3.4", Decimal, Big, false), "16909060");
+        assert_eq!(value("1.2.3.4", Decimal, Little, false), "67305985");
+        assert_eq!(value("1.2.3.4", Hex, Big, false), "01020304");
+        assert_eq!(value("1.2.3.4", Hex, Little, false), "04030201");
+        assert_eq!(value("1.2.3.4", Dotted, Little, true), "1.2.3.4");
+        assert_eq!(value("0.0.0.1", Hex, Big, false), "00000001");
+        assert_eq!(value("::1", Decimal, Big, false), "1");
+        assert_eq!(
+            value("::1", Decimal, Little, false),
+            (1u128 << 120).to_string()
+        );
+        assert_eq!(
+            value("::1", Hex, Big, false),
+            "00000000000000000000000000000001"
+        );
+    }
+
+    #[test]
+    fn signed() {
+        use Endianness::{Big, Little};
+        assert_eq!(value("255.255.255.255", IpFormat::Decimal, Big, true), "-1");
+        assert_eq!(
+            value("128.0.0.0", IpFormat::Decimal, Big, true),
+            "-2147483648"
+        );
+        assert_eq!(value("128.0.0.0", IpFormat::Decimal, Little, true), "128");
+        assert_eq!(
+            value("0.0.0.128", IpFormat::Decimal, Little, true),
+            "-2147483648"
+        );
+        assert_eq!(value("1.2.3.4", IpFormat::Decimal, Big, true), "16909060");
+        // The sign does not apply to hex.
+        assert_eq!(
+            value("255.255.255.255", IpFormat::Hex, Big, true),
+            "ffffffff"
+        );
+    }
+}
@@ -0,0 +1,462 @@
+//! Extraction of the registrable domain of a hostname, given the set of
+//! public suffix rules (the lines of the Public Suffix List).
+//!
+//! [`extract`] splits a host into its subdomain, registrable domain and
+//! public suffix, and says which case the host fell into, so that callers
+//! can decide for themselves what to do with hosts that are public suffixes,
+//! match no rule, or are malformed.  [`extract_domain`] is the shorthand for
+//! when only the registrable domain is wanted.
+//!
+//! The parts of a matched host are slices of it; [`ExtractedRef::to_owned`]
+//! copies them into an [`Extracted`] that can outlive the host's buffer.
+//!
+//! [`Psl::parse`] reads the list and [`compiled`] its binary form, and
+//! [`ip`] parses the address of a record (`1.2.3.4`, `4.3.2.1.in-addr.arpa`, an IPv6
+//! address) and converts it to the integer written for it.
+
+#![allow(clippy::needless_return)]
+
+pub mod compiled;
+pub mod ip;
+mod psl;
+
+use std::fmt;
+
+pub use psl::{Psl, SharedPsl};
+
+/// The `log` target of the steps logged by [`extract_traced`], so that a
+/// logger can show them whatever level it keeps otherwise.
+pub const TRACE_TARGET: &str = "vfb_tldextract::trace";
+
+/// The outcome of matching a host against the suffix rules.  All parts are
+/// slices of the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtractResult<'a> {
+    /// `www.example.co.uk` is subdomain `www`, domain `example` and suffix
+    /// `co.uk`.  The subdomain is empty when the host is the registrable
+    /// domain itself.
+    Matched {
+        subdomain: &'a str,
+        domain: &'a str,
+        suffix: &'a str,
+    },
+    /// The host is itself a public suffix (`co.uk`), and so has no
+    /// registrable domain.
+    SuffixOnly { suffix: &'a str },
+    /// No rule matches any ending of the host.
+    NoMatch,
+    /// The host cannot be matched at all; the value says why, e.g.
+    /// `empty-label`.
+    Invalid(&'static str),
+}
+
+impl<'a> ExtractResult<'a> {
+    /// The parts of a matched host, borrowed from it.
+    pub fn extracted(&self) -> Option<ExtractedRef<'a>> {
+        match *self {
+            ExtractResult::Matched {
+                subdomain,
+                domain,
+                suffix,
+            } => Some(ExtractedRef {
+                subdomain,
+                domain,
+                suffix,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The parts of a matched host, borrowed from the host itself so that hot
+/// loops need not allocate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ExtractedRef<'a> {
+    pub subdomain: &'a str,
+    pub domain: &'a str,
+    pub suffix: &'a str,
+}
+
+impl ExtractedRef<'_> {
+    /// Copies the parts, for callers that keep them beyond the lifetime of
+    /// the buffer the host was read into.
+    pub fn to_owned(&self) -> Extracted {
+        return Extracted {
+            subdomain: self.subdomain.to_string(),
+            domain: self.domain.to_string(),
+            suffix: self.suffix.to_string(),
+        };
+    }
+}
+
+/// The owned counterpart of [`ExtractedRef`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Extracted {
+    pub subdomain: String,
+    pub domain: String,
+    pub suffix: String,
+}
+
+impl Extracted {
+    pub fn as_ref(&self) -> ExtractedRef<'_> {
+        return ExtractedRef {
+            subdomain: &self.subdomain,
+            domain: &self.domain,
+            suffix: &self.suffix,
+        };
+    }
+}
+
+impl From<ExtractedRef<'_>> for Extracted {
+    fn from(parts: ExtractedRef<'_>) -> Extracted {
+        return parts.to_owned();
+    }
+}
+
+fn rfind_from(s: &str, c: char, offset: usize) -> Option<usize> {
+    s[..offset].rfind(c)
+}
+
+/// How the rules bear on `s`, an ending of `host` of one or more labels.
+#[derive(Clone, Copy, PartialEq)]
+enum Rule {
+    /// An exception rule names `s`, so that its parent is the suffix.
+    Exception,
+    /// `s` is a suffix: it has a rule of its own, falls under a wildcard on
+    /// its parent, or is itself the parent of a wildcard.
+    Suffix,
+    None,
+}
+
+fn rule_for(host: &str, s: &str, suffixes: &Psl, trace: bool) -> Rule {
+    if suffixes.has_exception(s) {
+        if trace {
+            log::info!(target: TRACE_TARGET, "trace {}: exception rule \"!{}\"", host, s);
+        }
+        return Rule::Exception;
+    }
+    if suffixes.contains(s) {
+        if trace {
+            log::info!(target: TRACE_TARGET, "trace {}: rule {:?} matches", host, s);
+        }
+        return Rule::Suffix;
+    }
+    if let Some((_, parent)) = s.split_once('.') {
+        if suffixes.has_wildcard(parent) {
+            if trace {
+                log::info!(
+                    target: TRACE_TARGET,
+                    "trace {}: wildcard rule \"*.{}\" matches {:?}",
+                    host,
+                    parent,
+                    s
+                );
+            }
+            return Rule::Suffix;
+        }
+    }
+    if suffixes.has_wildcard(s) {
+        if trace {
+            log::info!(target: TRACE_TARGET, "trace {}: {:?} has wildcard rule \"*.{}\"", host, s, s);
+        }
+        return Rule::Suffix;
+    }
+    if trace {
+        log::info!(target: TRACE_TARGET, "trace {}: no rule for {:?}", host, s);
+    }
+    return Rule::None;
+}
+
+/// Matches `host` against `suffixes`, comparing labels exactly as written;
+/// a trailing root dot should be removed first.
+pub fn extract<'a>(host: &'a str, suffixes: &Psl) -> ExtractResult<'a> {
+    return extract_traced(host, suffixes, false);
+}
+
+/// The registrable domain of `host` (`example.co.uk` for
+/// `www.example.co.uk`), or `None` when the host has none: it matches no
+/// rule, is a public suffix itself, or is malformed.
+pub fn extract_domain<'a>(host: &'a str, suffixes: &Psl) -> Option<&'a str> {
+    match extract(host, suffixes) {
+        ExtractResult::Matched { domain, suffix, .. } => {
+            let start = host.len() - (domain.len() + 1 + suffix.len());
+            return Some(&host[start..]);
+        }
+        _ => return None,
+    }
+}
+
+/// A rule found by [`matched_rule`]: the name it is written with, borrowed
+/// from the host, and its marker, so that matching a row need not
+/// allocate.  It displays as written in the list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MatchedRule<'a> {
+    /// `""`, `"*."` for a wildcard or `"!"` for an exception.
+    pub marker: &'static str,
+    pub name: &'a str,
+}
+
+impl MatchedRule<'_> {
+    /// Whether the rule is from the PRIVATE section of `suffixes`.
+    pub fn is_private(&self, suffixes: &Psl) -> bool {
+        if self.marker.is_empty() {
+            return suffixes.is_private(self.name);
+        }
+        return suffixes.is_private(&self.to_string());
+    }
+}
+
+impl fmt::Display for MatchedRule<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}{}", self.marker, self.name);
+    }
+}
+
+/// The rule that made `suffix` the public suffix of a host whose
+/// registrable domain is `registrable` (empty for a host that is a suffix
+/// itself): `co.uk`, `*.ck` for `foo.ck`, or `!www.ck` for `www.ck`, where
+/// the exception is what ended the suffix.
+pub fn matched_rule<'a>(
+    registrable: &'a str,
+    suffix: &'a str,
+    suffixes: &Psl,
+) -> Option<MatchedRule<'a>> {
+    let rule = |marker, name| Some(MatchedRule { marker, name });
+    if !registrable.is_empty() && suffixes.has_exception(registrable) {
+        return rule("!", registrable);
+    }
+    if suffixes.contains(suffix) {
+        return rule("", suffix);
+    }
+    if let Some((_, parent)) = suffix.split_once('.') {
+        if suffixes.has_wildcard(parent) {
+            return rule("*.", parent);
+        }
+    }
+    if suffixes.has_wildcard(suffix) {
+        return rule("*.", suffix);
+    }
+    return None;
+}
+
+/// Like [`extract`], but when `trace` is set each step of the suffix
+/// matching is logged at the info level, with the target [`TRACE_TARGET`].
+pub fn extract_traced<'a>(host: &'a str, suffixes: &Psl, trace: bool) -> ExtractResult<'a> {
+    if host.is_empty() {
+        return ExtractResult::Invalid("empty-host");
+    }
+    if host.starts_with('.') || host.ends_with('.') || host.contains("..") {
+        return ExtractResult::Invalid("empty-label");
+    }
+
+    // Every ending of the host is looked up, shortest first, as a rule for
+    // a longer ending need not have rules for the endings within it
+    // (`s3.amazonaws.com` without `amazonaws.com`).  The longest match is
+    // the suffix, unless an exception rule ends it first.  `start` is where
+    // the longest suffix so far begins, `frontier` the dot before the
+    // ending last looked up.
+    let mut start: Option<usize> = None;
+    let mut frontier: usize = host.len();
+    loop {
+        let (s, idx) = match rfind_from(host, '.', frontier) {
+            Some(idx) => (&host[idx + 1..], Some(idx)),
+            None => (host, None),
+        };
+        match rule_for(host, s, suffixes, trace) {
+            // An exception on a single label leaves no parent to be the
+            // suffix, and is ignored.
+            Rule::Exception if frontier < host.len() => {
+                start = Some(frontier + 1);
+                break;
+            }
+            Rule::Suffix => start = Some(idx.map_or(0, |idx| idx + 1)),
+            _ => {}
+        }
+        match idx {
+            Some(idx) => frontier = idx,
+            None => break,
+        }
+    }
+
+    let frontier = match start {
+        Some(0) => {
+            if trace {
+                log::info!(target: TRACE_TARGET, "trace {}: host is itself a suffix", host);
+            }
+            return ExtractResult::SuffixOnly { suffix: host };
+        }
+        Some(start) => start - 1,
+        None => {
+            if trace {
+                log::info!(target: TRACE_TARGET, "trace {}: no suffix matched, no domain", host);
+            }
+            return ExtractResult::NoMatch;
+        }
+    };
+
+    // host[frontier..] is the tld, now let's find the domain.
+    let start = match rfind_from(host, '.', frontier) {
+        Some(idx) => idx + 1,
+        None => 0,
+    };
+    let (subdomain, domain, suffix) = (
+        &host[..start.saturating_sub(1)],
+        &host[start..frontier],
+        &host[frontier + 1..],
+    );
+    if trace {
+        log::info!(target: TRACE_TARGET, "trace {}: suffix {:?}, domain {:?}", host, suffix, domain);
+    }
+    return ExtractResult::Matched {
+        subdomain,
+        domain,
+        suffix,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn psl(rules: &str) -> Psl {
+        return Psl::parse(rules.as_bytes()).unwrap();
+    }
+
+    fn parts<'a>(host: &'a str, suffixes: &Psl) -> Option<(&'a str, &'a str, &'a str)> {
+        let parts = extract(host, suffixes).extracted()?;
+        return Some((parts.subdomain, parts.domain, parts.suffix));
+    }
+
+    #[test]
+    fn registrable_domains() {
+        let suffixes = psl("com\nuk\nco.uk\n*.ck\n!www.ck\n");
+        assert_eq!(
+            extract_domain("www.example.co.uk", &suffixes),
+            Some("example.co.uk")
+        );
+        assert_eq!(
+            extract_domain("example.com", &suffixes),
+            Some("example.com")
+        );
+        assert_eq!(
+            extract_domain("a.b.example.uk", &suffixes),
+            Some("example.uk")
+        );
+        assert_eq!(extract_domain("foo.bar.ck", &suffixes), Some("foo.bar.ck"));
+        assert_eq!(extract_domain("www.ck", &suffixes), Some("www.ck"));
+        // A suffix itself, no rule at all, or a malformed host.
+        assert_eq!(extract_domain("co.uk", &suffixes), None);
+        assert_eq!(extract_domain("bar.ck", &suffixes), None);
+        assert_eq!(extract_domain("example.org", &suffixes), None);
+        assert_eq!(extract_domain("a..example.com", &suffixes), None);
+        assert_eq!(extract_domain("", &suffixes), None);
+    }
+
+    #[test]
+    fn plain_rules() {
+        let suffixes = psl("com\nuk\nco.uk\n");
+        assert_eq!(
+            parts("www.example.co.uk", &suffixes),
+            Some(("www", "example", "co.uk"))
+        );
+        assert_eq!(
+            parts("example.com", &suffixes),
+            Some(("", "example", "com"))
+        );
+        assert_eq!(
+            extract("co.uk", &suffixes),
+            ExtractResult::SuffixOnly { suffix: "co.uk" }
+        );
+        assert_eq!(extract("example.org", &suffixes), ExtractResult::NoMatch);
+    }
+
+    #[test]
+    fn nested_private_rules() {
+        // No rule for amazonaws.com: the longer rules must still be found.
+        let suffixes = psl("com\n// ===BEGIN PRIVATE DOMAINS===\ns3.amazonaws.com\n\
+             s3.dualstack.us-east-1.amazonaws.com\n");
+        assert_eq!(
+            parts("bucket.s3.amazonaws.com", &suffixes),
+            Some(("", "bucket", "s3.amazonaws.com"))
+        );
+        assert_eq!(
+            parts("a.bucket.s3.dualstack.us-east-1.amazonaws.com", &suffixes),
+            Some(("a", "bucket", "s3.dualstack.us-east-1.amazonaws.com"))
+        );
+        assert_eq!(
+            parts("www.amazonaws.com", &suffixes),
+            Some(("www", "amazonaws", "com"))
+        );
+        assert_eq!(
+            parts("x.dualstack.us-east-1.amazonaws.com", &suffixes),
+            Some(("x.dualstack.us-east-1", "amazonaws", "com"))
+        );
+        // Without a rule for the last label, the longer rule still matches.
+        let suffixes = psl("s3.amazonaws.com\n");
+        assert_eq!(
+            parts("bucket.s3.amazonaws.com", &suffixes),
+            Some(("", "bucket", "s3.amazonaws.com"))
+        );
+    }
+
+    #[test]
+    fn wildcards() {
+        let suffixes = psl("jp\n*.kawasaki.jp\n");
+        assert_eq!(
+            parts("a.b.kawasaki.jp", &suffixes),
+            Some(("", "a", "b.kawasaki.jp"))
+        );
+        assert_eq!(
+            extract("b.kawasaki.jp", &suffixes),
+            ExtractResult::SuffixOnly {
+                suffix: "b.kawasaki.jp"
+            }
+        );
+        assert_eq!(
+            matched_rule("a.b.kawasaki.jp", "b.kawasaki.jp", &suffixes),
+            Some(MatchedRule {
+                marker: "*.",
+                name: "kawasaki.jp"
+            })
+        );
+    }
+
+    #[test]
+    fn exceptions() {
+        let suffixes = psl("jp\n*.kawasaki.jp\n!city.kawasaki.jp\n");
+        assert_eq!(
+            parts("www.city.kawasaki.jp", &suffixes),
+            Some(("www", "city", "kawasaki.jp"))
+        );
+        assert_eq!(
+            parts("city.kawasaki.jp", &suffixes),
+            Some(("", "city", "kawasaki.jp"))
+        );
+        assert_eq!(
+            matched_rule("city.kawasaki.jp", "kawasaki.jp", &suffixes)
+                .map(|rule| rule.to_string())
+                .as_deref(),
+            Some("!city.kawasaki.jp")
+        );
+        // The exception wins over a longer rule under it.
+        let suffixes = psl("jp\n*.kawasaki.jp\n!city.kawasaki.jp\nx.city.kawasaki.jp\n");
+        assert_eq!(
+            parts("a.x.city.kawasaki.jp", &suffixes),
+            Some(("a.x", "city", "kawasaki.jp"))
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let suffixes = psl("com\n");
+        assert_eq!(extract("", &suffixes), ExtractResult::Invalid("empty-host"));
+        assert_eq!(
+            extract(".", &suffixes),
+            ExtractResult::Invalid("empty-label")
+        );
+        assert_eq!(
+            extract("a..com", &suffixes),
+            ExtractResult::Invalid("empty-label")
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Checksums of the files a run reads and writes, and the OpenLineage
+//! record written with `--lineage` so that a data catalog can track where
+//! each output came from.
+
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PRODUCER: &str = "https://github.com/gnuvince/vfb-tldextract";
+const RUN_EVENT_SCHEMA: &str = "https://openlineage.io/spec/2-0-2/OpenLineage.json#/$defs/RunEvent";
+const FACET_SCHEMA: &str = "https://openlineage.io/spec/2-0-2/OpenLineage.json#/$defs/BaseFacet";
+
+/// A running SHA-256 of the bytes that pass through the readers and writers
+/// created from it.  Clones share the same state.
+#[derive(Clone)]
+pub struct Checksum {
+    state: Arc<Mutex<(Sha256, u64)>>,
+}
+
+/// The final digest of a file, along with its size in bytes.
+#[derive(Clone)]
+pub struct FileDigest {
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl Checksum {
+    pub fn new() -> Checksum {
+        return Checksum {
+            state: Arc::new(Mutex::new((Sha256::new(), 0))),
+        };
+    }
+
+    fn update(&self, bytes: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.0.update(bytes);
+        state.1 += bytes.len() as u64;
+    }
+
+    pub fn reader<R: Read>(&self, inner: R) -> ChecksumReader<R> {
+        return ChecksumReader {
+            inner,
+            checksum: self.clone(),
+        };
+    }
+
+    pub fn writer<W: Write>(&self, inner: W) -> ChecksumWriter<W> {
+        return ChecksumWriter {
+            inner,
+            checksum: self.clone(),
+        };
+    }
+
+    /// Returns the digest of everything seen so far.
+    pub fn finish(&self) -> FileDigest {
+        let state = self.state.lock().unwrap();
+        return FileDigest {
+            sha256: hex(&state.0.clone().finalize()),
+            size: state.1,
+        };
+    }
+}
+
+pub struct ChecksumReader<R> {
+    inner: R,
+    checksum: Checksum,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.checksum.update(&buf[..n]);
+        return Ok(n);
+    }
+}
+
+pub struct ChecksumWriter<W> {
+    inner: W,
+    checksum: Checksum,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.checksum.update(&buf[..n]);
+        return Ok(n);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        s.push_str(&format!("{:02x}", b));
+    }
+    return s;
+}
+
+/// Reads `path` to the end and returns its digest.
+pub fn file_digest(path: &Path) -> io::Result<FileDigest> {
+    let checksum = Checksum::new();
+    io::copy(&mut checksum.reader(File::open(path)?), &mut io::sink())?;
+    return Ok(checksum.finish());
+}
+
+/// Formats `t` as an RFC 3339 UTC timestamp with millisecond precision.
+pub fn rfc3339(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs();
+    let (hh, mm, ss) = ((secs / 3600) % 24, (secs / 60) % 60, secs % 60);
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm).
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        hh,
+        mm,
+        ss,
+        d.subsec_millis()
+    );
+}
+
+/// A random (version 4) UUID for the run id.
+fn uuid_v4() -> io::Result<String> {
+    let mut b = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut b)?;
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let h = hex(&b);
+    return Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &h[0..8],
+        &h[8..12],
+        &h[12..16],
+        &h[16..20],
+        &h[20..32]
+    ));
+}
+
+/// A file read or written by the run.
+pub struct Dataset<'a> {
+    /// `-` stands for standard output, and `embedded::NAME` for the
+    /// embedded suffix list.
+    pub path: &'a Path,
+    pub digest: &'a FileDigest,
+    /// Number of records, for outputs.
+    pub rows: Option<u64>,
+}
+
+fn dataset_json(dataset: &Dataset) -> serde_json::Value {
+    let name = if dataset.path == Path::new("-") {
+        "stdout".to_string()
+    } else if crate::embedded::is(dataset.path) {
+        crate::embedded::NAME.to_string()
+    } else {
+        match crate::resolve_path(dataset.path) {
+            Ok(p) => p.display().to_string(),
+            Err(_) => dataset.path.display().to_string(),
+        }
+    };
+    let mut value = json!({
+        "namespace": "file",
+        "name": name,
+        "facets": {
+            "checksum": {
+                "_producer": PRODUCER,
+                "_schemaURL": FACET_SCHEMA,
+                "algorithm": "sha256",
+                "value": dataset.digest.sha256,
+                "size": dataset.digest.size,
+            },
+        },
+    });
+    if let Some(rows) = dataset.rows {
+        value["outputFacets"] = json!({
+            "outputStatistics": {
+                "_producer": PRODUCER,
+                "_schemaURL": "https://openlineage.io/spec/facets/1-0-2/OutputStatisticsOutputDatasetFacet.json",
+                "rowCount": rows,
+                "size": dataset.digest.size,
+            },
+        });
+    }
+    return value;
+}
+
+/// Writes an OpenLineage `COMPLETE` run event for a run that started at
+/// `started` to `path`.
+pub fn write_lineage(
+    path: &Path,
+    started: SystemTime,
+    inputs: &[Dataset],
+    outputs: &[Dataset],
+    run_facet: serde_json::Value,
+) -> anyhow::Result<()> {
+    let mut run_facet = run_facet;
+    run_facet["_producer"] = json!(PRODUCER);
+    run_facet["_schemaURL"] = json!(FACET_SCHEMA);
+    run_facet["startTime"] = json!(rfc3339(started));
+    run_facet["commandLine"] = json!(std::env::args().collect::<Vec<String>>());
+
+    let event = json!({
+        "eventType": "COMPLETE",
+        "eventTime": rfc3339(SystemTime::now()),
+        "producer": PRODUCER,
+        "schemaURL": RUN_EVENT_SCHEMA,
+        "run": {
+            "runId": uuid_v4()?,
+            "facets": { "vfbTldextract": run_facet },
+        },
+        "job": {
+            "namespace": crate::PROG,
+            "name": crate::PROG,
+        },
+        "inputs": inputs.iter().map(dataset_json).collect::<Vec<_>>(),
+        "outputs": outputs.iter().map(dataset_json).collect::<Vec<_>>(),
+    });
+    let mut file = File::create(path)?;
+    serde_json::to_writer_pretty(&mut file, &event)?;
+    writeln!(file)?;
+    return Ok(());
+}
@@ -0,0 +1,57 @@
+//! A minimal `log` backend: every message goes to stderr, prefixed with the
+//! program name, in the same form the summary line has always had.
+
+use crate::PROG;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use vfb_tldextract::TRACE_TARGET;
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // --trace-host output was asked for by name, so -q does not hide it.
+        return metadata.level() <= level() || metadata.target() == TRACE_TARGET;
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", PROG, record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// The level chosen by `init`, as a `LevelFilter` discriminant; `log`'s own
+/// maximum is kept at least at info so that traces reach the logger.
+static LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+
+fn level() -> LevelFilter {
+    return match LEVEL.load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+}
+
+/// Installs the logger.  `-q` keeps only warnings and errors; each `-v`
+/// goes one level further than the default summary lines.  Traces asked
+/// for with --trace-host are shown at every level.
+pub fn init(quiet: bool, verbose: u8) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    // Only fails if a logger is already installed.
+    let _ = log::set_logger(&LOGGER);
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(level.max(LevelFilter::Info));
+}
@@ -1,128 +1,2720 @@
-use flate2::read::GzDecoder;
+#![allow(clippy::needless_return)]
+
+use clap::{ArgAction, ArgGroup, CommandFactory, Parser, ValueEnum};
 use serde::Deserialize;
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
-use std::str::FromStr;
-use structopt::StructOpt;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use vfb_tldextract::{extract_traced, ip, ExtractResult, Psl};
+
+mod alias;
+mod annotate;
+mod blocks;
+mod cohost;
+mod columnar;
+mod compare;
+mod compression;
+mod dedup;
+mod diagnostic;
+mod direct_io;
+mod diskspace;
+mod embedded;
+mod fields;
+mod graph;
+mod iana;
+mod idn;
+mod inputs;
+mod lineage;
+mod logging;
+mod manifest;
+mod otlp;
+mod parallel;
+mod prealloc;
+mod progress;
+mod psl_cache;
+mod psl_diff;
+mod psl_merge;
+mod retry;
+mod run_stats;
+mod rusage;
+mod sample;
+mod selftest;
+mod spans;
+mod split;
+mod state;
+mod watch;
 
 const PROG: &str = env!("CARGO_BIN_NAME");
 
-#[derive(StructOpt)]
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Extract registered domains from reverse DNS records",
+    group(ArgGroup::new("mode").args(["watch", "manifest", "compare", "completions", "man", "compile_psl", "psl_diff", "annotate_ips", "annotate_domains", "report_diff", "psl_update", "selftest"]))
+)]
 struct Cli {
-    #[structopt(parse(from_os_str))]
-    tld_data_file: PathBuf,
+    /// Public suffix list, one rule per line [default: the list built in
+    /// with the embedded-psl feature, else the list fetched by --psl-update]
+    #[arg(conflicts_with_all = ["completions", "man", "annotate_ips", "annotate_domains", "report_diff", "psl_update"])]
+    tld_data_file: Option<PathBuf>,
+
+    /// RDNS records, one JSON object per line, gzipped or not, or - for
+    /// stdin
+    #[arg(required_unless_present = "mode", conflicts_with = "mode")]
+    input_file: Option<PathBuf>,
+
+    /// Where records that cannot be handled in this pass are written
+    #[arg(conflicts_with = "mode")]
+    rejected_file: Option<PathBuf>,
+
+    /// Another input to read after INPUT_FILE (repeatable), into the same
+    /// output, rejected file and stats
+    #[arg(
+        long = "input",
+        value_name = "FILE",
+        requires = "input_file",
+        conflicts_with = "lineage"
+    )]
+    more_inputs: Vec<PathBuf>,
+
+    /// Write rows to FILE instead of stdout.  They are written to a
+    /// temporary file next to it, which only replaces FILE once the run has
+    /// succeeded
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        requires = "input_file",
+        conflicts_with = "split_by_type"
+    )]
+    output: Option<PathBuf>,
+
+    /// Fetch the latest suffix list from publicsuffix.org into the cache
+    /// directory, where it is used when no TLD_DATA_FILE is given (needs
+    /// the psl-update feature)
+    #[arg(long)]
+    psl_update: bool,
+
+    /// Check that the suffix list loads and extracts known names
+    /// correctly, and list the features of this build
+    #[arg(long)]
+    selftest: bool,
+
+    /// With --psl-update, only fetch if the cached list is older than
+    /// DURATION (e.g. 1d)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, requires = "psl_update")]
+    max_age: Option<Duration>,
+
+    /// Watch DIR for new dataset files and process each as it lands, instead
+    /// of processing a single input file
+    #[arg(long, value_name = "DIR")]
+    watch: Option<PathBuf>,
+
+    /// In watch mode, only process FILE once FILE<SUFFIX> appears next to it
+    #[arg(long, value_name = "SUFFIX", requires = "watch")]
+    done_suffix: Option<String>,
+
+    /// In watch mode, where outputs and processed inputs are moved
+    /// [default: DIR/processed]
+    #[arg(long, value_name = "DIR", requires = "watch")]
+    processed_dir: Option<PathBuf>,
+
+    /// Run the INPUT OUTPUT REJECTED jobs listed in FILE (text or JSON)
+    /// instead of processing a single input file
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// Run the hostnames in HOSTS through the matcher and report where the
+    /// results disagree with EXPECTED, a `host,domain,suffix` file produced
+    /// by another extractor
+    #[arg(long, num_args = 2, value_names = ["HOSTS", "EXPECTED"])]
+    compare: Vec<PathBuf>,
+
+    /// Write the rules in TLD_DATA_FILE to FILE in the compiled binary
+    /// format, which can then be given as TLD_DATA_FILE itself
+    #[arg(long, value_name = "FILE")]
+    compile_psl: Option<PathBuf>,
+
+    /// Report the rules added, removed and changed between OLD and
+    /// TLD_DATA_FILE
+    #[arg(long, value_name = "OLD")]
+    psl_diff: Option<PathBuf>,
+
+    /// With --psl-diff, count the records of INPUT (plain or compressed,
+    /// as for the input) whose extraction result would change
+    #[arg(long, value_name = "INPUT", requires = "psl_diff")]
+    diff_sample: Option<PathBuf>,
+
+    /// Print a completion script for SHELL to stdout and exit
+    #[arg(long, value_name = "SHELL")]
+    completions: Option<clap_complete::Shell>,
+
+    /// Print a man page (roff) to stdout and exit
+    #[arg(long)]
+    man: bool,
+
+    /// Number of manifest jobs to run at the same time; must be 1 with
+    /// --state-db, as jobs then see the pairs committed by earlier ones
+    #[arg(long, default_value_t = 1)]
+    jobs: usize,
+
+    /// Write an OpenLineage run event (checksums, row counts, options) to
+    /// FILE when the run completes
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["watch", "manifest", "compare"])]
+    lineage: Option<PathBuf>,
+
+    /// Write the counts of the run (lines, rows, rejects by reason, rows by
+    /// suffix) as JSON to FILE when it ends, for --report-diff, with
+    /// "complete": false if it stopped early
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["watch", "manifest"])]
+    stats_json: Option<PathBuf>,
+
+    /// Write the rows by public suffix to FILE as CSV (suffix, rows and
+    /// percent of the rows) when the run ends, followed by a "# incomplete"
+    /// line if it stopped early
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["watch", "manifest"])]
+    stats_by_tld: Option<PathBuf>,
+
+    /// Append the counts so far to FILE every --stats-interval while the
+    /// run goes on, and once more at the end, one JSON object per line with
+    /// the fields of --stats-json and `time` and `final`
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["watch", "manifest"])]
+    stats_stream: Option<PathBuf>,
+
+    /// How often --stats-stream appends the counts (e.g. 10s, 1m)
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        default_value = "10s",
+        requires = "stats_stream"
+    )]
+    stats_interval: Duration,
+
+    /// Send the counts of the run as OTLP metrics and a span to the
+    /// OpenTelemetry collector at URL (http://collector:4318) when it ends
+    #[arg(long, value_name = "URL", conflicts_with_all = ["watch", "manifest"])]
+    otlp_endpoint: Option<String>,
+
+    /// Merge the suffix rules in FILE over TLD_DATA_FILE (repeatable); where
+    /// files disagree about a name, the later file wins and the override is
+    /// reported
+    #[arg(long, value_name = "FILE")]
+    extra_psl: Vec<PathBuf>,
+
+    /// Use only the ICANN section of TLD_DATA_FILE, so that names under
+    /// private suffixes such as blogspot.com extract to the service's own
+    /// domain
+    #[arg(long, conflicts_with = "include_private")]
+    icann_only: bool,
+
+    /// Count the PRIVATE section of TLD_DATA_FILE as suffixes too (the
+    /// default)
+    #[arg(long)]
+    include_private: bool,
+
+    /// Check hosts with non-ASCII characters or xn-- labels against IDNA2008
+    /// and for mixed scripts, and reject those that fail (strict), pass them
+    /// through unchanged (transitional), or convert them to ASCII as far as
+    /// possible (lenient)
+    #[arg(long, value_enum, value_name = "POLICY")]
+    idna_strictness: Option<idn::IdnaStrictness>,
+
+    /// Put hosts with non-ASCII characters in Unicode normalization form
+    /// FORM, and lowercase them, before the IDNA conversion, so that hosts
+    /// kept as they are by --idna-strictness transitional or lenient match
+    /// whichever way they were written.  Hosts converted to ASCII are
+    /// already mapped by UTS #46, which folds case and compatibility forms
+    #[arg(long, value_enum, value_name = "FORM")]
+    unicode_normalization: Option<idn::Normalization>,
+
+    /// Write records with \u escapes to REJECTED_FILE, as earlier versions
+    /// did, instead of extracting their hosts in ASCII (punycode) form
+    #[arg(long)]
+    reject_unicode: bool,
+
+    /// Fail if the suffix list is older than DURATION (e.g. 30d) according
+    /// to its VERSION header, or has none; lists older than 90 days are
+    /// warned about regardless
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    require_fresh_psl: Option<Duration>,
+
+    /// Count the records whose last label is not a TLD delegated in the
+    /// root zone, per IANA's tlds-alpha-by-domain.txt at FILE
+    #[arg(long, value_name = "FILE")]
+    iana_tlds: Option<PathBuf>,
+
+    /// --iana-tlds, once loaded.
+    #[arg(skip)]
+    iana: Option<iana::TldList>,
+
+    /// Compare the --stats-json files of two runs and print a report of what
+    /// changed, instead of processing records
+    #[arg(long, num_args = 2, value_names = ["RUN_A_STATS", "RUN_B_STATS"])]
+    report_diff: Vec<PathBuf>,
+
+    /// Print each step of the suffix matching for HOST to stderr when it is
+    /// encountered, even with -q (may be repeated).  HOST is compared in
+    /// normalized form, so `Example.COM.` and a Unicode name match their
+    /// lowercase and Punycode forms
+    #[arg(long, value_name = "HOST", value_parser = parse_trace_host)]
+    trace_host: Vec<String>,
+
+    /// What to do with records whose host is itself a public suffix: drop
+    /// them, write them to the rejected file, or emit them with an empty
+    /// domain or with the suffix as the domain
+    #[arg(long, value_enum, default_value_t = SuffixOnlyHosts::Drop)]
+    suffix_only_hosts: SuffixOnlyHosts,
+
+    /// What to do with hosts that have empty labels (a leading dot or two
+    /// dots in a row): drop the empty labels, or reject the record
+    #[arg(long, value_enum, default_value_t = EmptyLabels::Normalize)]
+    empty_labels: EmptyLabels,
+
+    /// Reject hosts with whitespace inside them (surrounding whitespace is
+    /// always trimmed)
+    #[arg(long)]
+    reject_inner_whitespace: bool,
+
+    /// Follow hosts through the CNAME records of the same input (read in a
+    /// first pass) and emit the domain of the final target
+    #[arg(long)]
+    follow_aliases: bool,
+
+    /// Longest alias chain to follow before rejecting the record
+    #[arg(long, default_value_t = 8, requires = "follow_aliases")]
+    max_alias_depth: usize,
+
+    /// Reject hosts longer than 253 bytes or with a label longer than 63
+    /// bytes, as allowed by RFC 1035
+    #[arg(long)]
+    strict_lengths: bool,
+
+    /// How the IP is written: as a decimal integer (32 bits for IPv4, 128
+    /// for IPv6), as its bits in zero-padded hexadecimal, or dotted
+    /// (1.2.3.4)
+    #[arg(long, value_enum, default_value_t = ip::IpFormat::Decimal)]
+    ip_format: ip::IpFormat,
+
+    /// Byte order of the integer written for the IP: big (network order,
+    /// 1.2.3.4 is 0x01020304) or little (0x04030201)
+    #[arg(long, value_enum, default_value_t = ip::Endianness::Big)]
+    ip_endianness: ip::Endianness,
+
+    /// Write the IP as a signed integer instead of an unsigned one
+    #[arg(long)]
+    ip_signed: bool,
+
+    /// How to write the output: csv, ndjson, parquet, graph, cohosting or
+    /// blocks
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv, conflicts_with_all = ["cdc", "split_by_type"])]
+    format: OutputFormat,
+
+    /// The columns of the rows, from ip, host (or hostname), subdomain,
+    /// domain, suffix, registrable (the domain with its suffix),
+    /// idn-roundtrip, raw-value, timestamp, rule and rule-section
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [fields::Field::Ip, fields::Field::Domain], conflicts_with = "cdc")]
+    fields: Vec<fields::Field>,
+
+    /// The separator of the columns of csv rows: a single character, or
+    /// tab or comma
+    #[arg(long, value_name = "CHAR", default_value = "comma", value_parser = parse_delimiter, conflicts_with = "cdc")]
+    delimiter: u8,
+
+    /// Add the record's value as given, before any normalization, as a last
+    /// column (the raw-value field), for audits of what the source held
+    #[arg(long, conflicts_with = "cdc")]
+    emit_raw_value: bool,
+
+    /// Add the suffix rule that matched (with its * or ! marker) and the
+    /// section of the list it is from, icann or private, as last columns
+    /// (the rule and rule-section fields)
+    #[arg(long, conflicts_with = "cdc")]
+    emit_rule: bool,
+
+    /// With --format cohosting, only report addresses with at least N
+    /// distinct registrable domains
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    cohosting_min: usize,
+
+    /// With --format cohosting, write ip,count rows without the domains
+    #[arg(long)]
+    cohosting_counts: bool,
+
+    /// With --format blocks, the fewest consecutive addresses that make a
+    /// range
+    #[arg(long, value_name = "N", default_value_t = 16)]
+    min_block: u64,
+
+    /// With --format parquet, the rows per row group, which are held in
+    /// memory until the group is written
+    #[arg(long, value_name = "N", default_value_t = 1 << 20, value_parser = clap::value_parser!(u64).range(1..))]
+    row_group_size: u64,
+
+    /// Accept in-addr.arpa PTR owner names (4.3.2.1.in-addr.arpa) in the
+    /// name field and write the address they stand for (1.2.3.4)
+    #[arg(long)]
+    in_addr_arpa: bool,
+
+    /// Accept IPs in hex (0x0A000001), 32-bit decimal and the other
+    /// inet_aton forms; names whose octal and decimal readings differ are
+    /// rejected, as are names that fail to parse
+    #[arg(long)]
+    lenient_ips: bool,
+
+    /// Times to retry a read or write that fails with a transient error
+    /// (EIO, ETIMEDOUT, EAGAIN), as seen on network filesystems
+    #[arg(long, default_value_t = 0)]
+    io_retries: u32,
+
+    /// Delay before the first I/O retry, in milliseconds; doubles on each
+    /// further retry of the same call, up to 30 seconds
+    #[arg(long, default_value_t = 100)]
+    io_retry_delay_ms: u64,
+
+    /// Print only warnings and errors, not even the final summary line
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print progress while processing as well (repeat for more detail)
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
 
-    #[structopt(parse(from_os_str))]
-    input_file: PathBuf,
+    /// Log every 10 seconds the lines read, rows written, rejects, the
+    /// rate, the share of the input files read and the time left
+    #[arg(long)]
+    progress: bool,
 
-    #[structopt(parse(from_os_str))]
-    rejected_file: PathBuf,
+    /// Stop reading once the run has taken DURATION (e.g. 90s, 15m, 2h,
+    /// 1h30m), still writing out everything processed so far, then exit
+    /// with an error since the output is incomplete (an --output FILE is
+    /// left as it was)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, conflicts_with = "watch")]
+    max_runtime: Option<Duration>,
+
+    /// Refuse to start when a volume written to has less than SIZE free
+    /// (e.g. 10G), and stop reading as at --max-runtime when one drops
+    /// below it during the run
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_free: Option<u64>,
+
+    /// Write PTR rows, CNAME rows and the records of other types to
+    /// separate files in DIR (ptr.csv, cname.csv and other.json) instead of
+    /// writing rows to stdout
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["watch", "manifest", "lineage"])]
+    split_by_type: Option<PathBuf>,
+
+    /// Only emit the rows whose (ip, registrable domain) pair was not seen
+    /// by an earlier run that used the database at PATH, recording every
+    /// pair seen once the output is complete (needs the state-db feature)
+    #[arg(long, value_name = "PATH")]
+    state_db: Option<PathBuf>,
+
+    /// With --state-db, emit every pair as an op,ip,registrable row: add
+    /// for new pairs, refresh for pairs seen before, and, once the whole
+    /// input has been read, remove for the pairs this run did not see
+    #[arg(long, requires = "state_db", conflicts_with_all = ["watch", "manifest"])]
+    cdc: bool,
+
+    /// With --state-db, drop the pairs not seen for DURATION (e.g. 90d)
+    /// from the database; with --cdc, pairs are only removed once they
+    /// have expired, rather than as soon as a run does not see them
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, requires = "state_db", conflicts_with_all = ["watch", "manifest"])]
+    expire: Option<Duration>,
+
+    /// Leave out the rows this run already wrote, with the same value in
+    /// every field, from any of its input files, so that overlapping shards
+    /// given with --input merge into one output without repeats
+    #[arg(long)]
+    dedup: bool,
+
+    /// The memory --dedup may use to remember rows (e.g. 512M); beyond
+    /// what fits, they are spilled to files in --temp-dir
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "1G", requires = "dedup")]
+    dedup_memory: u64,
+
+    /// Where --dedup spills the rows beyond --dedup-memory, e.g. a scratch
+    /// volume apart from the output [default: TMPDIR, else /tmp]
+    #[arg(long, value_name = "DIR", requires = "dedup")]
+    temp_dir: Option<PathBuf>,
+
+    /// List each IPv4 address in IPS_FILE (one per line) with the domains
+    /// recorded for it in --state-db, instead of processing records
+    #[arg(long, value_name = "IPS_FILE", requires = "state_db")]
+    annotate_ips: Option<PathBuf>,
+
+    /// List each registrable domain in DOMAINS_FILE (one per line, e.g.
+    /// example.co.uk) with the IPv4 addresses recorded for it in
+    /// --state-db, instead of processing records
+    #[arg(long, value_name = "DOMAINS_FILE", requires = "state_db")]
+    annotate_domains: Option<PathBuf>,
+
+    /// With --annotate-domains, write a row per host under each domain,
+    /// with the addresses it was seen with
+    #[arg(long, requires = "annotate_domains")]
+    subdomains: bool,
+
+    /// --state-db, once opened.
+    #[arg(skip)]
+    state: Option<state::StateDb>,
+
+    /// Keep only the records of a deterministic FRACTION (e.g. 0.05) of the
+    /// values of KEY: domain (the registrable domain), host or ip
+    #[arg(long, num_args = 2, value_names = ["KEY", "FRACTION"])]
+    sample_by: Vec<String>,
+
+    /// Keep at most N records per public suffix, the first N found in each
+    /// input file
+    #[arg(long, value_name = "N")]
+    sample_per_suffix: Option<u64>,
+
+    /// Keep only the records whose public suffix is or ends in one of TLDS
+    /// (e.g. com,net,org; co.uk matches uk)
+    #[arg(long, value_name = "TLDS", value_delimiter = ',', value_parser = parse_tld)]
+    only_tld: Vec<String>,
+
+    /// Leave out the records whose public suffix is or ends in one of TLDS
+    /// (e.g. arpa)
+    #[arg(long, value_name = "TLDS", value_delimiter = ',', value_parser = parse_tld)]
+    exclude_tld: Vec<String>,
+
+    /// --sample-by, once parsed.
+    #[arg(skip)]
+    sample: Option<sample::SampleBy>,
+
+    /// Time the read, decompress, parse, match, enrich and write phases and
+    /// write them to FILE as a Chrome trace (for chrome://tracing, Perfetto
+    /// or flamegraph tools)
+    #[arg(long, value_name = "FILE")]
+    trace_spans: Option<PathBuf>,
+
+    /// Size of the read and write buffers, in bytes or with a K, M, G or T
+    /// suffix (e.g. 512K, 1.5M); at least 4K
+    #[arg(long, value_name = "SIZE", default_value = "64K", value_parser = parse_buffer_size)]
+    buffer_size: u64,
+
+    /// Compression of INPUT_FILE; by default told from its first bytes
+    #[arg(long, value_enum, default_value_t = compression::Compression::Auto)]
+    compression: compression::Compression,
+
+    /// Parse and match records on N worker threads (`auto` or 0 for one
+    /// per CPU the process may run on), while the input is read and the
+    /// output written in order on the main thread; only for --format csv or
+    /// ndjson without per-run state, which is why it is not the default
+    #[arg(long, value_name = "N", default_value = "1", value_parser = parallel::parse_threads, conflicts_with_all = ["state_db", "sample_per_suffix", "split_by_type", "dedup"])]
+    threads: usize,
+
+    /// Run only on the CPUs in CPUS (e.g. 0-3,8), the reader, writer and
+    /// worker threads alike, so that a run shares a batch node without
+    /// oversubscribing it; --threads auto then starts one worker per CPU
+    /// in CPUS
+    #[arg(long, value_name = "CPUS", value_parser = parallel::parse_cpus)]
+    cpu_affinity: Option<parallel::Cpus>,
+
+    /// Permission bits, in octal, for files created by this run (e.g. 0640)
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    output_mode: Option<u32>,
+
+    /// Group, by name or numeric id, that should own files created by this run
+    #[arg(long, value_parser = parse_group)]
+    group: Option<u32>,
+
+    /// Write the output and rejected files with O_DIRECT, bypassing the page
+    /// cache, on volumes that support it; rows written to stdout are not
+    /// affected
+    #[arg(long)]
+    direct_io: bool,
+
+    /// Reserve RATIO times the size of the input files for the output file
+    /// before writing it, to keep it in few extents; the ratio of each run
+    /// is logged, and the space it did not use released at the end
+    #[arg(long, value_name = "RATIO", value_parser = parse_ratio)]
+    preallocate: Option<f64>,
+
+    /// Write the SHA-256 of each file written, computed as it is written,
+    /// to FILE.sha256 next to it, in the format checked by sha256sum -c
+    #[arg(long)]
+    checksum_files: bool,
+}
+
+/// Parses a byte count such as `4096`, `512K` or `1.5G`; the suffixes are
+/// powers of 1024 and may be followed by `B` or `iB`.
+fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit = &s[digits.len()..];
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => anyhow::bail!("unknown size suffix {:?}", unit),
+    };
+    if let Ok(n) = digits.parse::<u64>() {
+        return match n.checked_mul(multiplier) {
+            Some(size) => Ok(size),
+            None => anyhow::bail!("size {:?} is too large", s),
+        };
+    }
+    // Fractions are only meaningful with a suffix, and are rounded down to
+    // a whole byte.
+    if multiplier == 1 || !digits.bytes().all(|b| b.is_ascii_digit() || b == b'.') {
+        anyhow::bail!("invalid size {:?}", s);
+    }
+    let n: f64 = match digits.parse() {
+        Ok(n) => n,
+        Err(_) => anyhow::bail!("invalid size {:?}", s),
+    };
+    let size = n * multiplier as f64;
+    if size >= u64::MAX as f64 {
+        anyhow::bail!("size {:?} is too large", s);
+    }
+    return Ok(size as u64);
+}
+
+/// The directory of --temp-dir, else the system's temporary directory.
+fn scratch_dir(args: &Cli) -> PathBuf {
+    return args.temp_dir.clone().unwrap_or_else(std::env::temp_dir);
+}
+
+/// The smallest --buffer-size: a read buffer of no bytes reads nothing,
+/// and --direct-io writes whole pages.
+const MIN_BUFFER_SIZE: u64 = 4096;
+
+/// Parses --buffer-size, a size of at least [`MIN_BUFFER_SIZE`].
+fn parse_buffer_size(s: &str) -> anyhow::Result<u64> {
+    let size = parse_size(s)?;
+    if size < MIN_BUFFER_SIZE {
+        anyhow::bail!("buffer size {:?} is below the minimum of 4K", s);
+    }
+    return Ok(size);
+}
+
+/// Parses a host for --trace-host into the form hosts are matched in:
+/// trimmed, lowercase, without empty labels or the trailing dot, and in
+/// Punycode if it is not ASCII.
+fn parse_trace_host(s: &str) -> anyhow::Result<String> {
+    let host = match normalize_host(s, EmptyLabels::Normalize) {
+        Ok(host) => host,
+        Err(_) => anyhow::bail!("invalid host {:?}", s),
+    };
+    if host.is_ascii() {
+        return Ok(host.into_owned());
+    }
+    match idn::to_ascii(&host) {
+        Some(ascii) => return Ok(ascii),
+        None => anyhow::bail!("invalid host {:?}", s),
+    }
+}
+
+/// Parses a TLD or suffix for --only-tld and --exclude-tld (`uk`, `.co.uk`)
+/// into its lowercase form without the leading dot.
+fn parse_tld(s: &str) -> anyhow::Result<String> {
+    let tld = s.trim().trim_start_matches('.').to_ascii_lowercase();
+    if tld.is_empty() || tld.ends_with('.') || tld.contains("..") {
+        anyhow::bail!("invalid TLD {:?}", s);
+    }
+    return Ok(tld);
+}
+
+/// Whether `suffix` is `tld` or a suffix under it.
+fn under_tld(suffix: &str, tld: &str) -> bool {
+    return match suffix.len().checked_sub(tld.len()) {
+        Some(0) => suffix.eq_ignore_ascii_case(tld),
+        Some(n) => suffix.as_bytes()[n - 1] == b'.' && suffix[n..].eq_ignore_ascii_case(tld),
+        None => false,
+    };
+}
+
+/// Parses a duration such as `90`, `90s`, `15m`, `2h` or `1h30m`; a bare
+/// number is in seconds.
+fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let mut total = Duration::from_secs(0);
+    let mut rest = s;
+    if let Ok(secs) = s.parse::<u64>() {
+        total = Duration::from_secs(secs);
+        rest = "";
+    }
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let units = rest[digits..]
+            .bytes()
+            .take_while(u8::is_ascii_alphabetic)
+            .count();
+        let (n, unit) = (&rest[..digits], &rest[digits..digits + units]);
+        let n: u64 = match n.parse() {
+            Ok(n) => n,
+            Err(_) => anyhow::bail!("invalid duration {:?}", s),
+        };
+        let part = match unit {
+            "ms" => Duration::from_millis(n),
+            "s" => Duration::from_secs(n),
+            "m" => Duration::from_secs(n.saturating_mul(60)),
+            "h" => Duration::from_secs(n.saturating_mul(3600)),
+            "d" => Duration::from_secs(n.saturating_mul(86400)),
+            _ => anyhow::bail!("unknown duration unit {:?} in {:?}", unit, s),
+        };
+        total = match total.checked_add(part) {
+            Some(total) => total,
+            None => anyhow::bail!("duration {:?} is too long", s),
+        };
+        rest = &rest[digits + units..];
+    }
+    if total == Duration::from_secs(0) {
+        anyhow::bail!("invalid duration {:?}", s);
+    }
+    return Ok(total);
+}
+
+fn parse_delimiter(s: &str) -> anyhow::Result<u8> {
+    match s {
+        "tab" | "\\t" => return Ok(b'\t'),
+        "comma" => return Ok(b','),
+        _ => {}
+    }
+    match s.as_bytes() {
+        [b'"' | b'\n' | b'\r'] => anyhow::bail!("{:?} cannot separate columns", s),
+        &[b] if b.is_ascii() => return Ok(b),
+        _ => anyhow::bail!("{:?} is not a single ASCII character", s),
+    }
+}
+
+fn parse_ratio(s: &str) -> anyhow::Result<f64> {
+    let ratio: f64 = s.parse()?;
+    if !(ratio.is_finite() && ratio > 0.0) {
+        anyhow::bail!("ratio {:?} is not a positive number", s);
+    }
+    return Ok(ratio);
+}
+
+fn parse_mode(s: &str) -> anyhow::Result<u32> {
+    let mode = u32::from_str_radix(s, 8)?;
+    if mode > 0o7777 {
+        anyhow::bail!("mode {:?} is out of range", s);
+    }
+    return Ok(mode);
+}
+
+fn parse_group(s: &str) -> anyhow::Result<u32> {
+    if let Ok(gid) = s.parse::<u32>() {
+        return Ok(gid);
+    }
+    let name = CString::new(s)?;
+    // getgrnam() returns a pointer into static storage; only the gid is
+    // copied out, before anything else can call into the group database.
+    let grp = unsafe { libc::getgrnam(name.as_ptr()) };
+    if grp.is_null() {
+        anyhow::bail!("unknown group {:?}", s);
+    }
+    return Ok(unsafe { (*grp).gr_gid });
+}
+
+/// The INPUT_FILE that stands for stdin.
+const STDIN: &str = "-";
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OutputFormat {
+    /// ip,domain rows, or the --fields columns
+    Csv,
+    /// The same fields as one JSON object per line
+    Ndjson,
+    /// The same fields as the columns of a Parquet file (needs the parquet
+    /// feature)
+    Parquet,
+    /// A GraphML graph with a node per address and per domain and an edge
+    /// per pair
+    Graph,
+    /// The addresses shared by many registrable domains, as
+    /// ip,count,domains rows, busiest first
+    Cohosting,
+    /// start,end,domain,pattern rows collapsing ranges of templated hosts
+    Blocks,
+}
+
+/// What to do with records whose host is itself a public suffix (`co.uk`),
+/// and so has no registrable domain.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum SuffixOnlyHosts {
+    Drop,
+    Reject,
+    EmitEmpty,
+    EmitAsSuffix,
+}
+
+/// What to do with hosts that have empty labels (`.example.com`,
+/// `foo..example.com`).
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum EmptyLabels {
+    Normalize,
+    Reject,
 }
 
 #[derive(Deserialize)]
 struct RdnsRecord {
+    #[serde(default)]
+    timestamp: String,
     name: String,
     value: String,
+    #[serde(rename = "type", default)]
+    kind: String,
 }
 
-fn parse_tld_file(filename: &PathBuf) -> anyhow::Result<HashSet<String>> {
-    let rdr = BufReader::new(File::open(filename)?);
-    let mut set: HashSet<String> = HashSet::with_capacity(4096);
-    for line in rdr.lines() {
-        let line = line?;
-        if line.trim().is_empty() || line.starts_with("//") {
-            continue;
+impl RdnsRecord {
+    /// Reads a record that `Deserialize` rejects, from any JSON object with
+    /// a name and a value: numbers and booleans stand for their text, and
+    /// fields of other types are taken as missing.
+    fn parse_lenient(line: &str) -> Option<RdnsRecord> {
+        let record: serde_json::Value = serde_json::from_str(line).ok()?;
+        let record = record.as_object()?;
+        let text = |key: &str| -> Option<String> {
+            match record.get(key)? {
+                serde_json::Value::String(s) => return Some(s.clone()),
+                serde_json::Value::Number(n) => return Some(n.to_string()),
+                serde_json::Value::Bool(b) => return Some(b.to_string()),
+                _ => return None,
+            }
+        };
+        return Some(RdnsRecord {
+            timestamp: text("timestamp").unwrap_or_default(),
+            name: text("name")?,
+            value: text("value")?,
+            kind: text("type").unwrap_or_default(),
+        });
+    }
+}
+
+fn parse_tld_file(filename: &Path) -> anyhow::Result<Psl> {
+    if embedded::is(filename) {
+        return embedded::load();
+    }
+    match Psl::from_file(filename) {
+        Ok(psl) => return Ok(psl),
+        Err(e) => anyhow::bail!("{}: {}", filename.display(), e),
+    }
+}
+
+/// Whether `path` looks like an input rather than a suffix list; unreadable
+/// files are left for the run itself to report.
+fn is_input_file(path: &Path) -> bool {
+    let mut head = Vec::new();
+    return match File::open(path) {
+        Ok(file) => {
+            file.take(64).read_to_end(&mut head).is_ok() && compression::looks_like_input(&head)
         }
-        set.insert(line);
+        Err(_) => false,
+    };
+}
+
+/// Reads TLD_DATA_FILE, without its PRIVATE section under --icann-only,
+/// and merges the --extra-psl files over it.
+fn load_psl(tld_data_file: &Path, args: &Cli) -> anyhow::Result<Psl> {
+    let mut base = parse_tld_file(tld_data_file)?;
+    if args.icann_only {
+        let total = base.len();
+        base = base.icann_only();
+        log::info!(
+            "{}: dropped {} PRIVATE rules for --icann-only",
+            tld_data_file.display(),
+            total - base.len()
+        );
+    }
+    if args.extra_psl.is_empty() {
+        return Ok(base);
+    }
+    let mut extras = Vec::new();
+    for path in &args.extra_psl {
+        extras.push((path.as_path(), parse_tld_file(path)?));
     }
-    return Ok(set);
+    return Ok(psl_merge::merge(&base, tld_data_file, &extras));
 }
 
-fn rfind_from(s: &str, c: char, offset: usize) -> Option<usize> {
-    (&s[..offset]).rfind(c)
+/// Loads the list for matching hosts: checks its age, and adds the ASCII
+/// form of its internationalized rules, since hosts are matched in ASCII
+/// form.
+fn load_matching_psl(tld_data_file: &Path, args: &Cli) -> anyhow::Result<Psl> {
+    let psl = load_psl(tld_data_file, args)?;
+    check_psl_age(&psl, tld_data_file, args)?;
+    return Ok(psl.with_ascii_rules());
 }
 
-fn domain_for<'a, 'b>(host: &'a str, tld_set: &'b HashSet<String>) -> Option<&'a str> {
-    // The current longest TLD suffix extends from frontier to the end of `host`.
-    let mut frontier: usize = host.len();
+/// Lists older than this are warned about even without --require-fresh-psl.
+const STALE_PSL_AGE: Duration = Duration::from_secs(90 * 86400);
 
-    while let Some(idx) = rfind_from(host, '.', frontier) {
-        let s = &host[idx + 1..];
-        if !tld_set.contains(s) {
-            break;
+/// Warns if the suffix list at `filename` is stale, and fails if it is
+/// older than --require-fresh-psl or its age cannot be told.
+fn check_psl_age(psl: &Psl, filename: &Path, args: &Cli) -> anyhow::Result<()> {
+    let age = match psl.published() {
+        Some(published) => SystemTime::now()
+            .duration_since(published)
+            .unwrap_or_default(),
+        None => {
+            if args.require_fresh_psl.is_some() {
+                anyhow::bail!(
+                    "{}: no VERSION date in the list, so --require-fresh-psl cannot tell its age",
+                    filename.display()
+                );
+            }
+            return Ok(());
+        }
+    };
+    let days = age.as_secs() / 86400;
+    let version = psl.version().unwrap_or("");
+    if let Some(max_age) = args.require_fresh_psl {
+        if age > max_age {
+            anyhow::bail!(
+                "{}: the list ({}) is {} days old, older than --require-fresh-psl",
+                filename.display(),
+                version,
+                days
+            );
         }
-        frontier = idx;
     }
+    if age > STALE_PSL_AGE {
+        log::warn!(
+            "{}: the list ({}) is {} days old; newer suffixes will not be recognized",
+            filename.display(),
+            version,
+            days
+        );
+    }
+    return Ok(());
+}
 
-    if frontier == host.len() {
-        return None;
+/// Resolves `path` to an absolute path with symlinks followed.  Files that
+/// do not exist yet (e.g. the rejected file on a first run) are resolved
+/// through their parent directory.
+fn resolve_path(path: &Path) -> io::Result<PathBuf> {
+    match path.canonicalize() {
+        Ok(p) => Ok(p),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let name = match path.file_name() {
+                Some(name) => name,
+                None => return Err(e),
+            };
+            let parent = match path.parent() {
+                Some(p) if !p.as_os_str().is_empty() => p,
+                _ => Path::new("."),
+            };
+            Ok(parent.canonicalize()?.join(name))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Fails if any two of the named paths refer to the same file, either
+/// directly, through a symlink, or through a hard link.  This must run
+/// before any file is created, since creating the rejected file truncates it.
+fn check_distinct_paths(paths: &[(&str, &Path)]) -> anyhow::Result<()> {
+    let mut ids: Vec<(PathBuf, Option<(u64, u64)>)> = Vec::with_capacity(paths.len());
+    for &(role, path) in paths {
+        let resolved = resolve_path(path)?;
+        let inode = std::fs::metadata(path).ok().map(|m| (m.dev(), m.ino()));
+        for (j, (other_resolved, other_inode)) in ids.iter().enumerate() {
+            if resolved == *other_resolved || (inode.is_some() && inode == *other_inode) {
+                let (other_role, other_path) = paths[j];
+                anyhow::bail!(
+                    "{} file {:?} and {} file {:?} refer to the same file",
+                    other_role,
+                    other_path,
+                    role,
+                    path
+                );
+            }
+        }
+        ids.push((resolved, inode));
     }
+    return Ok(());
+}
+
+/// Creates (or truncates) a file that this run writes, applying the
+/// requested permission bits and group ownership.  The mode is set
+/// explicitly after creation so that the process umask does not mask it.
+fn create_output_file(path: &Path, args: &Cli) -> io::Result<File> {
+    let mut opts = OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    return open_output_file(path, opts, args);
+}
 
-    // host[frontier..] is the tld, now let's find the domain.
-    let start = match rfind_from(host, '.', frontier) {
-        Some(idx) => idx + 1,
-        None => 0,
+/// Opens a file that this run appends to, creating it as
+/// [`create_output_file`] does if need be.
+fn append_output_file(path: &Path, args: &Cli) -> io::Result<File> {
+    let mut opts = OpenOptions::new();
+    opts.create(true).append(true);
+    return open_output_file(path, opts, args);
+}
+
+fn open_output_file(path: &Path, mut opts: OpenOptions, args: &Cli) -> io::Result<File> {
+    if let Some(mode) = args.output_mode {
+        opts.mode(mode);
+    }
+    let file = opts.open(path)?;
+    if let Some(mode) = args.output_mode {
+        file.set_permissions(Permissions::from_mode(mode))?;
+    }
+    if let Some(gid) = args.group {
+        std::os::unix::fs::fchown(&file, None, Some(gid))?;
+    }
+    return Ok(file);
+}
+
+/// The temporary file that `--output` is written to before being renamed
+/// to `path`: hidden, next to it so that the rename cannot cross
+/// filesystems, and named after the process so that concurrent runs writing
+/// the same output do not share it.
+fn temp_output_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => anyhow::bail!("{}: not a file name", path.display()),
     };
-    return Some(&host[start..frontier]);
+    return Ok(path.with_file_name(format!(".{}.{}.tmp", name, std::process::id())));
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Cli::from_args();
-    let file = File::open(&args.input_file)?;
-    let mut rdr = BufReader::new(GzDecoder::new(file));
-    let mut rejected = BufWriter::new(File::create(&args.rejected_file)?);
-    let tld_set = parse_tld_file(&args.tld_data_file)?;
+/// Where --checksum-files writes the digest of `path`.
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".sha256");
+    return PathBuf::from(sidecar);
+}
+
+/// Writes the digest of the file at `path` next to it, in the format of
+/// `sha256sum`, naming it as the file `name` in the same directory.
+fn write_sidecar(
+    path: &Path,
+    name: &Path,
+    digest: &lineage::FileDigest,
+    args: &Cli,
+) -> anyhow::Result<()> {
+    let name = name.file_name().unwrap_or(name.as_os_str());
+    let mut file = create_output_file(&sidecar_path(path), args)?;
+    writeln!(file, "{}  {}", digest.sha256, name.to_string_lossy())?;
+    return Ok(());
+}
+
+/// Prepares `host` for suffix matching.  Surrounding whitespace, which some
+/// feeds leave inside the quoted value, and a single trailing dot (the DNS
+/// root, as in `example.com.`) are always removed.  Empty labels elsewhere are
+/// either dropped or cause the host to be rejected, according to `policy`;
+/// the error is the reject reason.  A host left empty (`""`, `.`) is
+/// rejected as `empty-host`.  ASCII letters are lowercased, as names are
+/// matched case-insensitively (`Example.COM` as `example.com`); other
+/// characters are left to the IDNA conversion.
+fn normalize_host(host: &str, policy: EmptyLabels) -> Result<Cow<'_, str>, &'static str> {
+    let host = host.trim();
+    let host = host.strip_suffix('.').unwrap_or(host);
+    if host.is_empty() {
+        return Err("empty-host");
+    }
+    let host = if !(host.starts_with('.') || host.ends_with('.') || host.contains("..")) {
+        Cow::Borrowed(host)
+    } else {
+        match policy {
+            EmptyLabels::Normalize => {
+                let labels: Vec<&str> = host.split('.').filter(|l| !l.is_empty()).collect();
+                if labels.is_empty() {
+                    return Err("empty-host");
+                }
+                Cow::Owned(labels.join("."))
+            }
+            EmptyLabels::Reject => return Err("empty-label"),
+        }
+    };
+    if host.bytes().any(|b| b.is_ascii_uppercase()) {
+        return Ok(Cow::Owned(host.to_ascii_lowercase()));
+    }
+    return Ok(host);
+}
+
+/// Longest hostname, in its dotted text form without the trailing dot, and
+/// longest label allowed by RFC 1035.
+const MAX_HOSTNAME_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+/// Checks `host` against the RFC 1035 length limits; the error is the reject
+/// reason.
+fn check_lengths(host: &str) -> Result<(), &'static str> {
+    if host.len() > MAX_HOSTNAME_LEN {
+        return Err("hostname-too-long");
+    }
+    if host.split('.').any(|label| label.len() > MAX_LABEL_LEN) {
+        return Err("label-too-long");
+    }
+    return Ok(());
+}
+
+#[derive(Default)]
+struct Stats {
+    num_lines: u64,
+    num_rejected: u64,
+    /// Rejected records, by reason.
+    rejects_by_reason: BTreeMap<&'static str, u64>,
+    num_retries: u64,
+    num_rows: u64,
+    num_suffix_only: u64,
+    /// Records only read by the lenient parser.
+    num_lenient: u64,
+    /// Matched records by the section of the list their rule is from, and
+    /// the records no rule matched, which the list's implicit `*` rule
+    /// would make suffixes of their own.
+    num_icann: u64,
+    num_private: u64,
+    num_no_match: u64,
+    /// Internationalized hosts by idn::Outcome, with --idna-strictness.
+    idna_outcomes: [u64; 4],
+    /// Alias records read with --follow-aliases, which produce no PTR row.
+    num_alias_records: u64,
+    /// Rows written to cname.csv and records to other.json with
+    /// --split-by-type.
+    num_cname_rows: u64,
+    num_other_records: u64,
+    /// Hosts resolved through an alias chain, by chain length.
+    alias_depths: BTreeMap<usize, u64>,
+    /// Only computed when a lineage record was requested.
+    digests: Option<Digests>,
+    /// Pairs left out because --state-db had seen them before.
+    num_seen_before: u64,
+    /// Rows left out by --dedup.
+    num_duplicates: u64,
+    /// Rows --dedup spilled to disk for want of --dedup-memory.
+    num_dedup_spilled: u64,
+    /// Rows by op, with --cdc.
+    num_added: u64,
+    num_refreshed: u64,
+    num_removed: u64,
+    /// Pairs dropped by --expire without --cdc.
+    num_expired: u64,
+    /// Records left out by --sample-by or --sample-per-suffix.
+    num_sampled_out: u64,
+    /// Records left out by --only-tld or --exclude-tld.
+    num_tld_filtered: u64,
+    /// Only counted for --stats-json and --stats-by-tld.
+    rows_by_suffix: BTreeMap<String, u64>,
+    /// Records by last label, for those not delegated per --iana-tlds.
+    undelegated_tlds: BTreeMap<String, u64>,
+    /// Whether reading stopped at the --max-runtime deadline.
+    stopped_at_deadline: bool,
+    /// Whether reading stopped because a volume fell below --min-free.
+    stopped_low_space: bool,
+}
+
+impl Stats {
+    fn count_reject(&mut self, reason: &'static str) {
+        self.num_rejected += 1;
+        *self.rejects_by_reason.entry(reason).or_insert(0) += 1;
+    }
+
+    /// Adds the counts of `other`, from another part of the same input.
+    fn merge(&mut self, other: Stats) {
+        self.num_lines += other.num_lines;
+        self.num_rejected += other.num_rejected;
+        for (reason, n) in other.rejects_by_reason {
+            *self.rejects_by_reason.entry(reason).or_insert(0) += n;
+        }
+        self.num_retries += other.num_retries;
+        self.num_rows += other.num_rows;
+        self.num_suffix_only += other.num_suffix_only;
+        self.num_lenient += other.num_lenient;
+        self.num_icann += other.num_icann;
+        self.num_private += other.num_private;
+        self.num_no_match += other.num_no_match;
+        for (total, n) in self.idna_outcomes.iter_mut().zip(other.idna_outcomes) {
+            *total += n;
+        }
+        self.num_alias_records += other.num_alias_records;
+        self.num_cname_rows += other.num_cname_rows;
+        self.num_other_records += other.num_other_records;
+        for (depth, n) in other.alias_depths {
+            *self.alias_depths.entry(depth).or_insert(0) += n;
+        }
+        self.num_seen_before += other.num_seen_before;
+        self.num_duplicates += other.num_duplicates;
+        self.num_dedup_spilled += other.num_dedup_spilled;
+        self.num_added += other.num_added;
+        self.num_refreshed += other.num_refreshed;
+        self.num_removed += other.num_removed;
+        self.num_expired += other.num_expired;
+        self.num_sampled_out += other.num_sampled_out;
+        self.num_tld_filtered += other.num_tld_filtered;
+        for (suffix, n) in other.rows_by_suffix {
+            *self.rows_by_suffix.entry(suffix).or_insert(0) += n;
+        }
+        for (tld, n) in other.undelegated_tlds {
+            *self.undelegated_tlds.entry(tld).or_insert(0) += n;
+        }
+        self.stopped_at_deadline |= other.stopped_at_deadline;
+        self.stopped_low_space |= other.stopped_low_space;
+    }
+
+    /// The counts as written by --stats-json and --stats-stream.
+    fn run_stats(&self, input: String, duration: Duration) -> run_stats::RunStats {
+        return run_stats::RunStats {
+            input,
+            lines: self.num_lines,
+            rows: self.num_rows,
+            rejected: self.num_rejected,
+            rejects_by_reason: self
+                .rejects_by_reason
+                .iter()
+                .map(|(reason, n)| (reason.to_string(), *n))
+                .collect(),
+            suffix_only: self.num_suffix_only,
+            icann: self.num_icann,
+            private: self.num_private,
+            unmatched: self.num_no_match,
+            rows_by_suffix: self.rows_by_suffix.clone(),
+            undelegated_tlds: self.undelegated_tlds.clone(),
+            duration_seconds: duration.as_secs_f64(),
+            complete: None,
+        };
+    }
+
+    /// Why reading stopped before the end of the input, if it did.
+    fn stopped_early(&self) -> Option<&'static str> {
+        if self.stopped_at_deadline {
+            return Some("at --max-runtime");
+        }
+        if self.stopped_low_space {
+            return Some("below --min-free");
+        }
+        return None;
+    }
+}
 
-    let stdout = io::stdout();
-    let stdout = stdout.lock();
-    let mut stdout = BufWriter::new(stdout);
+/// Appends the counts so far to the --stats-stream file.
+fn stream_stats(stream: &mut run_stats::Stream, stats: &Stats, done: bool) -> io::Result<()> {
+    let stats = stats.run_stats(stream.input.clone(), stream.started.elapsed());
+    return stream.write(&stats, done);
+}
 
+struct Digests {
+    input: lineage::FileDigest,
+    output: lineage::FileDigest,
+    rejected: lineage::FileDigest,
+}
+
+/// How many lines are read between looks at the clock for --progress and
+/// --stats-stream.
+const PROGRESS_CHECK_LINES: u64 = 1 << 16;
+
+/// How many lines are read between checks of the --max-runtime deadline,
+/// and of the free space with --min-free.
+const DEADLINE_CHECK_LINES: u64 = 1 << 10;
+const SPACE_CHECK_LINES: u64 = 1 << 14;
+
+/// Extracts `ip,domain` rows from the RDNS records read from `rdr` into
+/// `out`; records that cannot be handled in this pass go to `rejected`.
+/// `line_offset` is the number of input lines before those of `rdr`, for
+/// the line numbers in warnings.
+#[allow(clippy::too_many_arguments)]
+fn process<R: BufRead, W: Write, X: Write>(
+    rdr: &mut R,
+    out: &mut W,
+    rejected: &mut X,
+    tld_set: &Psl,
+    aliases: Option<&alias::Aliases>,
+    mut split_outputs: Option<&mut split::Outputs>,
+    deadline: Option<Instant>,
+    space: Option<&diskspace::Guard>,
+    mut stream: Option<&mut run_stats::Stream>,
+    mut progress: Option<&mut progress::Progress>,
+    batch: Option<&state::Batch>,
+    line_offset: u64,
+    args: &Cli,
+) -> anyhow::Result<Stats> {
     // Use read_line() so that we can re-use the same buffer;
     // the .lines() iterator allocates a new string for every
     // line.
     let mut line = String::with_capacity(4096);
-    let mut num_lines: u64 = 0;
-    let mut num_rejected: u64 = 0;
+    let mut stats = Stats::default();
+    let mut line_no: u64 = 0;
+    let mut per_suffix = args.sample_per_suffix.map(sample::PerSuffix::new);
+    let mut seen = args.dedup.then(|| {
+        dedup::Seen::new(
+            args.dedup_memory,
+            scratch_dir(args),
+            args.min_free.unwrap_or(0),
+        )
+    });
+    // The key of the row for --dedup, which for the CSV and NDJSON rows is
+    // also the row written.
+    let mut dedup_key = Vec::new();
+    let mut graph = match args.format {
+        OutputFormat::Graph => {
+            graph::Graph::header(out)?;
+            Some(graph::Graph::default())
+        }
+        OutputFormat::Csv
+        | OutputFormat::Ndjson
+        | OutputFormat::Parquet
+        | OutputFormat::Cohosting
+        | OutputFormat::Blocks => None,
+    };
+    let mut cohosting = match args.format {
+        OutputFormat::Cohosting => Some(cohost::CoHosting::default()),
+        _ => None,
+    };
+    let mut blocks = match args.format {
+        OutputFormat::Blocks => Some(blocks::Blocks::default()),
+        _ => None,
+    };
+    let mut parquet = match args.format {
+        OutputFormat::Parquet => Some(columnar::Writer::new(
+            &args.fields,
+            args.ip_format,
+            args.ip_signed,
+            usize::try_from(args.row_group_size)?,
+        )?),
+        _ => None,
+    };
 
-    let t0 = std::time::Instant::now();
     loop {
+        if let Some(deadline) = deadline {
+            if line_no.is_multiple_of(DEADLINE_CHECK_LINES) && Instant::now() >= deadline {
+                log::warn!("--max-runtime reached, stopping after {} lines", line_no);
+                stats.stopped_at_deadline = true;
+                break;
+            }
+        }
+        if let Some(space) = space {
+            if line_no.is_multiple_of(SPACE_CHECK_LINES) {
+                if let Some(low) = space.low()? {
+                    log::warn!("{}, stopping after {} lines", low, line_no);
+                    stats.stopped_low_space = true;
+                    break;
+                }
+            }
+        }
+
         line.clear();
         let n = rdr.read_line(&mut line)?;
         if n == 0 {
             break;
         }
+        line_no += 1;
 
-        // If the record contains unicode characters, write it to another file
-        // to be processed later.
-        if line.contains(r"\u") {
-            rejected.write(line.as_bytes())?;
-            num_rejected += 1;
+        // Checking the clock on every line would be wasteful.
+        if let Some(stream) = stream.as_deref_mut() {
+            if line_no.is_multiple_of(PROGRESS_CHECK_LINES) && stream.due() {
+                stream_stats(stream, &stats, false)?;
+            }
+        }
+        if let Some(progress) = progress.as_deref_mut() {
+            if line_no.is_multiple_of(PROGRESS_CHECK_LINES) && progress.due() {
+                progress.report(line_no, stats.num_rows, stats.num_rejected);
+            }
+        }
+
+        // With --reject-unicode, records with unicode escapes are written to
+        // another file to be processed later.
+        if args.reject_unicode && line.contains(r"\u") {
+            rejected.write_all(line.as_bytes())?;
+            stats.count_reject("unicode");
             continue;
         }
 
-        num_lines += 1;
+        stats.num_lines += 1;
 
-        let record: RdnsRecord = match serde_json::from_str(&line) {
+        let record = {
+            let _span = tracing::info_span!("parse").entered();
+            serde_json::from_str::<RdnsRecord>(&line)
+        };
+        // The derived parser is the fast path; what it rejects is tried
+        // again more leniently before being set aside.
+        let record = match record {
             Ok(r) => r,
-            Err(_) => {
-                eprintln!("{}: cannot deserialize this line: {:?}", PROG, line);
+            Err(e) => match RdnsRecord::parse_lenient(&line) {
+                Some(r) => {
+                    stats.num_lenient += 1;
+                    r
+                }
+                None => {
+                    log::warn!("{}", diagnostic::describe(line_offset + line_no, &line, &e));
+                    rejected.write_all(line.as_bytes())?;
+                    stats.count_reject("unparseable");
+                    continue;
+                }
+            },
+        };
+        let kind = split::kind(&record);
+        if let Some(split_outputs) = split_outputs.as_deref_mut() {
+            if kind == split::RecordKind::Other {
+                split_outputs.other.write_all(line.as_bytes())?;
+                stats.num_other_records += 1;
+                continue;
+            }
+        }
+        let mut host = match normalize_host(&record.value, args.empty_labels) {
+            Ok(host) => host,
+            Err(reason) => {
+                rejected.write_all(line.as_bytes())?;
+                stats.count_reject(reason);
                 continue;
             }
         };
-        if let Some(domain) = domain_for(&record.value, &tld_set) {
-            let ip: u32 = u32::from(Ipv4Addr::from_str(&record.name)?);
-            writeln!(stdout, "{},{}", ip, domain)?;
+        let mut trace = !args.trace_host.is_empty() && args.trace_host.iter().any(|h| *h == host);
+        if args.reject_inner_whitespace && host.contains(char::is_whitespace) {
+            rejected.write_all(line.as_bytes())?;
+            stats.count_reject("inner-whitespace");
+            continue;
+        }
+        if let Some(aliases) = aliases {
+            if alias::is_alias(&record) {
+                stats.num_alias_records += 1;
+                if split_outputs.is_none() {
+                    continue;
+                }
+            }
+            let resolved = {
+                let _span = tracing::info_span!("enrich").entered();
+                aliases.resolve(&host, args.max_alias_depth, trace)
+            };
+            match resolved {
+                Ok(Some((target, depth))) => {
+                    *stats.alias_depths.entry(depth).or_insert(0) += 1;
+                    host = Cow::Borrowed(target);
+                }
+                Ok(None) => {}
+                Err(reason) => {
+                    rejected.write_all(line.as_bytes())?;
+                    stats.count_reject(reason);
+                    continue;
+                }
+            }
+        }
+        if let Some(form) = args.unicode_normalization {
+            if !host.is_ascii() {
+                host = Cow::Owned(idn::normalize(&host, form));
+            }
+        }
+        if let Some(strictness) = args.idna_strictness {
+            if let Some((outcome, checked)) = idn::check(&host, strictness) {
+                stats.idna_outcomes[outcome as usize] += 1;
+                match checked {
+                    None => {
+                        rejected.write_all(line.as_bytes())?;
+                        stats.count_reject("invalid-idna");
+                        continue;
+                    }
+                    Some(Cow::Owned(converted)) => host = Cow::Owned(converted),
+                    Some(Cow::Borrowed(_)) => {}
+                }
+            }
+        } else if !args.reject_unicode && !host.is_ascii() {
+            match idn::to_ascii(&host) {
+                Some(ascii) => host = Cow::Owned(ascii),
+                None => {
+                    rejected.write_all(line.as_bytes())?;
+                    stats.count_reject("invalid-idna");
+                    continue;
+                }
+            }
+        }
+        // A Unicode host is matched again once in Punycode.
+        trace =
+            trace || (!args.trace_host.is_empty() && args.trace_host.iter().any(|h| *h == host));
+        if args.strict_lengths {
+            if let Err(reason) = check_lengths(&host) {
+                rejected.write_all(line.as_bytes())?;
+                stats.count_reject(reason);
+                continue;
+            }
+        }
+        if let Some(tld) = args.iana.as_ref().and_then(|iana| iana.undelegated(&host)) {
+            match stats.undelegated_tlds.get_mut(tld) {
+                Some(n) => *n += 1,
+                None => {
+                    stats.undelegated_tlds.insert(tld.to_string(), 1);
+                }
+            }
         }
+        let matched = {
+            let _span = tracing::info_span!("match").entered();
+            extract_traced(&host, tld_set, trace)
+        };
+        // The emitted domain, and the registrable domain's label, which is
+        // empty for a suffix-only host.
+        let (subdomain, domain, label, suffix) = match matched {
+            ExtractResult::SuffixOnly { suffix } => {
+                stats.num_suffix_only += 1;
+                match args.suffix_only_hosts {
+                    SuffixOnlyHosts::Drop => continue,
+                    SuffixOnlyHosts::Reject => {
+                        rejected.write_all(line.as_bytes())?;
+                        stats.count_reject("suffix-only");
+                        continue;
+                    }
+                    SuffixOnlyHosts::EmitEmpty => ("", "", "", suffix),
+                    SuffixOnlyHosts::EmitAsSuffix => ("", suffix, "", suffix),
+                }
+            }
+            ExtractResult::Matched {
+                subdomain,
+                domain,
+                suffix,
+            } => (subdomain, domain, domain, suffix),
+            ExtractResult::NoMatch => {
+                stats.num_no_match += 1;
+                continue;
+            }
+            ExtractResult::Invalid(reason) => {
+                rejected.write_all(line.as_bytes())?;
+                stats.count_reject(reason);
+                continue;
+            }
+        };
+        // The domain and suffix are the end of the host.
+        let registrable = match label.len() {
+            0 => "",
+            n => &host[host.len() - (n + 1 + suffix.len())..],
+        };
+        // What --state-db and --dedup know a pair by: the registrable
+        // domain, or the suffix of a suffix-only host.
+        let pair_domain = if registrable.is_empty() {
+            suffix
+        } else {
+            registrable
+        };
+        let rule = vfb_tldextract::matched_rule(registrable, suffix, tld_set);
+        let private_rule = rule.is_some_and(|rule| rule.is_private(tld_set));
+        if private_rule {
+            stats.num_private += 1;
+        } else {
+            stats.num_icann += 1;
+        }
+        if let (split::RecordKind::Cname, Some(split_outputs)) =
+            (kind, split_outputs.as_deref_mut())
+        {
+            let alias = match normalize_host(&record.name, args.empty_labels) {
+                Ok(alias) => alias,
+                Err(reason) => {
+                    rejected.write_all(line.as_bytes())?;
+                    stats.count_reject(reason);
+                    continue;
+                }
+            };
+            writeln!(
+                split_outputs.cname,
+                "{},{}",
+                fields::quoted(&alias, b','),
+                fields::quoted(domain, b',')
+            )?;
+            stats.num_cname_rows += 1;
+            continue;
+        }
+        let addr = {
+            let _span = tracing::info_span!("parse").entered();
+            ip::parse_name(&record.name, args.in_addr_arpa, args.lenient_ips)
+        };
+        let addr = match addr {
+            // The state database and blocks are keyed by IPv4 address, and
+            // the Parquet IP column is 32 bits wide.
+            Ok(IpAddr::V6(_)) if args.state.is_some() || blocks.is_some() || parquet.is_some() => {
+                rejected.write_all(line.as_bytes())?;
+                stats.count_reject("unsupported-ipv6");
+                continue;
+            }
+            Ok(addr) => addr,
+            Err(reason) => {
+                rejected.write_all(line.as_bytes())?;
+                stats.count_reject(reason);
+                continue;
+            }
+        };
+        if (!args.only_tld.is_empty() && !args.only_tld.iter().any(|tld| under_tld(suffix, tld)))
+            || args.exclude_tld.iter().any(|tld| under_tld(suffix, tld))
+        {
+            stats.num_tld_filtered += 1;
+            continue;
+        }
+        if let Some(sample) = &args.sample {
+            if !sample.keeps_record(&host, label, suffix, addr) {
+                stats.num_sampled_out += 1;
+                continue;
+            }
+        }
+        if let Some(per_suffix) = &mut per_suffix {
+            if !per_suffix.keeps(suffix) {
+                stats.num_sampled_out += 1;
+                continue;
+            }
+        }
+        let mut op = "";
+        if let (Some(batch), IpAddr::V4(addr)) = (batch, addr) {
+            let new = batch.see(addr, pair_domain, &host)?;
+            if args.cdc {
+                op = if new { "add" } else { "refresh" };
+            } else if !new {
+                stats.num_seen_before += 1;
+                continue;
+            }
+        }
+        let ip = ip::ip_value(addr, args.ip_format, args.ip_endianness, args.ip_signed);
+        let row = fields::Row {
+            ip,
+            host: &host,
+            subdomain,
+            domain,
+            label,
+            suffix,
+            raw_value: &record.value,
+            timestamp: &record.timestamp,
+            rule,
+            private_rule,
+        };
+        let rows = !args.cdc && graph.is_none() && cohosting.is_none() && blocks.is_none();
+        if let Some(seen) = &mut seen {
+            dedup_key.clear();
+            if rows && args.format == OutputFormat::Ndjson {
+                fields::write_json_row(&mut dedup_key, &args.fields, &row)?;
+            } else if rows {
+                fields::write_row(&mut dedup_key, &args.fields, args.delimiter, &row)?;
+            } else {
+                dedup::pair_key(&mut dedup_key, addr, pair_domain);
+            }
+            if !seen.insert(&dedup_key)? {
+                stats.num_duplicates += 1;
+                continue;
+            }
+        }
+        {
+            let _span = tracing::info_span!("write").entered();
+            if let Some(graph) = &mut graph {
+                graph.edge(out, addr, domain)?;
+            } else if let Some(cohosting) = &mut cohosting {
+                cohosting.add(addr, label, suffix);
+            } else if let (Some(blocks), IpAddr::V4(addr)) = (&mut blocks, addr) {
+                blocks.add(addr, &host, domain, label, suffix);
+            } else if args.cdc {
+                writeln!(out, "{},{},{}", op, ip, fields::quoted(pair_domain, b','))?;
+            } else if let Some(parquet) = &mut parquet {
+                parquet.add(&row, out)?;
+            } else if seen.is_some() {
+                out.write_all(&dedup_key)?;
+            } else if args.format == OutputFormat::Ndjson {
+                fields::write_json_row(out, &args.fields, &row)?;
+            } else {
+                fields::write_row(out, &args.fields, args.delimiter, &row)?;
+            }
+        }
+        match op {
+            "add" => stats.num_added += 1,
+            "refresh" => stats.num_refreshed += 1,
+            _ => {}
+        }
+        stats.num_rows += 1;
+        // Counted only for the outputs that report them, each row costing
+        // a lookup.
+        if args.stats_json.is_some()
+            || args.stats_by_tld.is_some()
+            || args.stats_stream.is_some()
+            || args.otlp_endpoint.is_some()
+        {
+            match stats.rows_by_suffix.get_mut(suffix) {
+                Some(n) => *n += 1,
+                None => {
+                    stats.rows_by_suffix.insert(suffix.to_string(), 1);
+                }
+            }
+        }
+    }
+    if graph.is_some() {
+        graph::Graph::footer(out)?;
+    }
+    if let Some(parquet) = parquet {
+        parquet.finish(out)?;
+    }
+    if let Some(cohosting) = &cohosting {
+        let n = cohosting.write(
+            out,
+            args.cohosting_min,
+            args.cohosting_counts,
+            args.ip_format,
+            args.ip_endianness,
+            args.ip_signed,
+        )?;
+        log::info!(
+            "{} addresses with at least {} registrable domains",
+            n,
+            args.cohosting_min
+        );
     }
-    eprintln!(
-        "{}: processed {} lines ({} rejected) in {:?}",
-        PROG,
-        num_lines,
-        num_rejected,
+    if let Some(blocks) = &mut blocks {
+        let n = blocks.write(
+            out,
+            args.min_block,
+            args.ip_format,
+            args.ip_endianness,
+            args.ip_signed,
+        )?;
+        log::info!("{} ranges of at least {} addresses", n, args.min_block);
+    }
+    if let Some(seen) = &seen {
+        stats.num_dedup_spilled = seen.spilled();
+    }
+    return Ok(stats);
+}
+
+/// Processes `input_file`, or stdin for `-`, writing rows to `output_file`
+/// (or stdout) and creating `rejected_file` for the records set aside.  Reading
+/// stops early, with `stopped_at_deadline` set, once `deadline` passes, and
+/// with `stopped_low_space` set when a volume written to falls below
+/// --min-free.  With --state-db, what the run sees is staged in `batch`,
+/// for the caller to commit once the output is in place.
+#[allow(clippy::too_many_arguments)]
+fn process_file(
+    input_files: &[&Path],
+    output_file: Option<&Path>,
+    rejected_file: &Path,
+    tld_set: &Psl,
+    deadline: Option<Instant>,
+    stream: Option<&mut run_stats::Stream>,
+    batch: Option<&state::Batch>,
+    args: &Cli,
+) -> anyhow::Result<Stats> {
+    let policy = retry::RetryPolicy {
+        retries: args.io_retries,
+        delay: Duration::from_millis(args.io_retry_delay_ms),
+    };
+    let retries = Arc::new(AtomicU64::new(0));
+    let space = match args.min_free {
+        Some(min_free) => {
+            let mut written = vec![rejected_file];
+            written.extend(output_file);
+            written.extend(args.state_db.as_deref());
+            let scratch = scratch_dir(args);
+            let dirs = if args.dedup {
+                vec![scratch.as_path()]
+            } else {
+                vec![]
+            };
+            let space = diskspace::Guard::new(&written, &dirs, min_free)?;
+            if let Some(low) = space.low()? {
+                anyhow::bail!("{}", low);
+            }
+            Some(space)
+        }
+        None => None,
+    };
+    let checksums = args.lineage.is_some().then(|| {
+        [
+            lineage::Checksum::new(),
+            lineage::Checksum::new(),
+            lineage::Checksum::new(),
+        ]
+    });
+
+    let open_input = |path: &Path| -> io::Result<Box<dyn Read>> {
+        if path == Path::new(STDIN) {
+            return Ok(Box::new(io::stdin()));
+        }
+        let file = File::open(path)?;
+        return Ok(Box::new(retry::Retrying::new(
+            file,
+            policy,
+            retries.clone(),
+        )));
+    };
+
+    let buffer_size = usize::try_from(args.buffer_size)?;
+    let aliases = if args.follow_aliases {
+        let inputs = inputs::Chain::new(input_files, |path| {
+            return compression::decoder(open_input(path)?, path, args.compression);
+        });
+        let mut rdr = BufReader::with_capacity(buffer_size, inputs);
+        Some(alias::collect(&mut rdr, args.empty_labels)?)
+    } else {
+        None
+    };
+
+    // With --checksum-files, each file written, the name it is to have,
+    // and the digest of what went into it.
+    let mut sidecars = Vec::new();
+    let mut open_output = |path: &Path| -> anyhow::Result<Box<dyn Write>> {
+        let file = create_output_file(path, args)?;
+        let file: Box<dyn Write> = if args.direct_io {
+            let file = match direct_io::DirectWriter::new(file, buffer_size) {
+                Ok(file) => file,
+                Err(e) => anyhow::bail!("{}: cannot use --direct-io: {}", path.display(), e),
+            };
+            Box::new(retry::Retrying::new(file, policy, retries.clone()))
+        } else {
+            Box::new(retry::Retrying::new(file, policy, retries.clone()))
+        };
+        if !args.checksum_files {
+            return Ok(file);
+        }
+        // The --output temporary file is named after the file it replaces.
+        let name = match (args.output.as_deref(), output_file) {
+            (Some(name), Some(output_file)) if path == output_file => name,
+            _ => path,
+        };
+        let checksum = lineage::Checksum::new();
+        sidecars.push((path.to_path_buf(), name.to_path_buf(), checksum.clone()));
+        return Ok(Box::new(checksum.writer(file)));
+    };
+    let mut reservation = None;
+    let mut out: Box<dyn Write> = match output_file {
+        Some(output_file) => {
+            let out = open_output(output_file)?;
+            if let Some(ratio) = args.preallocate {
+                reservation = prealloc::reserve(output_file, input_files, ratio)?;
+            }
+            out
+        }
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut rejected = open_output(rejected_file)?;
+    if let Some([_, c_output, c_rejected]) = &checksums {
+        out = Box::new(c_output.writer(out));
+        rejected = Box::new(c_rejected.writer(rejected));
+    }
+
+    let mut split_outputs = match &args.split_by_type {
+        Some(dir) => {
+            let mut create = |name: &str| -> anyhow::Result<BufWriter<Box<dyn Write>>> {
+                return Ok(BufWriter::with_capacity(
+                    buffer_size,
+                    open_output(&dir.join(name))?,
+                ));
+            };
+            Some(split::Outputs {
+                cname: create(split::CNAME_FILE)?,
+                other: create(split::OTHER_FILE)?,
+            })
+        }
+        None => None,
+    };
+
+    let names: Vec<String> = input_files
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect();
+    let _span = tracing::info_span!("file", path = %names.join(", ")).entered();
+    // -v logs progress too, at debug level.
+    let mut progress = (args.progress || log::log_enabled!(log::Level::Debug))
+        .then(|| progress::Progress::new(input_files, args.progress));
+    let bytes_read = progress.as_ref().map(progress::Progress::counter);
+    let inputs = inputs::Chain::new(input_files, |path| -> anyhow::Result<Box<dyn Read>> {
+        let mut file = open_input(path)?;
+        if let Some([c_input, _, _]) = &checksums {
+            file = Box::new(c_input.reader(file));
+        }
+        if let Some(read) = &bytes_read {
+            file = Box::new(progress::CountingReader::new(file, read.clone()));
+        }
+        let file = spans::PhaseReader::new(file, spans::Phase::Read);
+        return Ok(Box::new(spans::PhaseReader::new(
+            compression::decoder(Box::new(file), path, args.compression)?,
+            spans::Phase::Decompress,
+        )));
+    });
+    let mut rdr = BufReader::with_capacity(buffer_size, inputs);
+    let mut out = BufWriter::with_capacity(buffer_size, out);
+    let mut rejected = BufWriter::with_capacity(buffer_size, rejected);
+    let mut stats = if args.threads == 1 {
+        process(
+            &mut rdr,
+            &mut out,
+            &mut rejected,
+            tld_set,
+            aliases.as_ref(),
+            split_outputs.as_mut(),
+            deadline,
+            space.as_ref(),
+            stream,
+            progress.as_mut(),
+            batch,
+            0,
+            args,
+        )?
+    } else {
+        parallel::process(
+            &mut rdr,
+            &mut out,
+            &mut rejected,
+            tld_set,
+            aliases.as_ref(),
+            deadline,
+            space.as_ref(),
+            stream,
+            progress.as_mut(),
+            args,
+        )?
+    };
+    // Pairs are only known to be gone once the whole input has been read.
+    if (args.cdc || args.expire.is_some()) && stats.stopped_early().is_none() {
+        if let Some(batch) = batch {
+            let removed = batch.sweep(args.expire, |addr, domain| {
+                if !args.cdc {
+                    return Ok(());
+                }
+                let ip = ip::ip_value(
+                    IpAddr::V4(addr),
+                    args.ip_format,
+                    args.ip_endianness,
+                    args.ip_signed,
+                );
+                return writeln!(out, "remove,{},{}", ip, fields::quoted(domain, b','));
+            })?;
+            if args.cdc {
+                stats.num_removed = removed;
+            } else {
+                stats.num_expired = removed;
+            }
+        }
+    }
+    if let Some(split_outputs) = &mut split_outputs {
+        split_outputs.flush()?;
+    }
+    rejected.flush()?;
+    out.flush()?;
+    if let Some(reservation) = reservation {
+        reservation.release()?;
+    }
+    for (path, name, checksum) in &sidecars {
+        write_sidecar(path, name, &checksum.finish(), args)?;
+    }
+    stats.num_retries = retries.load(Ordering::Relaxed);
+    if let Some([c_input, c_output, c_rejected]) = &checksums {
+        stats.digests = Some(Digests {
+            input: c_input.finish(),
+            output: c_output.finish(),
+            rejected: c_rejected.finish(),
+        });
+    }
+    return Ok(stats);
+}
+
+/// Logs the summary line for a run, or for one `input` of a watch or
+/// manifest.
+fn report(input: Option<&Path>, stats: &Stats, t0: Instant) {
+    let prefix = match input {
+        Some(input) => format!("{}: ", input.display()),
+        None => String::new(),
+    };
+    let mut extra = String::new();
+    // With --reject-unicode, records with unicode escapes are routinely set
+    // aside; only break down the count when something else was rejected too.
+    if stats
+        .rejects_by_reason
+        .keys()
+        .any(|&reason| reason != "unicode")
+    {
+        let reasons: Vec<String> = stats
+            .rejects_by_reason
+            .iter()
+            .map(|(reason, n)| format!("{} {}", n, reason))
+            .collect();
+        extra.push_str(&format!(" [{}]", reasons.join(", ")));
+    }
+    if stats.num_suffix_only > 0 {
+        extra.push_str(&format!(", {} suffix-only", stats.num_suffix_only));
+    }
+    if stats.num_lenient > 0 {
+        extra.push_str(&format!(", {} parsed leniently", stats.num_lenient));
+    }
+    if stats.num_icann + stats.num_private + stats.num_no_match > 0 {
+        extra.push_str(&format!(
+            ", by PSL section: {} icann, {} private, {} unmatched",
+            stats.num_icann, stats.num_private, stats.num_no_match
+        ));
+    }
+    if stats.idna_outcomes.iter().any(|&n| n > 0) {
+        let [valid, rejected, raw, converted] = stats.idna_outcomes;
+        extra.push_str(&format!(
+            ", IDNA hosts: {} valid, {} rejected, {} raw, {} converted",
+            valid, rejected, raw, converted
+        ));
+    }
+    if stats.num_cname_rows > 0 || stats.num_other_records > 0 {
+        extra.push_str(&format!(
+            ", {} CNAME rows, {} other records",
+            stats.num_cname_rows, stats.num_other_records
+        ));
+    }
+    if stats.num_added + stats.num_refreshed + stats.num_removed > 0 {
+        extra.push_str(&format!(
+            ", {} added, {} refreshed, {} removed",
+            stats.num_added, stats.num_refreshed, stats.num_removed
+        ));
+    }
+    if stats.num_expired > 0 {
+        extra.push_str(&format!(", {} expired", stats.num_expired));
+    }
+    if stats.num_seen_before > 0 {
+        extra.push_str(&format!(", {} seen before", stats.num_seen_before));
+    }
+    if stats.num_duplicates > 0 {
+        extra.push_str(&format!(", {} duplicates", stats.num_duplicates));
+    }
+    if stats.num_dedup_spilled > 0 {
+        extra.push_str(&format!(
+            ", {} rows spilled to disk by --dedup",
+            stats.num_dedup_spilled
+        ));
+    }
+    if stats.num_sampled_out > 0 {
+        extra.push_str(&format!(", {} sampled out", stats.num_sampled_out));
+    }
+    if stats.num_tld_filtered > 0 {
+        extra.push_str(&format!(", {} filtered by TLD", stats.num_tld_filtered));
+    }
+    if let Some(why) = stats.stopped_early() {
+        extra.push_str(&format!(", stopped {}", why));
+    }
+    if !stats.undelegated_tlds.is_empty() {
+        let mut tlds: Vec<(&String, &u64)> = stats.undelegated_tlds.iter().collect();
+        tlds.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let total: u64 = tlds.iter().map(|(_, n)| *n).sum();
+        let top: Vec<String> = tlds
+            .iter()
+            .take(5)
+            .map(|(tld, n)| format!("{} {}", n, tld))
+            .collect();
+        extra.push_str(&format!(
+            ", {} with an undelegated TLD [{}{}]",
+            total,
+            top.join(", "),
+            if tlds.len() > 5 { ", ..." } else { "" }
+        ));
+    }
+    if stats.num_retries > 0 {
+        extra.push_str(&format!(", {} I/O retries", stats.num_retries));
+    }
+    if stats.num_alias_records > 0 || !stats.alias_depths.is_empty() {
+        let depths: Vec<String> = stats
+            .alias_depths
+            .iter()
+            .map(|(depth, n)| format!("{} at depth {}", n, depth))
+            .collect();
+        extra.push_str(&format!(
+            ", {} alias records, {} hosts resolved through aliases [{}]",
+            stats.num_alias_records,
+            stats.alias_depths.values().sum::<u64>(),
+            depths.join(", ")
+        ));
+    }
+    log::info!(
+        "{}processed {} lines ({} rejected{}) in {:?}",
+        prefix,
+        stats.num_lines,
+        stats.num_rejected,
+        extra,
         t0.elapsed()
     );
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = Cli::parse();
+    // Without TLD_DATA_FILE, the positionals of a single-file run are
+    // INPUT_FILE REJECTED_FILE; the input is compressed or JSON (or stdin)
+    // and a suffix list never is, which tells the two apart without risking
+    // an input being truncated as the rejected file.
+    if args.rejected_file.is_none() && args.input_file.is_some() {
+        let first = args.tld_data_file.take().unwrap();
+        if first != Path::new(STDIN) && !is_input_file(&first) {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::MissingRequiredArgument,
+                    "the following required arguments were not provided:\n  <REJECTED_FILE>",
+                )
+                .exit();
+        }
+        args.rejected_file = args.input_file.take();
+        args.input_file = Some(first);
+    }
+    if !args.sample_by.is_empty() {
+        match sample::SampleBy::parse(&args.sample_by) {
+            Ok(sample) => args.sample = Some(sample),
+            Err(e) => Cli::command()
+                .error(clap::error::ErrorKind::ValueValidation, e)
+                .exit(),
+        }
+    }
+    if args.jobs > 1 && args.state_db.is_some() {
+        // Each job reads the database before any other job's pairs are
+        // committed, so concurrent jobs would all report a shared pair as new.
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--jobs above 1 cannot be used with --state-db",
+            )
+            .exit();
+    }
+    if args.threads != 1 && !matches!(args.format, OutputFormat::Csv | OutputFormat::Ndjson) {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--threads only works with --format csv or ndjson",
+            )
+            .exit();
+    }
+    if args.dedup && !scratch_dir(&args).is_dir() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "--dedup: {} is not a directory",
+                    scratch_dir(&args).display()
+                ),
+            )
+            .exit();
+    }
+    if args.emit_raw_value && !args.fields.contains(&fields::Field::RawValue) {
+        args.fields.push(fields::Field::RawValue);
+    }
+    if args.emit_rule {
+        for field in [fields::Field::Rule, fields::Field::RuleSection] {
+            if !args.fields.contains(&field) {
+                args.fields.push(field);
+            }
+        }
+    }
+    logging::init(args.quiet, args.verbose);
+    // Before any thread is started, so that they all inherit it.
+    if let Some(cpus) = &args.cpu_affinity {
+        parallel::pin(cpus)?;
+    }
+    if let Some(path) = &args.iana_tlds {
+        let iana = iana::TldList::load(path)?;
+        log::debug!("{} delegated TLDs in {}", iana.len(), path.display());
+        args.iana = Some(iana);
+    }
+
+    if let Some(shell) = args.completions {
+        clap_complete::generate(shell, &mut Cli::command(), PROG, &mut io::stdout());
+        return Ok(());
+    }
+
+    if args.man {
+        clap_mangen::Man::new(Cli::command()).render(&mut io::stdout())?;
+        return Ok(());
+    }
+
+    // A deadline too far off for the clock to hold is none at all.
+    let deadline = args.max_runtime.and_then(|d| Instant::now().checked_add(d));
+    if let Some(path) = &args.state_db {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        args.state = Some(state::StateDb::open(path, now)?);
+    }
+    // Held until the end of main, when it completes the trace file.
+    let _trace_guard = match &args.trace_spans {
+        Some(path) => Some(spans::init(path)?),
+        None => None,
+    };
+
+    if let (Some(ips_file), Some(state)) = (&args.annotate_ips, &args.state) {
+        let mut out = BufWriter::new(io::stdout().lock());
+        return annotate::ips(ips_file, state, &mut out);
+    }
+    if let (Some(domains_file), Some(state)) = (&args.annotate_domains, &args.state) {
+        let mut out = BufWriter::new(io::stdout().lock());
+        return annotate::domains(domains_file, state, args.subdomains, &mut out);
+    }
+
+    if let [a, b] = args.report_diff.as_slice() {
+        return run_stats::diff(a, b);
+    }
+
+    if args.psl_update {
+        return psl_cache::update(args.max_age);
+    }
+
+    let cached;
+    let tld_data_file = match args.tld_data_file.as_deref().or_else(|| embedded::path()) {
+        Some(path) => path,
+        None => {
+            cached = psl_cache::cached_list()?;
+            cached.as_path()
+        }
+    };
+
+    if let Some(dir) = &args.watch {
+        check_distinct_paths(&[("TLD data", tld_data_file), ("watched", dir)])?;
+        let tld_set = load_matching_psl(tld_data_file, &args)?;
+        return watch::watch(dir, &tld_set, &args);
+    }
+
+    if let Some(path) = &args.compile_psl {
+        check_distinct_paths(&[("TLD data", tld_data_file), ("compiled", path)])?;
+        let tld_set = load_psl(tld_data_file, &args)?;
+        let mut out = BufWriter::new(create_output_file(path, &args)?);
+        tld_set.write_compiled(&mut out)?;
+        out.flush()?;
+        log::info!("compiled {} rules to {}", tld_set.len(), path.display());
+        return Ok(());
+    }
+
+    if let Some(old) = &args.psl_diff {
+        let (old, new) = psl_diff::load(old, tld_data_file, &args)?;
+        return psl_diff::run(&old, &new, args.diff_sample.as_deref(), &args);
+    }
+
+    if args.selftest {
+        let tld_set = load_psl(tld_data_file, &args)?;
+        return selftest::run(&tld_set, &tld_data_file.display().to_string());
+    }
+
+    if let [hosts, expected] = args.compare.as_slice() {
+        let tld_set = load_matching_psl(tld_data_file, &args)?;
+        return compare::run(hosts, expected, &tld_set);
+    }
+
+    if let Some(manifest) = &args.manifest {
+        let tld_set = load_matching_psl(tld_data_file, &args)?;
+        return manifest::run(manifest, tld_data_file, &tld_set, deadline, &args);
+    }
+
+    // Both are required unless another mode was chosen.
+    let input_file = args.input_file.as_deref().unwrap();
+    let rejected_file = args.rejected_file.as_deref().unwrap();
+    let mut paths = vec![
+        ("TLD data", tld_data_file.to_path_buf()),
+        ("rejected", rejected_file.to_path_buf()),
+    ];
+    let mut input_files = vec![input_file];
+    input_files.extend(args.more_inputs.iter().map(PathBuf::as_path));
+    for &input in &input_files {
+        if input == Path::new(STDIN) {
+            if args.follow_aliases {
+                anyhow::bail!("--follow-aliases reads the input twice, so it cannot read stdin");
+            }
+        } else {
+            paths.push(("input", input.to_path_buf()));
+        }
+    }
+    for extra in &args.extra_psl {
+        paths.push(("extra suffix", extra.clone()));
+    }
+    if let Some(path) = &args.stats_json {
+        paths.push(("stats JSON", path.clone()));
+    }
+    if let Some(path) = &args.stats_stream {
+        paths.push(("stats stream", path.clone()));
+    }
+    if let Some(path) = &args.stats_by_tld {
+        paths.push(("stats by TLD", path.clone()));
+    }
+    let temp_output = match &args.output {
+        Some(path) => {
+            paths.push(("output", path.clone()));
+            Some(temp_output_path(path)?)
+        }
+        None => None,
+    };
+    // With --split-by-type, the PTR rows go to a file rather than stdout.
+    let output_file = match &args.split_by_type {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            paths.push(("PTR output", dir.join(split::PTR_FILE)));
+            paths.push(("CNAME output", dir.join(split::CNAME_FILE)));
+            paths.push(("other output", dir.join(split::OTHER_FILE)));
+            Some(dir.join(split::PTR_FILE))
+        }
+        None => temp_output.clone(),
+    };
+    let paths: Vec<(&str, &Path)> = paths.iter().map(|(role, p)| (*role, p.as_path())).collect();
+    check_distinct_paths(&paths)?;
+    let tld_set = load_matching_psl(tld_data_file, &args)?;
+
+    let input_name = input_files
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<String>>()
+        .join(" ");
+    let exporter = match &args.otlp_endpoint {
+        Some(endpoint) => Some(otlp::Exporter::new(endpoint)?),
+        None => None,
+    };
+    let mut stream = match &args.stats_stream {
+        Some(path) => Some(run_stats::Stream::new(
+            append_output_file(path, &args)?,
+            input_name.clone(),
+            args.stats_interval,
+        )),
+        None => None,
+    };
+    let batch = match &args.state {
+        Some(state) => Some(state.batch()?),
+        None => None,
+    };
+    let started = SystemTime::now();
+    let t0 = Instant::now();
+    let result = process_file(
+        &input_files,
+        output_file.as_deref(),
+        rejected_file,
+        &tld_set,
+        deadline,
+        stream.as_mut(),
+        batch.as_ref(),
+        &args,
+    );
+    // An incomplete output never replaces the previous one.
+    if let (Some(path), Some(temp)) = (&args.output, &temp_output) {
+        match &result {
+            Ok(stats) if stats.stopped_early().is_none() => {
+                File::open(temp)?.sync_all()?;
+                std::fs::rename(temp, path)?;
+                if args.checksum_files {
+                    std::fs::rename(sidecar_path(temp), sidecar_path(path))?;
+                }
+            }
+            _ => {
+                for temp in [temp.clone(), sidecar_path(temp)] {
+                    match std::fs::remove_file(&temp) {
+                        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                            log::warn!("{}: {}", temp.display(), e);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    let stats = result?;
+    // The pairs of an incomplete output are left for the next run to emit.
+    if let (Some(batch), None) = (batch, stats.stopped_early()) {
+        batch.commit()?;
+    }
+    if let Some(stream) = &mut stream {
+        stream_stats(stream, &stats, true)?;
+    }
+    if let Some(exporter) = &exporter {
+        let run_stats = stats.run_stats(input_name.clone(), t0.elapsed());
+        if let Err(e) = exporter.export(&run_stats, started, stats.stopped_early()) {
+            log::warn!("cannot export to --otlp-endpoint: {}", e);
+        }
+    }
+    report(None, &stats, t0);
+    rusage::report();
+    // Written even for a run that stopped early, as what it did count
+    // tells how far it got.
+    if let Some(path) = &args.stats_json {
+        let mut run_stats = stats.run_stats(input_name, t0.elapsed());
+        run_stats.complete = Some(stats.stopped_early().is_none());
+        run_stats::write(create_output_file(path, &args)?, &run_stats)?;
+    }
+    if let Some(path) = &args.stats_by_tld {
+        run_stats::write_by_suffix(
+            create_output_file(path, &args)?,
+            &stats.rows_by_suffix,
+            stats.stopped_early(),
+        )?;
+    }
+    if let Some(why) = stats.stopped_early() {
+        anyhow::bail!("stopped {}; the output is incomplete", why);
+    }
+
+    if let (Some(path), Some(digests)) = (&args.lineage, &stats.digests) {
+        let mut suffix_files = vec![tld_data_file];
+        suffix_files.extend(args.extra_psl.iter().map(PathBuf::as_path));
+        let mut suffix_digests = Vec::new();
+        for file in &suffix_files {
+            suffix_digests.push(match embedded::is(file) {
+                true => embedded::digest()?,
+                false => lineage::file_digest(file)?,
+            });
+        }
+        let mut inputs: Vec<lineage::Dataset> = suffix_files
+            .iter()
+            .zip(&suffix_digests)
+            .map(|(path, digest)| lineage::Dataset {
+                path,
+                digest,
+                rows: None,
+            })
+            .collect();
+        inputs.push(lineage::Dataset {
+            path: input_file,
+            digest: &digests.input,
+            rows: None,
+        });
+        let usage = rusage::current()?;
+        lineage::write_lineage(
+            path,
+            started,
+            &inputs,
+            &[
+                lineage::Dataset {
+                    path: args.output.as_deref().unwrap_or(Path::new("-")),
+                    digest: &digests.output,
+                    rows: Some(stats.num_rows),
+                },
+                lineage::Dataset {
+                    path: rejected_file,
+                    digest: &digests.rejected,
+                    rows: Some(stats.num_rejected),
+                },
+            ],
+            serde_json::json!({
+                "lines": stats.num_lines,
+                "rejected": stats.num_rejected,
+                "ioRetries": stats.num_retries,
+                "durationSeconds": t0.elapsed().as_secs_f64(),
+                "maxRssBytes": usage.max_rss,
+                "userCpuSeconds": usage.user.as_secs_f64(),
+                "systemCpuSeconds": usage.system.as_secs_f64(),
+            }),
+        )?;
+    }
     return Ok(());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `input` through `process` with the options in `flags`, against
+    /// a list with the single rule `com`.
+    fn run(input: &str, flags: &[&str]) -> (Stats, String, String) {
+        return run_with("com\n", input, flags);
+    }
+
+    /// Runs `input` through `process` against the list `psl`.
+    fn run_with(psl: &str, input: &str, flags: &[&str]) -> (Stats, String, String) {
+        return run_batch(psl, input, flags, None);
+    }
+
+    /// Runs `input` through `process`, staging what it sees in `batch`.
+    fn run_batch(
+        psl: &str,
+        input: &str,
+        flags: &[&str],
+        batch: Option<&state::Batch>,
+    ) -> (Stats, String, String) {
+        let mut argv = vec![PROG, "psl.dat", "-", "rejected"];
+        argv.extend_from_slice(flags);
+        let args = Cli::try_parse_from(argv).unwrap();
+        let psl = Psl::parse(psl.as_bytes()).unwrap();
+        let (mut out, mut rejected) = (Vec::new(), Vec::new());
+        let stats = process(
+            &mut input.as_bytes(),
+            &mut out,
+            &mut rejected,
+            &psl,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            batch,
+            0,
+            &args,
+        )
+        .unwrap();
+        return (
+            stats,
+            String::from_utf8(out).unwrap(),
+            String::from_utf8(rejected).unwrap(),
+        );
+    }
+
+    fn record(value: &str) -> String {
+        return format!(
+            "{{\"name\":\"192.0.2.1\",\"value\":{:?},\"type\":\"ptr\"}}\n",
+            value
+        );
+    }
+
+    #[cfg(feature = "state-db")]
+    #[test]
+    fn cdc_rows_by_registrable_domain() {
+        let path = std::env::temp_dir().join(format!(".{}-cdc-test.{}", PROG, std::process::id()));
+        let flags = ["--state-db", path.to_str().unwrap(), "--cdc"];
+        let psl = "com\nnet\n";
+        let state = state::StateDb::open_when_free(&path, 86400).unwrap();
+        let batch = state.batch().unwrap();
+        let input = [record("www.example.com"), record("example.net")].concat();
+        let (stats, out, _) = run_batch(psl, &input, &flags, Some(&batch));
+        assert_eq!(
+            out,
+            "add,3221225985,example.com\nadd,3221225985,example.net\n"
+        );
+        assert_eq!(stats.num_added, 2);
+        batch.commit().unwrap();
+        drop(state);
+        let state = state::StateDb::open_when_free(&path, 2 * 86400).unwrap();
+        let batch = state.batch().unwrap();
+        let (stats, out, _) = run_batch(psl, &record("mail.example.com"), &flags, Some(&batch));
+        assert_eq!(out, "refresh,3221225985,example.com\n");
+        assert_eq!(stats.num_refreshed, 1);
+        drop(batch);
+        drop(state);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn rows_by_suffix_for_every_stats_output() {
+        let input = [record("www.example.com"), record("example.com")].concat();
+        let (stats, _, _) = run(&input, &[]);
+        assert!(stats.rows_by_suffix.is_empty());
+        for flags in [
+            &["--stats-json", "stats.json"][..],
+            &["--stats-by-tld", "by-tld.csv"],
+            &["--stats-stream", "stats.ndjson"],
+            &[
+                "--stats-stream",
+                "stats.ndjson",
+                "--otlp-endpoint",
+                "http://localhost:4318",
+            ],
+        ] {
+            let (stats, _, _) = run(&input, flags);
+            assert_eq!(stats.rows_by_suffix.get("com"), Some(&2), "{:?}", flags);
+        }
+    }
+
+    #[test]
+    fn dedup_keeps_rows_that_differ() {
+        let input = [
+            record("www.example.com"),
+            record("example.net"),
+            record("mail.example.com"),
+            record("example.net"),
+        ]
+        .concat();
+        let psl = "com\nnet\n";
+        let (stats, out, _) = run_with(psl, &input, &["--dedup", "--fields", "ip,registrable"]);
+        assert_eq!(out, "3221225985,example.com\n3221225985,example.net\n");
+        assert_eq!(stats.num_duplicates, 2);
+        let (stats, out, _) = run_with(psl, &input, &["--dedup", "--fields", "host"]);
+        assert_eq!(out, "www.example.com\nexample.net\nmail.example.com\n");
+        assert_eq!(stats.num_duplicates, 1);
+        // The same label under two suffixes is the same row with the
+        // default fields.
+        let (stats, out, _) = run_with(psl, &input, &["--dedup"]);
+        assert_eq!(out, "3221225985,example\n");
+        assert_eq!(stats.num_duplicates, 3);
+        let (_, out, _) = run_with(psl, &input, &["--dedup", "--format", "ndjson"]);
+        assert_eq!(out, "{\"ip\":3221225985,\"domain\":\"example\"}\n");
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("512K").unwrap(), 512 << 10);
+        assert_eq!(parse_size("512kib").unwrap(), 512 << 10);
+        assert_eq!(parse_size("2MB").unwrap(), 2 << 20);
+        assert_eq!(parse_size("1G").unwrap(), 1 << 30);
+        assert_eq!(parse_size("3T").unwrap(), 3 << 40);
+        assert_eq!(parse_size("1.5M").unwrap(), 3 << 19);
+        assert_eq!(parse_size("0.5K").unwrap(), 512);
+        assert_eq!(parse_size("1.0001K").unwrap(), 1024);
+        for bad in [
+            "",
+            "K",
+            "1.5",
+            "1.5B",
+            "-1",
+            "1X",
+            "1 K",
+            "1.2.3K",
+            "16777216T",
+        ] {
+            assert!(parse_size(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(
+            parse_duration("90d").unwrap(),
+            Duration::from_secs(90 * 86400)
+        );
+        assert_eq!(
+            parse_duration("1m1s1ms").unwrap(),
+            Duration::from_millis(61001)
+        );
+        for bad in ["", "0", "0s", "s", "1x", "1.5h", "-1s", "1h 30m", "h1"] {
+            assert!(parse_duration(bad).is_err(), "{:?}", bad);
+        }
+        let too_long = parse_duration("18446744073709551615s1s");
+        assert!(too_long.unwrap_err().to_string().contains("too long"));
+        // Units that overflow on their own saturate.
+        assert!(parse_duration("18446744073709551615d").is_ok());
+    }
+
+    #[test]
+    fn delimiters() {
+        assert_eq!(parse_delimiter(",").unwrap(), b',');
+        assert_eq!(parse_delimiter("comma").unwrap(), b',');
+        assert_eq!(parse_delimiter("tab").unwrap(), b'\t');
+        assert_eq!(parse_delimiter("\\t").unwrap(), b'\t');
+        assert_eq!(parse_delimiter("\t").unwrap(), b'\t');
+        assert_eq!(parse_delimiter("|").unwrap(), b'|');
+        for bad in ["", ",,", "\"", "\n", "\r", "é", "tabs"] {
+            assert!(parse_delimiter(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn psl_freshness() {
+        let args = |flags: &[&str]| {
+            let mut argv = vec![PROG, "psl.dat", "-", "rejected"];
+            argv.extend_from_slice(flags);
+            return Cli::try_parse_from(argv).unwrap();
+        };
+        let dated = |days_ago: u64| {
+            let published = SystemTime::now() - Duration::from_secs(days_ago * 86400);
+            let date = &lineage::rfc3339(published)[..10];
+            return Psl::parse(format!("// VERSION: {}\ncom\n", date).as_bytes()).unwrap();
+        };
+        let path = Path::new("psl.dat");
+        let fresh = dated(10);
+        let stale = dated(100);
+        let undated = Psl::parse(&b"com\n"[..]).unwrap();
+        let required = args(&["--require-fresh-psl", "30d"]);
+        assert!(check_psl_age(&fresh, path, &args(&[])).is_ok());
+        // Old lists are only warned about unless a freshness is required.
+        assert!(check_psl_age(&stale, path, &args(&[])).is_ok());
+        assert!(check_psl_age(&undated, path, &args(&[])).is_ok());
+        assert!(check_psl_age(&fresh, path, &required).is_ok());
+        let err = check_psl_age(&stale, path, &required).unwrap_err();
+        assert!(err.to_string().contains("100 days old"), "{}", err);
+        let err = check_psl_age(&undated, path, &required).unwrap_err();
+        assert!(err.to_string().contains("no VERSION date"), "{}", err);
+    }
+
+    #[test]
+    fn tlds() {
+        assert_eq!(parse_tld("com").unwrap(), "com");
+        assert_eq!(parse_tld(" .CO.UK ").unwrap(), "co.uk");
+        for bad in ["", ".", "co.uk.", "co..uk"] {
+            assert!(parse_tld(bad).is_err(), "{:?}", bad);
+        }
+        assert!(under_tld("com", "com"));
+        assert!(under_tld("co.uk", "uk"));
+        assert!(under_tld("CO.UK", "co.uk"));
+        assert!(!under_tld("uk", "co.uk"));
+        assert!(!under_tld("ck", "uk"));
+        assert!(!under_tld("bbc.co.uk", "c.co.uk"));
+        assert!(under_tld("xn--fiqs8s", "xn--fiqs8s"));
+    }
+
+    #[test]
+    fn tld_filters() {
+        let psl = "com\nuk\nco.uk\narpa\n";
+        let input = [
+            record("www.example.com"),
+            record("www.example.co.uk"),
+            record("1.2.0.192.in-addr.arpa"),
+        ]
+        .concat();
+        let (stats, out, _) = run_with(psl, &input, &["--only-tld", "uk,.COM"]);
+        assert_eq!(out, "3221225985,example\n3221225985,example\n");
+        assert_eq!(stats.num_tld_filtered, 1);
+        let (stats, out, _) = run_with(
+            psl,
+            &input,
+            &["--exclude-tld", "arpa", "--fields", "registrable"],
+        );
+        assert_eq!(out, "example.com\nexample.co.uk\n");
+        assert_eq!(stats.num_tld_filtered, 1);
+        let (_, out, _) = run_with(psl, &input, &["--only-tld", "uk", "--exclude-tld", "co.uk"]);
+        assert_eq!(out, "");
+    }
+
+    #[test]
+    fn trace_hosts() {
+        assert_eq!(parse_trace_host("example.com").unwrap(), "example.com");
+        assert_eq!(parse_trace_host(" Example.COM. ").unwrap(), "example.com");
+        assert_eq!(
+            parse_trace_host("www..example.com").unwrap(),
+            "www.example.com"
+        );
+        assert_eq!(parse_trace_host("Bücher.DE").unwrap(), "xn--bcher-kva.de");
+        assert_eq!(
+            parse_trace_host("xn--bcher-kva.de").unwrap(),
+            "xn--bcher-kva.de"
+        );
+        for bad in ["", ".", " "] {
+            assert!(parse_trace_host(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn buffer_sizes() {
+        assert_eq!(parse_buffer_size("64K").unwrap(), 64 << 10);
+        assert_eq!(parse_buffer_size("4096").unwrap(), 4096);
+        for bad in ["0", "0K", "4095", "1K", ""] {
+            assert!(parse_buffer_size(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn normalize_empty_hosts() {
+        for policy in [EmptyLabels::Normalize, EmptyLabels::Reject] {
+            assert_eq!(normalize_host("", policy), Err("empty-host"));
+            assert_eq!(normalize_host(".", policy), Err("empty-host"));
+            assert_eq!(normalize_host("  ", policy), Err("empty-host"));
+            assert_eq!(
+                normalize_host("example.com.", policy).as_deref(),
+                Ok("example.com")
+            );
+        }
+        assert_eq!(
+            normalize_host("..", EmptyLabels::Normalize),
+            Err("empty-host")
+        );
+        assert_eq!(
+            normalize_host("..", EmptyLabels::Reject),
+            Err("empty-label")
+        );
+        assert_eq!(
+            normalize_host("a..example.com", EmptyLabels::Normalize).as_deref(),
+            Ok("a.example.com")
+        );
+    }
+
+    #[test]
+    fn normalize_case() {
+        let host = normalize_host(" Example.COM. ", EmptyLabels::Normalize);
+        assert_eq!(host.as_deref(), Ok("example.com"));
+        let host = normalize_host("www.example.com", EmptyLabels::Normalize).unwrap();
+        assert!(matches!(host, Cow::Borrowed(_)));
+        // Only ASCII letters; the rest is for the IDNA conversion.
+        let host = normalize_host("BÜCHER.Example", EmptyLabels::Normalize);
+        assert_eq!(host.as_deref(), Ok("bÜcher.example"));
+    }
+
+    #[test]
+    fn mixed_case_hosts_match() {
+        let input = [record("WWW.Example.COM"), record("www.example.com")].concat();
+        let (stats, out, _) = run(&input, &[]);
+        assert_eq!(out, "3221225985,example\n3221225985,example\n");
+        assert_eq!(stats.num_no_match, 0);
+    }
+
+    #[test]
+    fn empty_hosts_are_rejected() {
+        let input = [record(""), record("."), record("www.example.com")].concat();
+        let (stats, out, rejected) = run(&input, &[]);
+        assert_eq!(out, "3221225985,example\n");
+        assert_eq!(rejected, [record(""), record(".")].concat());
+        assert_eq!(stats.num_rejected, 2);
+        assert_eq!(stats.rejects_by_reason.get("empty-host"), Some(&2));
+    }
+
+    #[test]
+    fn empty_labels_are_rejected() {
+        let input = [record(".."), record("a..example.com")].concat();
+        let (stats, out, _) = run(&input, &["--empty-labels", "reject"]);
+        assert_eq!(out, "");
+        assert_eq!(stats.rejects_by_reason.get("empty-label"), Some(&2));
+    }
+}
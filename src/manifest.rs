@@ -0,0 +1,149 @@
+//! `--manifest` mode: run a batch of (input, output, rejected) jobs.
+//!
+//! The manifest is either a JSON array of `{"input", "output", "rejected"}`
+//! objects, or a text file with one job per line as three whitespace-separated
+//! paths; blank lines and lines starting with `#` are ignored.
+
+use crate::{check_distinct_paths, process_file, report, Cli};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use vfb_tldextract::Psl;
+
+#[derive(Deserialize)]
+struct Job {
+    input: PathBuf,
+    output: PathBuf,
+    rejected: PathBuf,
+}
+
+fn parse_manifest(filename: &Path) -> anyhow::Result<Vec<Job>> {
+    let text = fs::read_to_string(filename)?;
+    if text.trim_start().starts_with('[') {
+        return Ok(serde_json::from_str(&text)?);
+    }
+    let mut jobs = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            anyhow::bail!(
+                "{}:{}: expected INPUT OUTPUT REJECTED, found {} fields",
+                filename.display(),
+                i + 1,
+                fields.len()
+            );
+        }
+        jobs.push(Job {
+            input: PathBuf::from(fields[0]),
+            output: PathBuf::from(fields[1]),
+            rejected: PathBuf::from(fields[2]),
+        });
+    }
+    return Ok(jobs);
+}
+
+fn run_job(job: &Job, tld_set: &Psl, deadline: Option<Instant>, args: &Cli) -> anyhow::Result<()> {
+    let batch = match &args.state {
+        Some(state) => Some(state.batch()?),
+        None => None,
+    };
+    let t0 = Instant::now();
+    let stats = process_file(
+        &[job.input.as_path()],
+        Some(&job.output),
+        &job.rejected,
+        tld_set,
+        deadline,
+        None,
+        batch.as_ref(),
+        args,
+    )?;
+    report(Some(&job.input), &stats, t0);
+    if let Some(why) = stats.stopped_early() {
+        anyhow::bail!("stopped {}; the output is incomplete", why);
+    }
+    if let Some(batch) = batch {
+        batch.commit()?;
+    }
+    return Ok(());
+}
+
+/// Runs every job in `manifest`, at most `args.jobs` at a time.  A failed job
+/// does not stop the others; the run fails at the end if any job did.  Jobs
+/// that have not started by `deadline` are skipped, and also fail the run.
+/// With `--state-db` each job commits its pairs before the next one starts,
+/// which is why main refuses `--jobs` above 1 there.
+pub fn run(
+    manifest: &Path,
+    tld_data_file: &Path,
+    tld_set: &Psl,
+    deadline: Option<Instant>,
+    args: &Cli,
+) -> anyhow::Result<()> {
+    let jobs = parse_manifest(manifest)?;
+
+    // No job may read or write a file that another job (or a suffix file)
+    // also uses.
+    let mut paths: Vec<(String, &Path)> = vec![("TLD data".to_string(), tld_data_file)];
+    for extra in &args.extra_psl {
+        paths.push(("extra suffix".to_string(), extra));
+    }
+    for (i, job) in jobs.iter().enumerate() {
+        paths.push((format!("job {} input", i + 1), &job.input));
+        paths.push((format!("job {} output", i + 1), &job.output));
+        paths.push((format!("job {} rejected", i + 1), &job.rejected));
+    }
+    let paths: Vec<(&str, &Path)> = paths.iter().map(|(role, p)| (role.as_str(), *p)).collect();
+    check_distinct_paths(&paths)?;
+
+    let next = AtomicUsize::new(0);
+    let failed = Mutex::new(Vec::new());
+    let skipped = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..args.jobs.max(1).min(jobs.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let job = match jobs.get(i) {
+                    Some(job) => job,
+                    None => break,
+                };
+                if deadline.is_some_and(|d| Instant::now() >= d) {
+                    skipped.lock().unwrap().push(i + 1);
+                    continue;
+                }
+                if let Err(e) = run_job(job, tld_set, deadline, args) {
+                    log::error!("{}: {:#}", job.input.display(), e);
+                    failed.lock().unwrap().push(i + 1);
+                }
+            });
+        }
+    });
+
+    let mut failed = failed.into_inner().unwrap();
+    failed.sort_unstable();
+    let mut skipped = skipped.into_inner().unwrap();
+    skipped.sort_unstable();
+    log::info!(
+        "manifest {}: {} jobs, {} succeeded, {} failed, {} not started",
+        manifest.display(),
+        jobs.len(),
+        jobs.len() - failed.len() - skipped.len(),
+        failed.len(),
+        skipped.len()
+    );
+    crate::rusage::report();
+    if !failed.is_empty() {
+        anyhow::bail!("failed jobs: {:?}", failed);
+    }
+    if !skipped.is_empty() {
+        anyhow::bail!("jobs not started before --max-runtime: {:?}", skipped);
+    }
+    return Ok(());
+}
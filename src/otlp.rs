@@ -0,0 +1,285 @@
+//! `--otlp-endpoint`: the counts of a run sent to an OpenTelemetry
+//! collector over OTLP/HTTP, JSON-encoded, once the run ends: as metrics
+//! (lines, rows, rejects by reason, matched records by section of the list
+//! and the duration), and as a span covering the whole run, in error if
+//! reading stopped early.  This is the summary line in a form the collector
+//! can ingest, not a trace of the records, which --trace-spans gives.
+//!
+//! The endpoint is the base URL of the collector's OTLP/HTTP receiver, as
+//! in `OTEL_EXPORTER_OTLP_ENDPOINT` (`http://collector:4318`); `/v1/metrics`
+//! and `/v1/traces` are appended.  By the time of the export the output is
+//! complete, so a failed export is logged rather than failing the run.
+//!
+//! Exporting needs a build with the `otlp` feature.
+
+use crate::run_stats::RunStats;
+#[cfg(feature = "otlp")]
+use serde_json::{json, Value};
+#[cfg(feature = "otlp")]
+use std::fs::File;
+#[cfg(feature = "otlp")]
+use std::io::Read;
+use std::time::SystemTime;
+#[cfg(feature = "otlp")]
+use std::time::{Duration, UNIX_EPOCH};
+
+/// How long the collector has to answer each request.
+#[cfg(feature = "otlp")]
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(feature = "otlp")]
+pub struct Exporter {
+    endpoint: String,
+}
+
+#[cfg(feature = "otlp")]
+fn nanos(t: SystemTime) -> String {
+    return t
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string();
+}
+
+/// An attribute, whose value is a string or, for a number, an `intValue`
+/// given as a string since OTLP integers are 64 bits.
+#[cfg(feature = "otlp")]
+fn attribute(key: &str, value: Value) -> Value {
+    let value = match value {
+        Value::Number(n) => json!({ "intValue": n.to_string() }),
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    return json!({ "key": key, "value": value });
+}
+
+#[cfg(feature = "otlp")]
+fn resource() -> Value {
+    return json!({
+        "attributes": [
+            attribute("service.name", json!(crate::PROG)),
+            attribute("service.version", json!(env!("CARGO_PKG_VERSION"))),
+        ],
+    });
+}
+
+#[cfg(feature = "otlp")]
+fn scope() -> Value {
+    return json!({ "name": crate::PROG, "version": env!("CARGO_PKG_VERSION") });
+}
+
+/// A cumulative counter for the run, with a data point per set of
+/// attributes.
+#[cfg(feature = "otlp")]
+fn counter(
+    name: &str,
+    description: &str,
+    unit: &str,
+    start: &str,
+    end: &str,
+    points: &[(Vec<Value>, u64)],
+) -> Value {
+    let points: Vec<Value> = points
+        .iter()
+        .map(|(attributes, n)| {
+            json!({
+                "attributes": attributes,
+                "startTimeUnixNano": start,
+                "timeUnixNano": end,
+                "asInt": n.to_string(),
+            })
+        })
+        .collect();
+    return json!({
+        "name": format!("vfb_tldextract.{}", name),
+        "description": description,
+        "unit": unit,
+        "sum": {
+            "aggregationTemporality": 2,
+            "isMonotonic": true,
+            "dataPoints": points,
+        },
+    });
+}
+
+#[cfg(feature = "otlp")]
+fn metrics(stats: &RunStats, started: SystemTime, ended: SystemTime) -> Value {
+    let (start, end) = (nanos(started), nanos(ended));
+    let input = || attribute("input", json!(stats.input));
+    let rejects: Vec<(Vec<Value>, u64)> = stats
+        .rejects_by_reason
+        .iter()
+        .map(|(reason, &n)| (vec![input(), attribute("reason", json!(reason))], n))
+        .collect();
+    let sections: Vec<(Vec<Value>, u64)> = [
+        ("icann", stats.icann),
+        ("private", stats.private),
+        ("none", stats.unmatched),
+    ]
+    .iter()
+    .map(|&(section, n)| (vec![input(), attribute("section", json!(section))], n))
+    .collect();
+    let metrics = vec![
+        counter(
+            "lines",
+            "Input lines read",
+            "{line}",
+            &start,
+            &end,
+            &[(vec![input()], stats.lines)],
+        ),
+        counter(
+            "rows",
+            "Rows written",
+            "{row}",
+            &start,
+            &end,
+            &[(vec![input()], stats.rows)],
+        ),
+        counter(
+            "rejected",
+            "Records set aside, by reason",
+            "{record}",
+            &start,
+            &end,
+            &rejects,
+        ),
+        counter(
+            "matched",
+            "Records by the section of the list their rule is in",
+            "{record}",
+            &start,
+            &end,
+            &sections,
+        ),
+        counter(
+            "suffix_only",
+            "Records whose host is a public suffix",
+            "{record}",
+            &start,
+            &end,
+            &[(vec![input()], stats.suffix_only)],
+        ),
+        json!({
+            "name": "vfb_tldextract.duration",
+            "description": "Time taken by the run",
+            "unit": "s",
+            "gauge": {
+                "dataPoints": [{
+                    "attributes": [input()],
+                    "timeUnixNano": end,
+                    "asDouble": stats.duration_seconds,
+                }],
+            },
+        }),
+    ];
+    return json!({
+        "resourceMetrics": [{
+            "resource": resource(),
+            "scopeMetrics": [{ "scope": scope(), "metrics": metrics }],
+        }],
+    });
+}
+
+/// Random trace and span ids, in the hex form of OTLP/JSON.
+#[cfg(feature = "otlp")]
+fn ids() -> std::io::Result<(String, String)> {
+    let mut b = [0u8; 24];
+    File::open("/dev/urandom")?.read_exact(&mut b)?;
+    return Ok((crate::lineage::hex(&b[..16]), crate::lineage::hex(&b[16..])));
+}
+
+#[cfg(feature = "otlp")]
+fn trace(
+    stats: &RunStats,
+    started: SystemTime,
+    ended: SystemTime,
+    stopped: Option<&str>,
+) -> std::io::Result<Value> {
+    let (trace_id, span_id) = ids()?;
+    let status = match stopped {
+        // STATUS_CODE_ERROR
+        Some(why) => json!({ "code": 2, "message": format!("stopped {}", why) }),
+        // STATUS_CODE_OK
+        None => json!({ "code": 1 }),
+    };
+    let span = json!({
+        "traceId": trace_id,
+        "spanId": span_id,
+        "name": "run",
+        // SPAN_KIND_INTERNAL
+        "kind": 1,
+        "startTimeUnixNano": nanos(started),
+        "endTimeUnixNano": nanos(ended),
+        "attributes": [
+            attribute("input", json!(stats.input)),
+            attribute("lines", json!(stats.lines)),
+            attribute("rows", json!(stats.rows)),
+            attribute("rejected", json!(stats.rejected)),
+        ],
+        "status": status,
+    });
+    return Ok(json!({
+        "resourceSpans": [{
+            "resource": resource(),
+            "scopeSpans": [{ "scope": scope(), "spans": [span] }],
+        }],
+    }));
+}
+
+#[cfg(feature = "otlp")]
+impl Exporter {
+    pub fn new(endpoint: &str) -> anyhow::Result<Exporter> {
+        if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+            anyhow::bail!("--otlp-endpoint {:?}: not an http or https URL", endpoint);
+        }
+        return Ok(Exporter {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+        });
+    }
+
+    /// Errors from ureq name the URL already.
+    fn post(&self, path: &str, body: &Value) -> anyhow::Result<()> {
+        ureq::post(&format!("{}{}", self.endpoint, path))
+            .timeout(TIMEOUT)
+            .set("Content-Type", "application/json")
+            .send_bytes(&serde_json::to_vec(body)?)?;
+        return Ok(());
+    }
+
+    /// Sends the metrics and the span of a run that started at `started`;
+    /// `stopped` is why it stopped early, if it did.
+    pub fn export(
+        &self,
+        stats: &RunStats,
+        started: SystemTime,
+        stopped: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let ended = SystemTime::now();
+        self.post("/v1/metrics", &metrics(stats, started, ended))?;
+        self.post("/v1/traces", &trace(stats, started, ended, stopped)?)?;
+        log::debug!("exported the run to {}", self.endpoint);
+        return Ok(());
+    }
+}
+
+/// Without the feature there is no way to create an exporter, so no value
+/// of this type can exist.
+#[cfg(not(feature = "otlp"))]
+pub enum Exporter {}
+
+#[cfg(not(feature = "otlp"))]
+impl Exporter {
+    pub fn new(_endpoint: &str) -> anyhow::Result<Exporter> {
+        anyhow::bail!("--otlp-endpoint needs a build with the otlp feature");
+    }
+
+    pub fn export(
+        &self,
+        _stats: &RunStats,
+        _started: SystemTime,
+        _stopped: Option<&str>,
+    ) -> anyhow::Result<()> {
+        match *self {}
+    }
+}
@@ -0,0 +1,322 @@
+//! `--threads`: the records of a file parsed and matched by a pool of
+//! worker threads, for dumps where one core cannot keep up with the
+//! decompression.
+//!
+//! The main thread decompresses the input and hands it out in chunks of
+//! `CHUNK_LINES` lines; each worker runs the usual [`process`] over a chunk
+//! into buffers, and the main thread writes those back in input order, so
+//! the output is the same as with one thread.  This only holds for what
+//! keeps no state from one record to the next, hence the conflicts with
+//! --state-db, --sample-per-suffix, --split-by-type and the formats other
+//! than csv and ndjson.
+//!
+//! `--cpu-affinity` restricts the whole process to a set of CPUs; the
+//! worker count of `--threads auto` follows it, as the standard library
+//! reads the affinity mask.
+
+use crate::{alias, diskspace, progress, run_stats, Cli, Stats};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::Instant;
+use vfb_tldextract::Psl;
+
+/// Lines per chunk: enough that the channels cost little per record.
+const CHUNK_LINES: u64 = 4096;
+
+/// Chunks read ahead of the workers, per worker.
+const CHUNKS_PER_THREAD: usize = 2;
+
+/// Parses the N of `--threads`, or `auto` (0) for one per CPU.
+pub fn parse_threads(s: &str) -> anyhow::Result<usize> {
+    if s.eq_ignore_ascii_case("auto") {
+        return Ok(0);
+    }
+    match s.parse() {
+        Ok(n) => return Ok(n),
+        Err(_) => anyhow::bail!("invalid thread count {:?} (a number or auto)", s),
+    }
+}
+
+/// The CPUs of `--cpu-affinity`, sorted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cpus(Vec<usize>);
+
+/// Parses a CPU list such as `0-3,8`.
+pub fn parse_cpus(s: &str) -> anyhow::Result<Cpus> {
+    let mut cpus = Vec::new();
+    for part in s.split(',') {
+        let part = part.trim();
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first, last),
+            None => (part, part),
+        };
+        let (first, last): (usize, usize) = match (first.parse(), last.parse()) {
+            (Ok(first), Ok(last)) if first <= last => (first, last),
+            _ => anyhow::bail!("invalid CPU list {:?} (e.g. 0-3,8)", s),
+        };
+        if last >= libc::CPU_SETSIZE as usize {
+            anyhow::bail!("CPU {} is beyond the {} supported", last, libc::CPU_SETSIZE);
+        }
+        cpus.extend(first..=last);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    return Ok(Cpus(cpus));
+}
+
+/// Restricts the calling thread, and the threads it starts from then on,
+/// to `cpus`.
+pub fn pin(cpus: &Cpus) -> io::Result<()> {
+    // cpu_set_t is a plain bit array, for which all zeroes is the empty
+    // set; parse_cpus has checked every CPU against CPU_SETSIZE.
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for &cpu in &cpus.0 {
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    if unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    log::debug!("running on CPUs {:?}", cpus.0);
+    return Ok(());
+}
+
+struct Chunk {
+    seq: u64,
+    line_offset: u64,
+    text: String,
+}
+
+struct Done {
+    seq: u64,
+    out: Vec<u8>,
+    rejected: Vec<u8>,
+    stats: Stats,
+}
+
+/// The finished chunks that cannot be written yet because an earlier one
+/// is still being worked on.
+struct Reorder {
+    next: u64,
+    pending: BTreeMap<u64, Done>,
+}
+
+impl Reorder {
+    fn add<W: Write, X: Write>(
+        &mut self,
+        done: Done,
+        out: &mut W,
+        rejected: &mut X,
+        stats: &mut Stats,
+    ) -> io::Result<()> {
+        self.pending.insert(done.seq, done);
+        while let Some(done) = self.pending.remove(&self.next) {
+            out.write_all(&done.out)?;
+            rejected.write_all(&done.rejected)?;
+            stats.merge(done.stats);
+            self.next += 1;
+        }
+        return Ok(());
+    }
+}
+
+/// Reads up to `CHUNK_LINES` lines, returning how many were read.
+fn read_chunk<R: BufRead>(rdr: &mut R, text: &mut String) -> io::Result<u64> {
+    let mut n = 0;
+    while n < CHUNK_LINES && rdr.read_line(text)? > 0 {
+        n += 1;
+    }
+    return Ok(n);
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn process<R: BufRead, W: Write, X: Write>(
+    rdr: &mut R,
+    out: &mut W,
+    rejected: &mut X,
+    tld_set: &Psl,
+    aliases: Option<&alias::Aliases>,
+    deadline: Option<Instant>,
+    space: Option<&diskspace::Guard>,
+    mut stream: Option<&mut run_stats::Stream>,
+    mut progress: Option<&mut progress::Progress>,
+    args: &Cli,
+) -> anyhow::Result<Stats> {
+    let threads = match args.threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    log::debug!("processing with {} worker threads", threads);
+    let mut stats = Stats::default();
+    let mut reorder = Reorder {
+        next: 0,
+        pending: BTreeMap::new(),
+    };
+
+    let (work_tx, work_rx) = mpsc::sync_channel::<Chunk>(CHUNKS_PER_THREAD * threads);
+    let work_rx = Mutex::new(work_rx);
+    let (done_tx, done_rx) = mpsc::channel::<anyhow::Result<Done>>();
+    // The senders are moved into the scope so that they are dropped on an
+    // early return too, which lets the workers, and so the scope, finish.
+    thread::scope(|scope| -> anyhow::Result<()> {
+        let work_tx = work_tx;
+        for _ in 0..threads {
+            let work_rx = &work_rx;
+            let done_tx = done_tx.clone();
+            scope.spawn(move || loop {
+                let chunk = match work_rx.lock().unwrap().recv() {
+                    Ok(chunk) => chunk,
+                    Err(_) => return,
+                };
+                let mut out = Vec::new();
+                let mut rejected = Vec::new();
+                let done = crate::process(
+                    &mut chunk.text.as_bytes(),
+                    &mut out,
+                    &mut rejected,
+                    tld_set,
+                    aliases,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    None,
+                    chunk.line_offset,
+                    args,
+                )
+                .map(|stats| Done {
+                    seq: chunk.seq,
+                    out,
+                    rejected,
+                    stats,
+                });
+                if done_tx.send(done).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(done_tx);
+
+        let mut seq = 0;
+        let mut line_no = 0;
+        loop {
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    log::warn!("--max-runtime reached, stopping after {} lines", line_no);
+                    stats.stopped_at_deadline = true;
+                    break;
+                }
+            }
+            if let Some(low) = space.map(|space| space.low()).transpose()?.flatten() {
+                log::warn!("{}, stopping after {} lines", low, line_no);
+                stats.stopped_low_space = true;
+                break;
+            }
+            let mut text = String::new();
+            let n = read_chunk(rdr, &mut text)?;
+            if n == 0 {
+                break;
+            }
+            let chunk = Chunk {
+                seq,
+                line_offset: line_no,
+                text,
+            };
+            // The workers are all gone only if one panicked, which the scope
+            // passes on.
+            if work_tx.send(chunk).is_err() {
+                break;
+            }
+            seq += 1;
+            line_no += n;
+            while let Ok(done) = done_rx.try_recv() {
+                reorder.add(done?, out, rejected, &mut stats)?;
+            }
+            if let Some(stream) = stream.as_deref_mut().filter(|stream| stream.due()) {
+                crate::stream_stats(stream, &stats, false)?;
+            }
+            if let Some(progress) = progress.as_deref_mut().filter(|progress| progress.due()) {
+                progress.report(line_no, stats.num_rows, stats.num_rejected);
+            }
+        }
+        // Let the workers finish once the queue is empty.
+        drop(work_tx);
+        for done in done_rx {
+            reorder.add(done?, out, rejected, &mut stats)?;
+            if let Some(stream) = stream.as_deref_mut().filter(|stream| stream.due()) {
+                crate::stream_stats(stream, &stats, false)?;
+            }
+        }
+        return Ok(());
+    })?;
+    return Ok(stats);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thread_counts() {
+        assert_eq!(parse_threads("1").unwrap(), 1);
+        assert_eq!(parse_threads("16").unwrap(), 16);
+        assert_eq!(parse_threads("auto").unwrap(), 0);
+        assert_eq!(parse_threads("AUTO").unwrap(), 0);
+        assert_eq!(parse_threads("0").unwrap(), 0);
+        for bad in ["", "-1", "all", "1.5"] {
+            assert!(parse_threads(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpus("3").unwrap(), Cpus(vec![3]));
+        assert_eq!(parse_cpus("0-3,8").unwrap(), Cpus(vec![0, 1, 2, 3, 8]));
+        assert_eq!(parse_cpus("8, 2-3,3").unwrap(), Cpus(vec![2, 3, 8]));
+        for bad in ["", "a", "3-1", "1-", "-1", "0,,1", "1024", "0-4096"] {
+            assert!(parse_cpus(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn options() {
+        use crate::{Cli, PROG};
+        use clap::Parser;
+        let argv = [
+            PROG,
+            "psl.dat",
+            "-",
+            "rejected",
+            "--threads",
+            "auto",
+            "--cpu-affinity",
+            "0-1",
+        ];
+        let args = Cli::try_parse_from(argv).unwrap();
+        assert_eq!(args.threads, 0);
+        assert_eq!(args.cpu_affinity, Some(Cpus(vec![0, 1])));
+    }
+
+    #[test]
+    fn pinned_threads_count_their_cpus() {
+        // In a thread of its own, so the test harness keeps its CPUs.
+        thread::spawn(|| {
+            let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+            let size = mem::size_of::<libc::cpu_set_t>();
+            assert_eq!(unsafe { libc::sched_getaffinity(0, size, &mut set) }, 0);
+            let first = (0..libc::CPU_SETSIZE as usize)
+                .find(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+                .unwrap();
+            pin(&Cpus(vec![first])).unwrap();
+            assert_eq!(thread::available_parallelism().unwrap().get(), 1);
+            // Threads started from then on inherit the CPUs.
+            let inherited = thread::spawn(|| thread::available_parallelism().unwrap().get());
+            assert_eq!(inherited.join().unwrap(), 1);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+//! `--preallocate`: space for the output file reserved up front with
+//! `fallocate(2)`, so that a file of hundreds of GB written a buffer at a
+//! time ends up in a few large extents rather than fragmented across the
+//! volume, as happens on busy XFS archives.
+//!
+//! The size reserved is the size of the input files times a ratio from
+//! earlier runs of the same dataset, each of which logs the ratio it saw.
+//! The space is reserved beyond the end of the file, so that the file only
+//! ever holds what was written, and what the run did not use is released
+//! once the output is complete.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+pub struct Reservation {
+    path: PathBuf,
+    /// A handle of its own on the output file.
+    file: File,
+    input_len: u64,
+}
+
+/// Reserves `ratio` times the size of `input_files` for the output file at
+/// `path`, which must exist.  Nothing is reserved for stdin, whose size is
+/// unknown, or when the filesystem cannot preallocate, which is logged.
+pub fn reserve(path: &Path, input_files: &[&Path], ratio: f64) -> io::Result<Option<Reservation>> {
+    let mut input_len = 0;
+    for input in input_files {
+        if *input == Path::new(crate::STDIN) {
+            log::warn!("--preallocate: cannot estimate the output size from stdin");
+            return Ok(None);
+        }
+        input_len += fs::metadata(input)?.len();
+    }
+    let file = OpenOptions::new().write(true).open(path)?;
+    let len = (input_len as f64 * ratio) as libc::off_t;
+    if len > 0 {
+        let rc = unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) };
+        if rc != 0 {
+            log::warn!(
+                "{}: cannot preallocate: {}",
+                path.display(),
+                io::Error::last_os_error()
+            );
+            return Ok(None);
+        }
+        log::debug!("{}: preallocated {} bytes", path.display(), len);
+    }
+    return Ok(Some(Reservation {
+        path: path.to_path_buf(),
+        file,
+        input_len,
+    }));
+}
+
+impl Reservation {
+    /// Releases the space reserved beyond what was written, once the output
+    /// has been flushed, and logs the ratio of this run.
+    pub fn release(self) -> io::Result<()> {
+        let len = self.file.metadata()?.len();
+        // Truncating to the current size frees the blocks past the end.
+        self.file.set_len(len)?;
+        if self.input_len > 0 {
+            log::info!(
+                "{}: {} bytes, {:.4} times the input (for --preallocate)",
+                self.path.display(),
+                len,
+                len as f64 / self.input_len as f64
+            );
+        }
+        return Ok(());
+    }
+}
@@ -0,0 +1,123 @@
+//! `--progress`: how far a run has got, logged every `INTERVAL`: lines
+//! read, rows written and rejects, the rate, and the bytes of the input
+//! files read so far.  The bytes are counted before decompression, so that
+//! against the size of the files they give the share of the input done and
+//! an estimate of the time left; neither is known when reading stdin.
+//!
+//! With `-v` and without `--progress`, the same lines are logged at debug
+//! level.
+
+use crate::rusage::human_bytes;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct Progress {
+    /// Bytes of the input files read so far.
+    read: Arc<AtomicU64>,
+    /// The size of the input files, unless one is stdin.
+    total: Option<u64>,
+    level: log::Level,
+    t0: Instant,
+    last: Instant,
+}
+
+/// A reader that adds the bytes read through it to a count.
+pub struct CountingReader<R> {
+    inner: R,
+    read: Arc<AtomicU64>,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R, read: Arc<AtomicU64>) -> CountingReader<R> {
+        return CountingReader { inner, read };
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.fetch_add(n as u64, Ordering::Relaxed);
+        return Ok(n);
+    }
+}
+
+/// `d` to the second, as `1h02m03s`, `2m03s` or `3s`.
+fn human_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        return format!("{}h{:02}m{:02}s", h, m, s);
+    }
+    if m > 0 {
+        return format!("{}m{:02}s", m, s);
+    }
+    return format!("{}s", s);
+}
+
+impl Progress {
+    /// Reports on the reading of `input_files`, at info level when `info`
+    /// is set (--progress) and at debug level otherwise.
+    pub fn new(input_files: &[&Path], info: bool) -> Progress {
+        let mut total = Some(0);
+        for input in input_files {
+            if *input == Path::new(crate::STDIN) {
+                total = None;
+                break;
+            }
+            // An unreadable file fails the run as soon as it is opened.
+            total = total.map(|total| total + fs::metadata(input).map_or(0, |m| m.len()));
+        }
+        let now = Instant::now();
+        return Progress {
+            read: Arc::new(AtomicU64::new(0)),
+            total,
+            level: if info {
+                log::Level::Info
+            } else {
+                log::Level::Debug
+            },
+            t0: now,
+            last: now,
+        };
+    }
+
+    /// The count for a `CountingReader` over each input file.
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        return self.read.clone();
+    }
+
+    pub fn due(&self) -> bool {
+        return self.last.elapsed() >= INTERVAL;
+    }
+
+    pub fn report(&mut self, lines: u64, rows: u64, rejected: u64) {
+        self.last = Instant::now();
+        let elapsed = self.t0.elapsed();
+        let read = self.read.load(Ordering::Relaxed);
+        let mut msg = format!(
+            "{} lines read, {} rows written, {} rejected ({:.0} lines/s), {} of input read",
+            lines,
+            rows,
+            rejected,
+            lines as f64 / elapsed.as_secs_f64(),
+            human_bytes(read)
+        );
+        if let Some(total) = self.total.filter(|&total| total > 0 && read > 0) {
+            let done = read.min(total) as f64 / total as f64;
+            let left = elapsed.mul_f64((1.0 - done) / done);
+            msg.push_str(&format!(
+                " ({:.1}% of {}), about {} left",
+                100.0 * done,
+                human_bytes(total),
+                human_duration(left)
+            ));
+        }
+        log::log!(self.level, "{}", msg);
+    }
+}
@@ -0,0 +1,335 @@
+//! The suffix rules, and a handle for swapping them while they are in use.
+
+use crate::compiled;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::iter::FromIterator;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The header comment that gives the version of the published list, such
+/// as `// VERSION: 2026-09-30_12-34-56_UTC`.
+const VERSION_PREFIX: &str = "// VERSION:";
+
+/// The comments that open the sections of the published list; rules before
+/// either are taken to be ICANN ones.
+const BEGIN_ICANN: &str = "// ===BEGIN ICANN DOMAINS===";
+const BEGIN_PRIVATE: &str = "// ===BEGIN PRIVATE DOMAINS===";
+
+/// A set of public suffix rules.  It is never modified once built, so it
+/// can be shared between threads as an `Arc<Psl>`.
+///
+/// Besides the rules as written, the names under wildcard rules (`ck` for
+/// `*.ck`) and the names of exception rules (`www.ck` for `!www.ck`) are
+/// kept apart, so that matching a host needs no allocation.  The rules from
+/// the PRIVATE section of the list (`blogspot.com`) are remembered, so that
+/// they can be dropped with [`Psl::icann_only`].
+#[derive(Clone, Debug, Default)]
+pub struct Psl {
+    rules: HashSet<String>,
+    wildcards: HashSet<String>,
+    exceptions: HashSet<String>,
+    private: HashSet<String>,
+    version: Option<String>,
+}
+
+/// Days since the Unix epoch of a date in the proleptic Gregorian calendar
+/// (Howard Hinnant's algorithm).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    return era * 146097 + doe - 719468;
+}
+
+/// Reads the `YYYY-MM-DD` date and the optional `_HH-MM-SS` time at the
+/// start of a list version, taken to be UTC.
+fn parse_version_time(version: &str) -> Option<SystemTime> {
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let s = version.get(range)?;
+        if !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        return s.parse().ok();
+    };
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let mut secs = days_from_civil(year, month, day) * 86400;
+    if let (Some(h), Some(m), Some(s)) = (number(11..13), number(14..16), number(17..19)) {
+        secs += h * 3600 + m * 60 + s;
+    }
+    return UNIX_EPOCH.checked_add(Duration::from_secs(u64::try_from(secs).ok()?));
+}
+
+impl Psl {
+    /// Reads rules in the Public Suffix List format: one per line, with
+    /// blank lines and `//` comments ignored, except for the `VERSION`
+    /// header and the section markers of the published list.
+    pub fn parse<R: BufRead>(rdr: R) -> io::Result<Psl> {
+        let mut rules: HashSet<String> = HashSet::with_capacity(4096);
+        let mut private = HashSet::new();
+        let mut in_private = false;
+        let mut version = None;
+        for line in rdr.lines() {
+            let line = line?;
+            if let Some(v) = line.strip_prefix(VERSION_PREFIX) {
+                if version.is_none() {
+                    version = Some(v.trim().to_string());
+                }
+                continue;
+            }
+            if line.starts_with(BEGIN_ICANN) {
+                in_private = false;
+            } else if line.starts_with(BEGIN_PRIVATE) {
+                in_private = true;
+            }
+            if line.trim().is_empty() || line.starts_with("//") {
+                continue;
+            }
+            if in_private {
+                private.insert(line.clone());
+            }
+            rules.insert(line);
+        }
+        return Ok(Psl::from_rules(rules)
+            .with_private(private)
+            .with_version(version));
+    }
+
+    fn from_rules(rules: HashSet<String>) -> Psl {
+        let mut wildcards = HashSet::new();
+        let mut exceptions = HashSet::new();
+        for rule in &rules {
+            if let Some(parent) = rule.strip_prefix("*.") {
+                wildcards.insert(parent.to_string());
+            } else if let Some(name) = rule.strip_prefix('!') {
+                exceptions.insert(name.to_string());
+            }
+        }
+        return Psl {
+            rules,
+            wildcards,
+            exceptions,
+            private: HashSet::new(),
+            version: None,
+        };
+    }
+
+    /// Reads `path`, which may hold either the text list or a compiled
+    /// one.
+    pub fn from_file(path: &Path) -> io::Result<Psl> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        if bytes.starts_with(compiled::MAGIC) {
+            return compiled::read(&mut &bytes[..]);
+        }
+        return Psl::parse(&bytes[..]);
+    }
+
+    /// Writes the rules in the binary format described in [`compiled`].
+    pub fn write_compiled<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        return compiled::write(self, out);
+    }
+
+    /// Reads rules written by [`Psl::write_compiled`].
+    pub fn read_compiled<R: Read>(rdr: &mut R) -> io::Result<Psl> {
+        return compiled::read(rdr);
+    }
+
+    pub fn contains(&self, suffix: &str) -> bool {
+        return self.rules.contains(suffix);
+    }
+
+    /// Whether there is a wildcard rule `*.parent`, making every name
+    /// directly under `parent` a suffix.
+    pub fn has_wildcard(&self, parent: &str) -> bool {
+        return !self.wildcards.is_empty() && self.wildcards.contains(parent);
+    }
+
+    /// Whether there is an exception rule `!name`, making `name` a
+    /// registrable domain despite a wildcard.
+    pub fn has_exception(&self, name: &str) -> bool {
+        return !self.exceptions.is_empty() && self.exceptions.contains(name);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.rules.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.rules.is_empty();
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &str> {
+        return self.rules.iter().map(String::as_str);
+    }
+
+    /// The version of the published list the rules came from, if known.
+    pub fn version(&self) -> Option<&str> {
+        return self.version.as_deref();
+    }
+
+    /// When the list was published, if its version says.
+    pub fn published(&self) -> Option<SystemTime> {
+        return self.version.as_deref().and_then(parse_version_time);
+    }
+
+    /// Sets the version of the published list the rules came from.
+    pub fn with_version(mut self, version: Option<String>) -> Psl {
+        self.version = version;
+        return self;
+    }
+
+    /// Whether `rule` came from the PRIVATE section of the list.
+    pub fn is_private(&self, rule: &str) -> bool {
+        return !self.private.is_empty() && self.private.contains(rule);
+    }
+
+    /// Marks the given rules as coming from the PRIVATE section; those that
+    /// are not rules of the list are ignored.
+    pub fn with_private<I, S>(mut self, rules: I) -> Psl
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        for rule in rules {
+            let rule = rule.into();
+            if self.rules.contains(&rule) {
+                self.private.insert(rule);
+            }
+        }
+        return self;
+    }
+
+    /// Adds the ASCII (punycode) form of each internationalized rule, so
+    /// that `xn--fiqs8s` matches as well as `中国`.  Rules that do not
+    /// convert are left as they are.
+    pub fn with_ascii_rules(self) -> Psl {
+        let mut rules = self.rules.clone();
+        let mut private = Vec::new();
+        for rule in self.rules.iter().filter(|r| !r.is_ascii()) {
+            let (marker, name) = match rule.strip_prefix("*.") {
+                Some(name) => ("*.", name),
+                None => match rule.strip_prefix('!') {
+                    Some(name) => ("!", name),
+                    None => ("", rule.as_str()),
+                },
+            };
+            if let Ok(ascii) = idna::domain_to_ascii(name) {
+                let ascii = format!("{}{}", marker, ascii);
+                if self.private.contains(rule) {
+                    private.push(ascii.clone());
+                }
+                rules.insert(ascii);
+            }
+        }
+        private.extend(self.private);
+        return Psl::from_rules(rules)
+            .with_private(private)
+            .with_version(self.version);
+    }
+
+    /// The rules without those from the PRIVATE section, so that names such
+    /// as `example.blogspot.com` extract to `blogspot` under `com`.
+    pub fn icann_only(self) -> Psl {
+        let private = self.private;
+        let rules = self.rules.into_iter().filter(|r| !private.contains(r));
+        return Psl::from_rules(rules.collect()).with_version(self.version);
+    }
+}
+
+impl<S: Into<String>> FromIterator<S> for Psl {
+    fn from_iter<I: IntoIterator<Item = S>>(rules: I) -> Psl {
+        return Psl::from_rules(rules.into_iter().map(Into::into).collect());
+    }
+}
+
+/// The current rules, replaceable while extractions are running.
+///
+/// Each extraction (or batch of them) takes a snapshot with [`load`]; a
+/// [`store`] swaps in new rules for later snapshots without waiting for, or
+/// affecting, the ones already taken.  The lock is only held long enough to
+/// clone or replace an `Arc`.
+///
+/// [`load`]: SharedPsl::load
+/// [`store`]: SharedPsl::store
+#[derive(Debug, Default)]
+pub struct SharedPsl {
+    current: RwLock<Arc<Psl>>,
+}
+
+impl SharedPsl {
+    pub fn new(psl: Psl) -> SharedPsl {
+        return SharedPsl {
+            current: RwLock::new(Arc::new(psl)),
+        };
+    }
+
+    /// Returns a snapshot of the current rules.
+    pub fn load(&self) -> Arc<Psl> {
+        // A panic while holding the lock cannot leave the Arc half-written.
+        let current = self.current.read().unwrap_or_else(|e| e.into_inner());
+        return Arc::clone(&current);
+    }
+
+    /// Replaces the rules, returning the previous ones.
+    pub fn store(&self, psl: Psl) -> Arc<Psl> {
+        let psl = Arc::new(psl);
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        return std::mem::replace(&mut *current, psl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> Option<SystemTime> {
+        return Some(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+
+    #[test]
+    fn version_times() {
+        assert_eq!(parse_version_time("1970-01-01"), at(0));
+        assert_eq!(parse_version_time("2024-03-01"), at(1709251200));
+        assert_eq!(
+            parse_version_time("2024-03-01_12-30-15_UTC"),
+            at(1709296215)
+        );
+        // A leap day, and a time that is only partly there.
+        assert_eq!(parse_version_time("2024-02-29"), at(1709164800));
+        assert_eq!(parse_version_time("2024-02-29_12"), at(1709164800));
+        for bad in [
+            "",
+            "2024",
+            "2024-13-01",
+            "2024-00-10",
+            "2024-01-32",
+            "24-01-01",
+            "2024-0a-01",
+            "1969-12-31",
+        ] {
+            assert_eq!(parse_version_time(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn published_from_the_header() {
+        let psl =
+            Psl::parse(&b"// VERSION: 2024-03-01_12-30-15_UTC\n// VERSION: 2000-01-01\ncom\n"[..])
+                .unwrap();
+        assert_eq!(psl.version(), Some("2024-03-01_12-30-15_UTC"));
+        assert_eq!(psl.published(), at(1709296215));
+        let psl = Psl::parse(&b"com\n"[..]).unwrap();
+        assert_eq!(psl.version(), None);
+        assert_eq!(psl.published(), None);
+    }
+}
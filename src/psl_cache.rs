@@ -0,0 +1,121 @@
+//! `--psl-update`: a copy of the Public Suffix List fetched from
+//! publicsuffix.org and kept in the cache directory, used when no
+//! TLD_DATA_FILE is given.
+//!
+//! The cache is `$XDG_CACHE_HOME/vfb-tldextract`, or `~/.cache/vfb-tldextract`
+//! when that is not set.  A download replaces the cached copy only once it
+//! has been read in full and parsed, so a failed update leaves the previous
+//! list in place.  Fetching needs a build with the `psl-update` feature;
+//! a list cached by another build is used either way.
+
+use std::fs::{self, File};
+#[cfg(feature = "psl-update")]
+use std::io::Read;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use vfb_tldextract::Psl;
+
+const URL: &str = "https://publicsuffix.org/list/public_suffix_list.dat";
+const FILE_NAME: &str = "public_suffix_list.dat";
+
+/// The published list is a few hundred KiB; anything far larger is not it.
+#[cfg(feature = "psl-update")]
+const MAX_SIZE: u64 = 16 << 20;
+
+/// A list with fewer rules than this is taken to be truncated or an error
+/// page, rather than replacing a good copy.
+const MIN_RULES: usize = 1000;
+
+fn cache_dir() -> anyhow::Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()) {
+        return Ok(PathBuf::from(dir).join(crate::PROG));
+    }
+    match std::env::var_os("HOME").filter(|d| !d.is_empty()) {
+        Some(home) => return Ok(PathBuf::from(home).join(".cache").join(crate::PROG)),
+        None => {
+            anyhow::bail!("neither XDG_CACHE_HOME nor HOME is set, so there is no cache directory")
+        }
+    }
+}
+
+/// Downloads the list, up to `MAX_SIZE` bytes.
+#[cfg(feature = "psl-update")]
+fn fetch() -> anyhow::Result<Vec<u8>> {
+    log::info!("fetching {}", URL);
+    let response = ureq::get(URL).call()?;
+    let mut bytes = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_SIZE + 1)
+        .read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > MAX_SIZE {
+        anyhow::bail!("{}: more than {} bytes, not a suffix list", URL, MAX_SIZE);
+    }
+    return Ok(bytes);
+}
+
+#[cfg(not(feature = "psl-update"))]
+fn fetch() -> anyhow::Result<Vec<u8>> {
+    anyhow::bail!(
+        "--psl-update needs a build with the psl-update feature; \
+         fetch {} and give it as TLD_DATA_FILE instead",
+        URL
+    );
+}
+
+/// The path of the cached list, which must exist.
+pub fn cached_list() -> anyhow::Result<PathBuf> {
+    let path = cache_dir()?.join(FILE_NAME);
+    if !path.exists() {
+        anyhow::bail!(
+            "no TLD_DATA_FILE given and no cached list at {}; fetch one with --psl-update",
+            path.display()
+        );
+    }
+    return Ok(path);
+}
+
+/// Fetches the list into the cache, unless the cached copy is younger than
+/// `max_age`.
+pub fn update(max_age: Option<Duration>) -> anyhow::Result<()> {
+    let dir = cache_dir()?;
+    let path = dir.join(FILE_NAME);
+    if let (Some(max_age), Ok(meta)) = (max_age, fs::metadata(&path)) {
+        let age = SystemTime::now()
+            .duration_since(meta.modified()?)
+            .unwrap_or_default();
+        if age < max_age {
+            log::info!(
+                "{} is {} hours old, not fetching",
+                path.display(),
+                age.as_secs() / 3600
+            );
+            return Ok(());
+        }
+    }
+
+    let bytes = fetch()?;
+    let psl = Psl::parse(&bytes[..])?;
+    if psl.len() < MIN_RULES {
+        anyhow::bail!(
+            "{}: only {} rules, not replacing the cached list",
+            URL,
+            psl.len()
+        );
+    }
+
+    fs::create_dir_all(&dir)?;
+    let tmp = dir.join(format!("{}.tmp", FILE_NAME));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, &path)?;
+    log::info!(
+        "saved {} rules ({}) to {}",
+        psl.len(),
+        psl.version().unwrap_or("no version"),
+        path.display()
+    );
+    return Ok(());
+}
@@ -0,0 +1,257 @@
+//! `--psl-diff` mode: what changes between two versions of the suffix list,
+//! and, given a sample of records, how many of them would be extracted
+//! differently.
+//!
+//! A rule is "changed" rather than added and removed when only its wildcard
+//! (`*.`) or exception (`!`) marker differs, as when `ck` becomes `*.ck`.
+//!
+//! Both lists are loaded as a normal run would load TLD_DATA_FILE, with
+//! --icann-only and --extra-psl applied to each, so that only the change of
+//! list shows.
+
+use crate::{compression, idn, load_psl, normalize_host, Cli, RdnsRecord};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use vfb_tldextract::{extract, ExtractResult, Psl};
+
+/// How many distinct example hosts are listed for the sample.
+const EXAMPLES: usize = 10;
+
+/// The name a rule applies to, without its wildcard or exception marker.
+pub fn rule_key(rule: &str) -> &str {
+    let rule = rule.strip_prefix('!').unwrap_or(rule);
+    return rule.strip_prefix("*.").unwrap_or(rule);
+}
+
+fn by_key(psl: &Psl) -> BTreeMap<&str, BTreeSet<&str>> {
+    let mut keys: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for rule in psl.rules() {
+        keys.entry(rule_key(rule)).or_default().insert(rule);
+    }
+    return keys;
+}
+
+fn describe(result: ExtractResult) -> String {
+    match result {
+        ExtractResult::Matched { domain, suffix, .. } => {
+            format!("domain {:?} suffix {:?}", domain, suffix)
+        }
+        ExtractResult::SuffixOnly { suffix } => format!("suffix-only {:?}", suffix),
+        ExtractResult::NoMatch => "no match".to_string(),
+        ExtractResult::Invalid(reason) => format!("invalid ({})", reason),
+    }
+}
+
+/// Loads `old` and `new` the same way, as a run would load TLD_DATA_FILE.
+pub fn load(old: &Path, new: &Path, args: &Cli) -> anyhow::Result<(Psl, Psl)> {
+    return Ok((load_psl(old, args)?, load_psl(new, args)?));
+}
+
+/// The rules added (`+`), removed (`-`) and changed (`~`) from `old` to
+/// `new`, one line each, removals and changes first.
+fn rule_changes(old: &Psl, new: &Psl) -> Vec<String> {
+    let (old_keys, new_keys) = (by_key(old), by_key(new));
+    let mut lines = Vec::new();
+    for (key, old_rules) in &old_keys {
+        match new_keys.get(key) {
+            None => {
+                for rule in old_rules {
+                    lines.push(format!("- {}", rule));
+                }
+            }
+            Some(new_rules) if new_rules != old_rules => {
+                let before: Vec<&str> = old_rules.iter().copied().collect();
+                let after: Vec<&str> = new_rules.iter().copied().collect();
+                lines.push(format!("~ {} -> {}", before.join(" "), after.join(" ")));
+            }
+            Some(_) => {}
+        }
+    }
+    for (key, new_rules) in &new_keys {
+        if !old_keys.contains_key(key) {
+            for rule in new_rules {
+                lines.push(format!("+ {}", rule));
+            }
+        }
+    }
+    return lines;
+}
+
+/// What the sample shows: how many records were read and how many of them
+/// would be extracted differently, with up to `EXAMPLES` distinct hosts.
+#[derive(Debug, Default, PartialEq)]
+struct Estimate {
+    num_records: u64,
+    num_changed: u64,
+    examples: Vec<String>,
+}
+
+/// Extracts the host of each record of `rdr` under `old` and `new`, which
+/// must already have their ASCII rules.  Hosts are normalized and converted
+/// to ASCII as a run would; records a run would reject are skipped, as the
+/// run reports them.
+fn estimate<R: BufRead>(rdr: R, old: &Psl, new: &Psl, args: &Cli) -> anyhow::Result<Estimate> {
+    let mut est = Estimate::default();
+    let mut shown = HashSet::new();
+    for line in rdr.lines() {
+        let line = line?;
+        let record: RdnsRecord = match serde_json::from_str(&line) {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+        let mut host = match normalize_host(&record.value, args.empty_labels) {
+            Ok(host) => host.into_owned(),
+            Err(_) => continue,
+        };
+        if !host.is_ascii() {
+            if let Some(form) = args.unicode_normalization {
+                host = idn::normalize(&host, form);
+            }
+            host = match idn::to_ascii(&host) {
+                Some(ascii) => ascii,
+                None => continue,
+            };
+        }
+        est.num_records += 1;
+        let (before, after) = (extract(&host, old), extract(&host, new));
+        if before != after {
+            est.num_changed += 1;
+            if est.examples.len() < EXAMPLES && !shown.contains(&host) {
+                est.examples.push(format!(
+                    "{}: {} -> {}",
+                    host,
+                    describe(before),
+                    describe(after)
+                ));
+                shown.insert(host);
+            }
+        }
+    }
+    return Ok(est);
+}
+
+pub fn run(old: &Psl, new: &Psl, sample: Option<&Path>, args: &Cli) -> anyhow::Result<()> {
+    let lines = rule_changes(old, new);
+    for line in &lines {
+        println!("{}", line);
+    }
+    let count = |prefix: &str| lines.iter().filter(|l| l.starts_with(prefix)).count();
+    println!(
+        "{} rules added, {} removed, {} changed",
+        count("+ "),
+        count("- "),
+        count("~ ")
+    );
+
+    if let Some(sample) = sample {
+        // Hosts are matched in ASCII form, as in a run.
+        let old = old.clone().with_ascii_rules();
+        let new = new.clone().with_ascii_rules();
+        let file = Box::new(File::open(sample)?);
+        let rdr = BufReader::new(compression::decoder(file, sample, args.compression)?);
+        let est = estimate(rdr, &old, &new, args)?;
+        for example in &est.examples {
+            println!("  {}", example);
+        }
+        let percent = if est.num_records > 0 {
+            100.0 * est.num_changed as f64 / est.num_records as f64
+        } else {
+            0.0
+        };
+        println!(
+            "sample {}: {} of {} records ({:.2}%) would be extracted differently",
+            sample.display(),
+            est.num_changed,
+            est.num_records,
+            percent
+        );
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROG;
+    use clap::Parser;
+
+    fn psl(rules: &str) -> Psl {
+        return Psl::parse(rules.as_bytes()).unwrap();
+    }
+
+    fn args(flags: &[&str]) -> Cli {
+        let mut argv = vec![PROG, "psl.dat", "-", "rejected"];
+        argv.extend_from_slice(flags);
+        return Cli::try_parse_from(argv).unwrap();
+    }
+
+    fn record(value: &str) -> String {
+        return format!(
+            "{{\"name\":\"192.0.2.1\",\"value\":{:?},\"type\":\"ptr\"}}\n",
+            value
+        );
+    }
+
+    #[test]
+    fn rules_added_removed_and_changed() {
+        let old = psl("com\nck\nio\n");
+        let new = psl("com\n*.ck\nnet\n");
+        assert_eq!(
+            rule_changes(&old, &new),
+            vec!["~ ck -> *.ck", "- io", "+ net"]
+        );
+        assert!(rule_changes(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn both_lists_load_alike() {
+        let dir = std::env::temp_dir();
+        let old_path = dir.join(format!(".{}-psl-diff-old.{}", PROG, std::process::id()));
+        let new_path = dir.join(format!(".{}-psl-diff-new.{}", PROG, std::process::id()));
+        let private = "// ===BEGIN PRIVATE DOMAINS===\nblogspot.com\n";
+        std::fs::write(&old_path, format!("com\n{}", private)).unwrap();
+        std::fs::write(&new_path, format!("com\nnet\n{}", private)).unwrap();
+        let loaded = load(&old_path, &new_path, &args(&["--icann-only"]));
+        std::fs::remove_file(&old_path).unwrap();
+        std::fs::remove_file(&new_path).unwrap();
+        let (old, new) = loaded.unwrap();
+        // The PRIVATE rule is dropped from both lists, not reported removed.
+        assert_eq!(rule_changes(&old, &new), vec!["+ net"]);
+    }
+
+    #[test]
+    fn sample_examples_are_distinct() {
+        let old = psl("com\n").with_ascii_rules();
+        let new = psl("com\nblogspot.com\n").with_ascii_rules();
+        let input = [
+            record("a.blogspot.com"),
+            record("A.BLOGSPOT.COM."),
+            record("b.blogspot.com"),
+            record("example.com"),
+            "not json\n".to_string(),
+        ]
+        .concat();
+        let est = estimate(input.as_bytes(), &old, &new, &args(&[])).unwrap();
+        assert_eq!(est.num_records, 4);
+        assert_eq!(est.num_changed, 3);
+        assert_eq!(
+            est.examples,
+            vec![
+                "a.blogspot.com: domain \"blogspot\" suffix \"com\" -> domain \"a\" suffix \"blogspot.com\"",
+                "b.blogspot.com: domain \"blogspot\" suffix \"com\" -> domain \"b\" suffix \"blogspot.com\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn sample_hosts_match_in_ascii() {
+        let old = psl("com\n").with_ascii_rules();
+        let new = psl("com\n中国\n").with_ascii_rules();
+        let est = estimate(record("例子.中国").as_bytes(), &old, &new, &args(&[])).unwrap();
+        assert_eq!(est.num_records, 1);
+        assert_eq!(est.num_changed, 1);
+        assert!(est.examples[0].starts_with("xn--fsqu00a.xn--fiqs8s: no match -> domain"));
+    }
+}
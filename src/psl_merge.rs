@@ -0,0 +1,132 @@
+//! `--extra-psl`: suffix files merged over TLD_DATA_FILE, such as internal
+//! additions to the upstream list, each taking precedence over the files
+//! before it.
+//!
+//! Files conflict over a name when their rules for it differ only in the
+//! wildcard or exception marker (`ck` in one, `*.ck` in the other, as in
+//! `--psl-diff`); the later file's rules replace the earlier ones, and each
+//! override is reported with the files involved.  The merged list keeps the
+//! version of TLD_DATA_FILE, the upstream list whose age matters, and which
+//! of each file's rules were PRIVATE ones.
+
+use crate::psl_diff::rule_key;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use vfb_tldextract::Psl;
+
+pub fn merge(base: &Psl, base_path: &Path, extras: &[(&Path, Psl)]) -> Psl {
+    // The rules for each name, and the file they came from.
+    let mut by_key: BTreeMap<&str, (BTreeSet<&str>, &Path)> = BTreeMap::new();
+    for rule in base.rules() {
+        let entry = by_key
+            .entry(rule_key(rule))
+            .or_insert_with(|| (BTreeSet::new(), base_path));
+        entry.0.insert(rule);
+    }
+    for (path, extra) in extras {
+        let mut rules: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+        for rule in extra.rules() {
+            rules.entry(rule_key(rule)).or_default().insert(rule);
+        }
+        let (mut added, mut present, mut overriding) = (0, 0, 0);
+        for (key, rules) in rules {
+            match by_key.get(key) {
+                None => added += rules.len(),
+                Some((old, _)) if *old == rules => present += rules.len(),
+                Some((old, source)) => {
+                    let before: Vec<&str> = old.iter().copied().collect();
+                    let after: Vec<&str> = rules.iter().copied().collect();
+                    log::warn!(
+                        "{}: {} overrides {} from {}",
+                        path.display(),
+                        after.join(" "),
+                        before.join(" "),
+                        source.display()
+                    );
+                    overriding += 1;
+                }
+            }
+            by_key.insert(key, (rules, path));
+        }
+        log::info!(
+            "{}: {} rules, {} new, {} already present, {} overriding earlier rules",
+            path.display(),
+            extra.len(),
+            added,
+            present,
+            overriding
+        );
+    }
+    let merged: Psl = by_key
+        .values()
+        .flat_map(|(rules, _)| rules.iter().copied())
+        .collect();
+    let private = std::iter::once(base)
+        .chain(extras.iter().map(|(_, extra)| extra))
+        .flat_map(|psl| psl.rules().filter(move |rule| psl.is_private(rule)));
+    return merged
+        .with_private(private)
+        .with_version(base.version().map(str::to_string));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn psl(rules: &str) -> Psl {
+        return Psl::parse(rules.as_bytes()).unwrap();
+    }
+
+    fn rules(psl: &Psl) -> Vec<&str> {
+        let mut rules: Vec<&str> = psl.rules().collect();
+        rules.sort_unstable();
+        return rules;
+    }
+
+    #[test]
+    fn extras_add_rules() {
+        let base = psl("// VERSION: 2024-03-01\ncom\nuk\nco.uk\n");
+        let extra = psl("corp.example\ncom\n");
+        let merged = merge(
+            &base,
+            Path::new("base.dat"),
+            &[(Path::new("extra.dat"), extra)],
+        );
+        assert_eq!(rules(&merged), vec!["co.uk", "com", "corp.example", "uk"]);
+        // The version of the base list is kept.
+        assert_eq!(merged.version(), Some("2024-03-01"));
+    }
+
+    #[test]
+    fn later_files_take_precedence() {
+        let base = psl("ck\n*.er\n!www.er\n");
+        let first = psl("*.ck\n!www.ck\n");
+        let second = psl("ck\ner\n");
+        let merged = merge(
+            &base,
+            Path::new("base.dat"),
+            &[
+                (Path::new("first.dat"), first),
+                (Path::new("second.dat"), second),
+            ],
+        );
+        // Rules are replaced by name: `!www.er` is a rule for `www.er`.
+        assert_eq!(rules(&merged), vec!["!www.ck", "!www.er", "ck", "er"]);
+        assert!(!merged.has_wildcard("er"));
+    }
+
+    #[test]
+    fn private_rules_are_kept() {
+        let base = psl("com\n// ===BEGIN PRIVATE DOMAINS===\nblogspot.com\n");
+        let extra = psl("// ===BEGIN PRIVATE DOMAINS===\ncorp.example\n");
+        let merged = merge(
+            &base,
+            Path::new("base.dat"),
+            &[(Path::new("extra.dat"), extra)],
+        );
+        assert!(merged.is_private("blogspot.com"));
+        assert!(merged.is_private("corp.example"));
+        assert!(!merged.is_private("com"));
+        assert_eq!(rules(&merged.icann_only()), vec!["com"]);
+    }
+}
@@ -0,0 +1,145 @@
+//! Retrying reads and writes for files on flaky network storage.
+//!
+//! NFS and S3FS mounts sporadically fail a read or write with `EIO` or
+//! `ETIMEDOUT` even though the same call succeeds a moment later.  A failed
+//! `read(2)`/`write(2)` transfers nothing, so the call can simply be repeated.
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The longest the delay between retries grows to, unless the first
+/// delay is longer still.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    /// How many times a failed call is repeated before giving up.
+    pub retries: u32,
+    /// Delay before the first retry; it doubles with every further retry,
+    /// up to [`MAX_DELAY`].
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// The delay before the retry after one that waited `delay`.
+    fn next_delay(&self, delay: Duration) -> Duration {
+        return delay.saturating_mul(2).min(MAX_DELAY.max(self.delay));
+    }
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(code) => code == libc::EIO || code == libc::ETIMEDOUT || code == libc::EAGAIN,
+        None => e.kind() == io::ErrorKind::Interrupted,
+    }
+}
+
+/// Wraps a reader or writer, retrying calls that fail with a transient error
+/// according to `policy`.  Every retry is added to `count`.
+pub struct Retrying<T> {
+    inner: T,
+    policy: RetryPolicy,
+    count: Arc<AtomicU64>,
+}
+
+impl<T> Retrying<T> {
+    pub fn new(inner: T, policy: RetryPolicy, count: Arc<AtomicU64>) -> Retrying<T> {
+        return Retrying {
+            inner,
+            policy,
+            count,
+        };
+    }
+
+    fn with_retries<R>(&mut self, mut f: impl FnMut(&mut T) -> io::Result<R>) -> io::Result<R> {
+        let mut delay = self.policy.delay;
+        let mut attempt = 0;
+        loop {
+            match f(&mut self.inner) {
+                Err(e) if attempt < self.policy.retries && is_transient(&e) => {
+                    attempt += 1;
+                    self.count.fetch_add(1, Ordering::Relaxed);
+                    std::thread::sleep(delay);
+                    delay = self.policy.next_delay(delay);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<T: Read> Read for Retrying<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.with_retries(|inner| inner.read(buf))
+    }
+}
+
+impl<T: Write> Write for Retrying<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.with_retries(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.with_retries(|inner| inner.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails its first `failures` reads with EIO.
+    struct Flaky {
+        failures: u32,
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+            buf[0] = b'x';
+            return Ok(1);
+        }
+    }
+
+    fn policy(retries: u32, delay: Duration) -> RetryPolicy {
+        return RetryPolicy { retries, delay };
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let p = policy(u32::MAX, Duration::from_millis(100));
+        assert_eq!(p.next_delay(p.delay), Duration::from_millis(200));
+        assert_eq!(p.next_delay(Duration::from_secs(20)), MAX_DELAY);
+        assert_eq!(p.next_delay(MAX_DELAY), MAX_DELAY);
+        // Doubling would overflow long before u32::MAX retries.
+        let mut delay = p.delay;
+        for _ in 0..200 {
+            delay = p.next_delay(delay);
+        }
+        assert_eq!(delay, MAX_DELAY);
+        assert_eq!(p.next_delay(Duration::MAX), MAX_DELAY);
+        // A first delay beyond the cap is kept.
+        let p = policy(3, Duration::from_secs(60));
+        assert_eq!(p.next_delay(p.delay), p.delay);
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let count = Arc::new(AtomicU64::new(0));
+        let flaky = Flaky { failures: 3 };
+        let mut rdr = Retrying::new(flaky, policy(3, Duration::ZERO), Arc::clone(&count));
+        assert_eq!(rdr.read(&mut [0; 1]).unwrap(), 1);
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+        let flaky = Flaky { failures: 3 };
+        let mut rdr = Retrying::new(flaky, policy(2, Duration::ZERO), Arc::clone(&count));
+        assert_eq!(
+            rdr.read(&mut [0; 1]).unwrap_err().raw_os_error(),
+            Some(libc::EIO)
+        );
+    }
+}
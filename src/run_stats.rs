@@ -0,0 +1,266 @@
+//! `--stats-json` writes the counts of a run as JSON, marked incomplete if
+//! the run stopped early, and `--report-diff` compares two of them, as when
+//! checking a new dataset release against the previous one.
+//! `--stats-stream` appends the same counts to a file every
+//! `--stats-interval` while the run goes on, one JSON object per line, so
+//! that the progress of a long run can be followed.  `--stats-by-tld`
+//! writes the rows by public suffix as a CSV table, ending in a comment
+//! line if the run stopped early.
+//!
+//! The report covers the volumes, the records matched by rules of each
+//! section of the list, the reject rate and its reasons, the records with
+//! undelegated TLDs (with `--iana-tlds`), and the suffixes whose share of
+//! the rows moved the most.
+
+use crate::fields::quoted;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How many suffixes are listed, and the smallest change in share of the
+/// rows (in percentage points) worth listing.
+const SUFFIXES: usize = 20;
+const MIN_SHIFT: f64 = 0.01;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunStats {
+    pub input: String,
+    pub lines: u64,
+    pub rows: u64,
+    pub rejected: u64,
+    #[serde(default)]
+    pub rejects_by_reason: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub suffix_only: u64,
+    /// Matched records by the section of their rule, and those no rule
+    /// matched.
+    #[serde(default)]
+    pub icann: u64,
+    #[serde(default)]
+    pub private: u64,
+    #[serde(default)]
+    pub unmatched: u64,
+    #[serde(default)]
+    pub rows_by_suffix: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub undelegated_tlds: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub duration_seconds: f64,
+    /// Whether the run read all of its input, in --stats-json files; those
+    /// written before it was recorded were all of complete runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub complete: Option<bool>,
+}
+
+/// Writes `stats` to `file`, created by the caller with the run's
+/// --output-mode and --group.
+pub fn write(file: File, stats: &RunStats) -> anyhow::Result<()> {
+    let mut out = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut out, stats)?;
+    writeln!(out)?;
+    out.flush()?;
+    return Ok(());
+}
+
+/// A line of the --stats-stream file: the counts so far, when they were
+/// taken, and whether they are those of the end of the run.
+#[derive(Serialize)]
+struct Snapshot<'a> {
+    time: String,
+    #[serde(rename = "final")]
+    done: bool,
+    #[serde(flatten)]
+    stats: &'a RunStats,
+}
+
+pub struct Stream {
+    file: File,
+    /// What the run reads, as in `RunStats::input`.
+    pub input: String,
+    pub started: Instant,
+    interval: Duration,
+    last: Instant,
+}
+
+impl Stream {
+    /// Appends to `file`, opened for appending by the caller, so that a
+    /// dashboard reading it sees the snapshots of every run.
+    pub fn new(file: File, input: String, interval: Duration) -> Stream {
+        let now = Instant::now();
+        return Stream {
+            file,
+            input,
+            started: now,
+            interval,
+            last: now,
+        };
+    }
+
+    /// Whether the interval has passed since the last snapshot.
+    pub fn due(&self) -> bool {
+        return self.last.elapsed() >= self.interval;
+    }
+
+    /// Appends a snapshot as a single write, so that a reader never sees
+    /// part of a line.
+    pub fn write(&mut self, stats: &RunStats, done: bool) -> io::Result<()> {
+        let snapshot = Snapshot {
+            time: crate::lineage::rfc3339(SystemTime::now()),
+            done,
+            stats,
+        };
+        let mut line = serde_json::to_vec(&snapshot)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.last = Instant::now();
+        return Ok(());
+    }
+}
+
+/// Writes `suffix,rows,percent` lines for the rows of each public suffix,
+/// most rows first.  When `stopped` says why the run stopped early, a last
+/// `# incomplete: ...` comment line says so.
+pub fn write_by_suffix(
+    file: File,
+    rows_by_suffix: &BTreeMap<String, u64>,
+    stopped: Option<&str>,
+) -> anyhow::Result<()> {
+    let total: u64 = rows_by_suffix.values().sum();
+    let mut suffixes: Vec<(&String, &u64)> = rows_by_suffix.iter().collect();
+    suffixes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    let mut out = BufWriter::new(file);
+    writeln!(out, "suffix,rows,percent")?;
+    for (suffix, &n) in suffixes {
+        writeln!(
+            out,
+            "{},{},{:.4}",
+            quoted(suffix, b','),
+            n,
+            percent(n, total)
+        )?;
+    }
+    if let Some(why) = stopped {
+        writeln!(out, "# incomplete: stopped {}", why)?;
+    }
+    out.flush()?;
+    return Ok(());
+}
+
+fn read(path: &Path) -> anyhow::Result<RunStats> {
+    let rdr = BufReader::new(File::open(path)?);
+    return match serde_json::from_reader(rdr) {
+        Ok(stats) => Ok(stats),
+        Err(e) => anyhow::bail!("{}: {}", path.display(), e),
+    };
+}
+
+fn percent(n: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    return 100.0 * n as f64 / total as f64;
+}
+
+/// The relative change from `a` to `b`, or nothing when `a` is zero.
+fn change(a: u64, b: u64) -> String {
+    if a == 0 {
+        return String::new();
+    }
+    return format!("  ({:+.1}%)", 100.0 * (b as f64 - a as f64) / a as f64);
+}
+
+fn count_line(name: &str, a: u64, b: u64) {
+    println!("{:<16}{:>14} -> {:<14}{}", name, a, b, change(a, b));
+}
+
+fn share_line(name: &str, a: f64, b: f64) {
+    let (a_pct, b_pct) = (format!("{:.2}%", a), format!("{:.2}%", b));
+    println!(
+        "{:<16}{:>14} -> {:<14}  ({:+.2} pp)",
+        name,
+        a_pct,
+        b_pct,
+        b - a
+    );
+}
+
+pub fn diff(a_path: &Path, b_path: &Path) -> anyhow::Result<()> {
+    let (a, b) = (read(a_path)?, read(b_path)?);
+    let incomplete = |stats: &RunStats| match stats.complete {
+        Some(false) => ", incomplete",
+        _ => "",
+    };
+    println!("A: {} ({}{})", a_path.display(), a.input, incomplete(&a));
+    println!("B: {} ({}{})", b_path.display(), b.input, incomplete(&b));
+    println!();
+    count_line("lines", a.lines, b.lines);
+    count_line("rows", a.rows, b.rows);
+    count_line("rejected", a.rejected, b.rejected);
+    count_line("suffix-only", a.suffix_only, b.suffix_only);
+    count_line("icann", a.icann, b.icann);
+    count_line("private", a.private, b.private);
+    count_line("unmatched", a.unmatched, b.unmatched);
+    let (a_rate, b_rate) = (percent(a.rejected, a.lines), percent(b.rejected, b.lines));
+    share_line("reject rate", a_rate, b_rate);
+
+    let reasons: BTreeSet<&String> = a
+        .rejects_by_reason
+        .keys()
+        .chain(b.rejects_by_reason.keys())
+        .collect();
+    if !reasons.is_empty() {
+        println!();
+        println!("rejects by reason:");
+        for reason in reasons {
+            let a_n = a.rejects_by_reason.get(reason).copied().unwrap_or(0);
+            let b_n = b.rejects_by_reason.get(reason).copied().unwrap_or(0);
+            count_line(&format!("  {}", reason), a_n, b_n);
+        }
+    }
+
+    let tlds: BTreeSet<&String> = a
+        .undelegated_tlds
+        .keys()
+        .chain(b.undelegated_tlds.keys())
+        .collect();
+    if !tlds.is_empty() {
+        println!();
+        println!("records by undelegated TLD:");
+        for tld in tlds {
+            let a_n = a.undelegated_tlds.get(tld).copied().unwrap_or(0);
+            let b_n = b.undelegated_tlds.get(tld).copied().unwrap_or(0);
+            count_line(&format!("  {}", tld), a_n, b_n);
+        }
+    }
+
+    let suffixes: BTreeSet<&String> = a
+        .rows_by_suffix
+        .keys()
+        .chain(b.rows_by_suffix.keys())
+        .collect();
+    let mut shifts: Vec<(&String, f64, f64)> = suffixes
+        .into_iter()
+        .map(|suffix| {
+            let a_n = a.rows_by_suffix.get(suffix).copied().unwrap_or(0);
+            let b_n = b.rows_by_suffix.get(suffix).copied().unwrap_or(0);
+            (suffix, percent(a_n, a.rows), percent(b_n, b.rows))
+        })
+        .filter(|(_, a_share, b_share)| (b_share - a_share).abs() >= MIN_SHIFT)
+        .collect();
+    if !shifts.is_empty() {
+        shifts.sort_by(|x, y| (y.2 - y.1).abs().total_cmp(&(x.2 - x.1).abs()));
+        println!();
+        println!("largest shifts in share of rows:");
+        for (suffix, a_share, b_share) in shifts.iter().take(SUFFIXES) {
+            share_line(&format!("  {}", suffix), *a_share, *b_share);
+        }
+        if shifts.len() > SUFFIXES {
+            println!("  ... and {} more", shifts.len() - SUFFIXES);
+        }
+    }
+    return Ok(());
+}
@@ -0,0 +1,68 @@
+//! Resource usage of the whole run, from `getrusage(2)`, reported at the
+//! end so that the containers these jobs run in can be sized from it.
+
+use std::io;
+use std::time::Duration;
+
+/// Block counts from `getrusage` are in units of 512 bytes.
+const BLOCK_SIZE: u64 = 512;
+
+pub struct Usage {
+    /// Peak resident set size, in bytes.
+    pub max_rss: u64,
+    pub user: Duration,
+    pub system: Duration,
+    /// Bytes read from and written to storage (not the page cache).
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+fn duration(tv: libc::timeval) -> Duration {
+    return Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64);
+}
+
+/// Returns the resource usage of this process so far.
+pub fn current() -> io::Result<Usage> {
+    let mut ru: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut ru) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    return Ok(Usage {
+        // Linux reports the peak RSS in kilobytes.
+        max_rss: ru.ru_maxrss as u64 * 1024,
+        user: duration(ru.ru_utime),
+        system: duration(ru.ru_stime),
+        read_bytes: ru.ru_inblock as u64 * BLOCK_SIZE,
+        write_bytes: ru.ru_oublock as u64 * BLOCK_SIZE,
+    });
+}
+
+/// Formats a byte count with a binary unit, e.g. `1.5 MiB`.
+pub fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        return format!("{} B", n);
+    }
+    return format!("{:.1} {}", value, UNITS[unit]);
+}
+
+/// Logs the resource usage of the run so far.
+pub fn report() {
+    match current() {
+        Ok(usage) => log::info!(
+            "peak RSS {}, {:.2}s user and {:.2}s system CPU, {} read and {} written",
+            human_bytes(usage.max_rss),
+            usage.user.as_secs_f64(),
+            usage.system.as_secs_f64(),
+            human_bytes(usage.read_bytes),
+            human_bytes(usage.write_bytes)
+        ),
+        Err(e) => log::warn!("cannot read resource usage: {}", e),
+    }
+}
@@ -0,0 +1,218 @@
+//! Sampling of the output records.
+//!
+//! `--sample-by` keeps the records of a deterministic fraction of keys.
+//! Whether a key is kept depends only on a hash of the key, so every record
+//! of a kept domain is kept, and the same input always gives the same
+//! sample, across runs and machines.
+//!
+//! `--sample-per-suffix` keeps at most N records per public suffix, so that
+//! `com` does not crowd out everything else.
+
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum SampleKey {
+    /// The registrable domain, e.g. `example.co.uk`.
+    Domain,
+    /// The whole host.
+    Host,
+    /// The IP address.
+    Ip,
+}
+
+pub struct SampleBy {
+    pub key: SampleKey,
+    fraction: f64,
+}
+
+impl SampleBy {
+    /// Parses the `KEY FRACTION` values of `--sample-by`.
+    pub fn parse(values: &[String]) -> anyhow::Result<SampleBy> {
+        let (key, fraction) = match values {
+            [key, fraction] => (key, fraction),
+            _ => anyhow::bail!("--sample-by takes a key and a fraction"),
+        };
+        let key = match SampleKey::from_str(key, false) {
+            Ok(key) => key,
+            Err(_) => anyhow::bail!("unknown --sample-by key {:?} (domain, host or ip)", key),
+        };
+        let fraction: f64 = match fraction.parse() {
+            Ok(f) if f > 0.0 && f <= 1.0 => f,
+            _ => anyhow::bail!("--sample-by fraction {:?} is not in (0, 1]", fraction),
+        };
+        return Ok(SampleBy { key, fraction });
+    }
+
+    /// Returns whether the records of the key made of `parts` (concatenated)
+    /// are kept.
+    fn keeps(&self, parts: &[&[u8]]) -> bool {
+        let hash = hash(parts);
+        // The top 53 bits, as a uniform fraction in [0, 1).
+        let unit = (hash >> 11) as f64 / (1u64 << 53) as f64;
+        return unit < self.fraction;
+    }
+
+    /// Returns whether the record for `host` and `addr` is kept; `label` is
+    /// the label of the registrable domain under `suffix`, and is empty for
+    /// a suffix-only host.
+    pub fn keeps_record(&self, host: &str, label: &str, suffix: &str, addr: IpAddr) -> bool {
+        match self.key {
+            SampleKey::Domain if label.is_empty() => self.keeps(&[suffix.as_bytes()]),
+            SampleKey::Domain => self.keeps(&[label.as_bytes(), b".", suffix.as_bytes()]),
+            SampleKey::Host => self.keeps(&[host.as_bytes()]),
+            SampleKey::Ip => match addr {
+                IpAddr::V4(a) => self.keeps(&[&a.octets()]),
+                IpAddr::V6(a) => self.keeps(&[&a.octets()]),
+            },
+        }
+    }
+}
+
+/// The number of records kept so far for each suffix, for
+/// `--sample-per-suffix`.
+pub struct PerSuffix {
+    limit: u64,
+    counts: HashMap<String, u64>,
+}
+
+impl PerSuffix {
+    pub fn new(limit: u64) -> PerSuffix {
+        return PerSuffix {
+            limit,
+            counts: HashMap::new(),
+        };
+    }
+
+    /// Returns whether a record under `suffix` is kept, counting it if so.
+    pub fn keeps(&mut self, suffix: &str) -> bool {
+        // Only allocate the first time a suffix is seen.
+        let count = match self.counts.get_mut(suffix) {
+            Some(count) => count,
+            None => self.counts.entry(suffix.to_string()).or_insert(0),
+        };
+        if *count >= self.limit {
+            return false;
+        }
+        *count += 1;
+        return true;
+    }
+}
+
+/// FNV-1a over `parts`, then the splitmix64 finalizer so that similar keys
+/// (`a.com`, `b.com`) land far apart.  Fixed, unlike `std`'s hasher, so
+/// that samples are reproducible.
+fn hash(parts: &[&[u8]]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for &b in *part {
+            h ^= b as u64;
+            h = h.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    return h ^ (h >> 31);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_by(key: &str, fraction: &str) -> anyhow::Result<SampleBy> {
+        return SampleBy::parse(&[key.to_string(), fraction.to_string()]);
+    }
+
+    #[test]
+    fn parse_values() {
+        assert!(sample_by("domain", "0.5").is_ok());
+        assert!(sample_by("ip", "1").is_ok());
+        assert!(SampleBy::parse(&["domain".to_string()]).is_err());
+        for (key, fraction) in [
+            ("domain", "0"),
+            ("domain", "1.5"),
+            ("domain", "-0.1"),
+            ("domain", "x"),
+            ("label", "0.5"),
+        ] {
+            assert!(sample_by(key, fraction).is_err(), "{} {}", key, fraction);
+        }
+    }
+
+    #[test]
+    fn hash_is_fixed() {
+        // Samples must not change between releases or machines.
+        assert_eq!(hash(&[b"example.com"]), hash(&[b"example", b".", b"com"]));
+        assert_eq!(hash(&[b"example.com"]), 0x4d07_e1c6_cc59_7ab7);
+        assert_ne!(hash(&[b"a.com"]), hash(&[b"b.com"]));
+    }
+
+    #[test]
+    fn domains_are_kept_whole() {
+        let by = sample_by("domain", "0.5").unwrap();
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "2001:db8::1".parse().unwrap();
+        for i in 0..200 {
+            let label = format!("example{}", i);
+            let kept = by.keeps_record(&format!("www.{}.com", label), &label, "com", addr);
+            assert_eq!(
+                kept,
+                by.keeps_record(&format!("mail.{}.com", label), &label, "com", other)
+            );
+        }
+        // A suffix-only host is keyed by its suffix.
+        assert_eq!(
+            by.keeps_record("co.uk", "", "co.uk", addr),
+            by.keeps(&[b"co.uk"])
+        );
+    }
+
+    #[test]
+    fn fraction_is_roughly_kept() {
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        for (fraction, low, high) in [("0.1", 800, 1200), ("0.5", 4700, 5300), ("1", 10000, 10000)]
+        {
+            let by = sample_by("host", fraction).unwrap();
+            let kept = (0..10000)
+                .filter(|i| by.keeps_record(&format!("h{}.example.com", i), "example", "com", addr))
+                .count();
+            assert!(low <= kept && kept <= high, "{}: {}", fraction, kept);
+        }
+    }
+
+    #[test]
+    fn ips_are_sampled_by_address() {
+        let by = sample_by("ip", "0.5").unwrap();
+        let mut kept = 0;
+        for i in 0..=255u8 {
+            let addr = IpAddr::from([192, 0, 2, i]);
+            let first = by.keeps_record("a.example.com", "example", "com", addr);
+            assert_eq!(
+                first,
+                by.keeps_record("b.example.net", "example", "net", addr)
+            );
+            kept += first as u32;
+        }
+        assert!(0 < kept && kept < 256);
+    }
+
+    #[test]
+    fn per_suffix_limit() {
+        let mut per_suffix = PerSuffix::new(2);
+        assert!(per_suffix.keeps("com"));
+        assert!(per_suffix.keeps("co.uk"));
+        assert!(per_suffix.keeps("com"));
+        assert!(!per_suffix.keeps("com"));
+        assert!(!per_suffix.keeps("com"));
+        assert!(per_suffix.keeps("co.uk"));
+        assert!(!per_suffix.keeps("co.uk"));
+        assert_eq!(per_suffix.counts["com"], 2);
+    }
+
+    #[test]
+    fn per_suffix_zero_keeps_nothing() {
+        let mut per_suffix = PerSuffix::new(0);
+        assert!(!per_suffix.keeps("com"));
+    }
+}
@@ -0,0 +1,70 @@
+//! `--selftest`: a check that a build works where it is deployed, with
+//! nothing but the binary and its list: the list loads and verifies, and
+//! names under rules of each kind, which any published list has, extract
+//! as they should.  The features the binary was built with are listed, so
+//! that an offline build can be seen to have no network code.
+
+use vfb_tldextract::{extract_domain, Psl};
+
+/// Hosts and their registrable domains: a plain rule, a rule of two
+/// labels, a wildcard rule (`*.kawasaki.jp`), an exception to it and a
+/// host that is a suffix itself.
+const CASES: &[(&str, Option<&str>)] = &[
+    ("www.example.com", Some("example.com")),
+    ("www.example.co.uk", Some("example.co.uk")),
+    ("a.b.kawasaki.jp", Some("a.b.kawasaki.jp")),
+    ("www.city.kawasaki.jp", Some("city.kawasaki.jp")),
+    ("co.uk", None),
+];
+
+const FEATURES: &[(&str, bool)] = &[
+    ("psl-update", cfg!(feature = "psl-update")),
+    ("trace-spans", cfg!(feature = "trace-spans")),
+    ("embedded-psl", cfg!(feature = "embedded-psl")),
+    ("state-db", cfg!(feature = "state-db")),
+    ("zstd", cfg!(feature = "zstd")),
+    ("xz", cfg!(feature = "xz")),
+    ("bzip2", cfg!(feature = "bzip2")),
+    ("parquet", cfg!(feature = "parquet")),
+    ("otlp", cfg!(feature = "otlp")),
+];
+
+pub fn run(tld_set: &Psl, source: &str) -> anyhow::Result<()> {
+    let features: Vec<&str> = FEATURES
+        .iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| *name)
+        .collect();
+    println!(
+        "features: {}",
+        if features.is_empty() {
+            "none".to_string()
+        } else {
+            features.join(" ")
+        }
+    );
+    println!(
+        "list: {}, {} rules, version {}",
+        source,
+        tld_set.len(),
+        tld_set.version().unwrap_or("unknown")
+    );
+    let mut failed = 0;
+    for &(host, expected) in CASES {
+        let got = extract_domain(host, tld_set);
+        if got != expected {
+            println!(
+                "  {}: got {}, expected {}",
+                host,
+                got.unwrap_or("no domain"),
+                expected.unwrap_or("no domain")
+            );
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("selftest failed: {} of {} cases", failed, CASES.len());
+    }
+    println!("selftest passed: {} cases", CASES.len());
+    return Ok(());
+}
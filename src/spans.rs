@@ -0,0 +1,64 @@
+//! `--trace-spans`: time the phases of the pipeline (read, decompress,
+//! parse, match, enrich, write) with `tracing` spans, written as a Chrome
+//! trace that chrome://tracing, Perfetto or a flamegraph tool can load.
+//!
+//! Most spans cover a single record, so traces of whole datasets get large;
+//! a sample of a few hundred thousand records is usually enough.
+//!
+//! Writing the trace needs a build with the `trace-spans` feature; without
+//! it the spans are still entered, at next to no cost.
+
+#[cfg(feature = "trace-spans")]
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+#[cfg(feature = "trace-spans")]
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+#[cfg(feature = "trace-spans")]
+use tracing_subscriber::prelude::*;
+
+/// Starts writing spans to `path`.  The trace is complete once the returned
+/// guard is dropped.
+#[cfg(feature = "trace-spans")]
+pub fn init(path: &Path) -> anyhow::Result<FlushGuard> {
+    let (layer, guard) = ChromeLayerBuilder::new()
+        .writer(File::create(path)?)
+        .include_locations(false)
+        .build();
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))?;
+    return Ok(guard);
+}
+
+#[cfg(not(feature = "trace-spans"))]
+pub fn init(_path: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("--trace-spans needs a build with the trace-spans feature");
+}
+
+#[derive(Clone, Copy)]
+pub enum Phase {
+    Read,
+    Decompress,
+}
+
+/// A reader whose reads are each timed as `phase`.
+pub struct PhaseReader<R> {
+    inner: R,
+    phase: Phase,
+}
+
+impl<R: Read> PhaseReader<R> {
+    pub fn new(inner: R, phase: Phase) -> PhaseReader<R> {
+        return PhaseReader { inner, phase };
+    }
+}
+
+impl<R: Read> Read for PhaseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Span names must be known at compile time.
+        let _span = match self.phase {
+            Phase::Read => tracing::info_span!("read").entered(),
+            Phase::Decompress => tracing::info_span!("decompress").entered(),
+        };
+        return self.inner.read(buf);
+    }
+}
@@ -0,0 +1,136 @@
+//! `--split-by-type`: separate outputs for PTR records, CNAME records and
+//! everything else, written in a single pass.
+//!
+//! `ptr.csv` has the usual `ip,domain` rows, `cname.csv` has `alias,domain`
+//! rows with the domain of the alias target, and `other.json` has the
+//! records of any other type exactly as read.
+
+use crate::RdnsRecord;
+use std::io::{self, BufWriter, Write};
+
+pub const PTR_FILE: &str = "ptr.csv";
+pub const CNAME_FILE: &str = "cname.csv";
+pub const OTHER_FILE: &str = "other.json";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RecordKind {
+    Ptr,
+    Cname,
+    Other,
+}
+
+/// Records without a type are taken to be PTR records, as they always
+/// have been.
+pub fn kind(record: &RdnsRecord) -> RecordKind {
+    if record.kind.is_empty() || record.kind.eq_ignore_ascii_case("ptr") {
+        return RecordKind::Ptr;
+    }
+    if record.kind.eq_ignore_ascii_case("cname") {
+        return RecordKind::Cname;
+    }
+    return RecordKind::Other;
+}
+
+/// The outputs besides the PTR rows.
+pub struct Outputs {
+    pub cname: BufWriter<Box<dyn Write>>,
+    pub other: BufWriter<Box<dyn Write>>,
+}
+
+impl Outputs {
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.cname.flush()?;
+        return self.other.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{process, Cli, PROG};
+    use clap::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use vfb_tldextract::Psl;
+
+    /// A writer whose bytes can still be read once it is boxed.
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            return self.0.borrow_mut().write(buf);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    impl Shared {
+        fn text(&self) -> String {
+            return String::from_utf8(self.0.borrow().clone()).unwrap();
+        }
+    }
+
+    fn record_kind(kind: &str) -> RecordKind {
+        let line = format!("{{\"name\":\"a\",\"value\":\"b\",\"type\":{:?}}}", kind);
+        return super::kind(&serde_json::from_str(&line).unwrap());
+    }
+
+    #[test]
+    fn kinds() {
+        assert!(record_kind("ptr") == RecordKind::Ptr);
+        assert!(record_kind("PTR") == RecordKind::Ptr);
+        assert!(record_kind("") == RecordKind::Ptr);
+        assert!(record_kind("cname") == RecordKind::Cname);
+        assert!(record_kind("CNAME") == RecordKind::Cname);
+        assert!(record_kind("a") == RecordKind::Other);
+        let untyped = serde_json::from_str(r#"{"name":"a","value":"b"}"#).unwrap();
+        assert!(super::kind(&untyped) == RecordKind::Ptr);
+    }
+
+    #[test]
+    fn records_go_to_their_outputs() {
+        let args =
+            Cli::try_parse_from([PROG, "psl.dat", "-", "rejected", "--split-by-type", "dir"])
+                .unwrap();
+        let psl = Psl::parse(&b"com\n"[..]).unwrap();
+        let (cname, other) = (Shared::default(), Shared::default());
+        let mut outputs = Outputs {
+            cname: BufWriter::new(Box::new(cname.clone())),
+            other: BufWriter::new(Box::new(other.clone())),
+        };
+        let input = concat!(
+            "{\"name\":\"192.0.2.1\",\"value\":\"www.example.com\",\"type\":\"ptr\"}\n",
+            "{\"name\":\"shop.example.org\",\"value\":\"Shops.Host.COM\",\"type\":\"cname\"}\n",
+            "{\"name\":\"example.com\",\"value\":\"192.0.2.1\",\"type\":\"a\"}\n",
+        );
+        let (mut out, mut rejected) = (Vec::new(), Vec::new());
+        let stats = process(
+            &mut input.as_bytes(),
+            &mut out,
+            &mut rejected,
+            &psl,
+            None,
+            Some(&mut outputs),
+            None,
+            None,
+            None,
+            None,
+            None,
+            0,
+            &args,
+        )
+        .unwrap();
+        outputs.flush().unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "3221225985,example\n");
+        assert_eq!(cname.text(), "shop.example.org,host\n");
+        assert_eq!(
+            other.text(),
+            "{\"name\":\"example.com\",\"value\":\"192.0.2.1\",\"type\":\"a\"}\n"
+        );
+        assert!(rejected.is_empty());
+        assert_eq!((stats.num_cname_rows, stats.num_other_records), (1, 1));
+    }
+}
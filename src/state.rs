@@ -0,0 +1,568 @@
+//! `--state-db`: the `(ip, registrable domain)` pairs seen by earlier runs,
+//! so that a run only emits the pairs that are new.  This turns a series of full
+//! dataset snapshots into an incremental feed.  With `--cdc`, every pair is
+//! emitted as an `add` or a `refresh`, and the pairs the run did not see are
+//! emitted as a `remove` and dropped from the database.  `--expire` bounds
+//! the database instead by dropping only the pairs not seen for a while.
+//!
+//! The database is a sled tree keyed by the four octets of the address
+//! followed by the registrable domain, e.g. `example.co.uk`, so that an
+//! address that moves from `example.com` to `example.net` is a new pair.  The value holds the first and last times (Unix
+//! seconds) the pair was seen and the number of the last run that saw it;
+//! runs are numbered in a separate tree, so that two runs within the same
+//! second are still told apart.  The `by-domain` tree indexes the same pairs
+//! the other way round, keyed by the domain, a NUL and the octets, with the
+//! hosts seen for the pair (up to [`MAX_HOSTS`]) one per line as the value.
+//!
+//! What a run sees is staged in a [`Batch`], a tree of its own, and only
+//! written to the database once the run's output is complete and in place,
+//! so that a run that fails or stops early leaves the database as it was
+//! and the pairs it saw are emitted again by the next run.  The batch
+//! being committed is named in the `runs` tree until it is done, so that
+//! a commit cut short is finished when the database is next opened.
+//! It needs a build with the `state-db` feature.
+//!
+//! Databases written before pairs were keyed by the registrable domain
+//! were keyed by its label (`example`) alone, which cannot be told apart
+//! from the registrable domains once the suffix is lost.  Their `runs`
+//! tree has no `version`, and they are refused with a message to start a
+//! new database; the first run with the new one emits every pair.
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+#[cfg(feature = "state-db")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// How many hosts are kept per pair in the `by-domain` tree, so that a
+/// wildcard record cannot grow a value without bound.
+#[cfg(feature = "state-db")]
+const MAX_HOSTS: usize = 64;
+
+/// The prefix of the names of batch trees, and the `runs` key naming the
+/// batch being committed.
+#[cfg(feature = "state-db")]
+const BATCH_PREFIX: &[u8] = b"batch.";
+#[cfg(feature = "state-db")]
+const COMMITTING: &[u8] = b"committing";
+
+/// The `runs` key holding the version of the database's keys, and the
+/// version written: 2 for keys by registrable domain.
+#[cfg(feature = "state-db")]
+const VERSION_KEY: &[u8] = b"version";
+#[cfg(feature = "state-db")]
+const VERSION: u64 = 2;
+
+/// What a batch key stands for, as its first byte: a pair and its value, a
+/// `by-domain` entry and its hosts, or a pair to remove.
+#[cfg(feature = "state-db")]
+const STAGED_PAIR: u8 = b'p';
+#[cfg(feature = "state-db")]
+const STAGED_HOSTS: u8 = b'h';
+#[cfg(feature = "state-db")]
+const STAGED_REMOVAL: u8 = b'r';
+
+#[cfg(feature = "state-db")]
+pub struct StateDb {
+    db: sled::Db,
+    by_domain: sled::Tree,
+    runs: sled::Tree,
+    /// The time recorded for every pair seen by this run.
+    now: u64,
+    run: u64,
+    /// Batches begun by this run, which number them.
+    batches: AtomicU64,
+}
+
+/// The pairs seen by one run of the processing, and the pairs it removes,
+/// until they are committed to the database.  A batch dropped without
+/// being committed is discarded.
+#[cfg(feature = "state-db")]
+pub struct Batch<'a> {
+    state: &'a StateDb,
+    tree: sled::Tree,
+    committed: bool,
+}
+
+/// Without the feature there is no database to begin a batch on.
+#[cfg(not(feature = "state-db"))]
+pub enum Batch {}
+
+/// Without the feature there is no way to open a database, so no value of
+/// this type can exist.
+#[cfg(not(feature = "state-db"))]
+pub enum StateDb {}
+
+#[cfg(feature = "state-db")]
+fn key(addr: Ipv4Addr, domain: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(4 + domain.len());
+    key.extend_from_slice(&addr.octets());
+    key.extend_from_slice(domain.as_bytes());
+    return key;
+}
+
+#[cfg(feature = "state-db")]
+fn reverse_key(addr: Ipv4Addr, domain: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(domain.len() + 5);
+    key.extend_from_slice(domain.as_bytes());
+    key.push(0);
+    key.extend_from_slice(&addr.octets());
+    return key;
+}
+
+#[cfg(feature = "state-db")]
+fn staged(tag: u8, key: &[u8]) -> Vec<u8> {
+    let mut staged = Vec::with_capacity(1 + key.len());
+    staged.push(tag);
+    staged.extend_from_slice(key);
+    return staged;
+}
+
+/// The address and domain of a pair key.
+#[cfg(feature = "state-db")]
+fn split_key(key: &[u8]) -> anyhow::Result<(Ipv4Addr, std::borrow::Cow<'_, str>)> {
+    if key.len() < 4 {
+        anyhow::bail!("malformed key in the state database: {:?}", key);
+    }
+    let addr = Ipv4Addr::new(key[0], key[1], key[2], key[3]);
+    return Ok((addr, String::from_utf8_lossy(&key[4..])));
+}
+
+#[cfg(feature = "state-db")]
+fn value(first_seen: u64, last_seen: u64, run: u64) -> [u8; 24] {
+    let mut value = [0u8; 24];
+    value[..8].copy_from_slice(&first_seen.to_be_bytes());
+    value[8..16].copy_from_slice(&last_seen.to_be_bytes());
+    value[16..].copy_from_slice(&run.to_be_bytes());
+    return value;
+}
+
+/// Reads the `i`th 64-bit field of a value; values written before runs were
+/// numbered have no third field, which reads as run 0.
+#[cfg(feature = "state-db")]
+fn field(value: &[u8], i: usize) -> Option<u64> {
+    let mut field = [0u8; 8];
+    field.copy_from_slice(value.get(8 * i..8 * (i + 1))?);
+    return Some(u64::from_be_bytes(field));
+}
+
+#[cfg(feature = "state-db")]
+impl StateDb {
+    /// Opens (or creates) the database at `path`; pairs seen by this run are
+    /// recorded as seen at `now`.
+    pub fn open(path: &Path, now: u64) -> anyhow::Result<StateDb> {
+        let db = sled::open(path)?;
+        let runs = db.open_tree("runs")?;
+        match runs.get(VERSION_KEY)?.as_deref().and_then(|v| field(v, 0)) {
+            Some(VERSION) => {}
+            None if db.is_empty() => {
+                runs.insert(VERSION_KEY, &VERSION.to_be_bytes())?;
+            }
+            None => anyhow::bail!(
+                "{}: the database keys pairs by domain label, from before they \
+                 were keyed by registrable domain; remove it to start a new one",
+                path.display()
+            ),
+            Some(version) => anyhow::bail!(
+                "{}: the database is of version {}, not {}",
+                path.display(),
+                version,
+                VERSION
+            ),
+        }
+        let run = runs.update_and_fetch("last", |last| {
+            let last = last.and_then(|v| field(v, 0)).unwrap_or(0);
+            Some((last + 1).to_be_bytes().to_vec())
+        })?;
+        let run = run.as_deref().and_then(|v| field(v, 0)).unwrap_or(1);
+        let by_domain = db.open_tree("by-domain")?;
+        // A commit cut short is finished; batches of runs that did not get
+        // as far as committing are dropped.
+        if let Some(name) = runs.get(COMMITTING)? {
+            apply(&db, &by_domain, &db.open_tree(&name)?)?;
+            runs.remove(COMMITTING)?;
+        }
+        for name in db.tree_names() {
+            if name.starts_with(BATCH_PREFIX) {
+                db.drop_tree(&name)?;
+            }
+        }
+        return Ok(StateDb {
+            db,
+            by_domain,
+            runs,
+            now,
+            run,
+            batches: AtomicU64::new(0),
+        });
+    }
+
+    /// Starts staging what a run of the processing sees.
+    pub fn batch(&self) -> anyhow::Result<Batch<'_>> {
+        let n = self.batches.fetch_add(1, Ordering::Relaxed);
+        let mut name = BATCH_PREFIX.to_vec();
+        name.extend_from_slice(format!("{}.{}", self.run, n).as_bytes());
+        let tree = self.db.open_tree(name)?;
+        // Left over from a batch of the same name in a database restored
+        // from a copy.
+        tree.clear()?;
+        return Ok(Batch {
+            state: self,
+            tree,
+            committed: false,
+        });
+    }
+
+    /// The domains recorded for `addr`, in byte order.
+    pub fn domains(&self, addr: Ipv4Addr) -> anyhow::Result<Vec<String>> {
+        let mut domains = Vec::new();
+        for entry in self.db.scan_prefix(addr.octets()) {
+            let (key, _) = entry?;
+            domains.push(String::from_utf8_lossy(&key[4..]).into_owned());
+        }
+        return Ok(domains);
+    }
+
+    /// The addresses recorded for `domain`, in byte order, each with the
+    /// hosts it was seen through.
+    pub fn addrs(&self, domain: &str) -> anyhow::Result<Vec<(Ipv4Addr, Vec<String>)>> {
+        let mut prefix = domain.as_bytes().to_vec();
+        prefix.push(0);
+        let mut addrs = Vec::new();
+        for entry in self.by_domain.scan_prefix(&prefix) {
+            let (key, value) = entry?;
+            let octets = &key[prefix.len()..];
+            if octets.len() != 4 {
+                anyhow::bail!("malformed key in the state database: {:?}", key);
+            }
+            let addr = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+            let hosts = String::from_utf8_lossy(&value)
+                .split('\n')
+                .filter(|h| !h.is_empty())
+                .map(str::to_string)
+                .collect();
+            addrs.push((addr, hosts));
+        }
+        return Ok(addrs);
+    }
+}
+
+/// Writes the pairs and removals staged in `batch` to the database.
+/// Applying a batch again changes nothing.
+#[cfg(feature = "state-db")]
+fn apply(db: &sled::Db, by_domain: &sled::Tree, batch: &sled::Tree) -> anyhow::Result<()> {
+    for entry in batch.iter() {
+        let (key, value) = entry?;
+        match key.split_first() {
+            Some((&STAGED_PAIR, key)) => {
+                db.insert(key, value)?;
+            }
+            Some((&STAGED_HOSTS, key)) => {
+                by_domain.insert(key, value)?;
+            }
+            Some((&STAGED_REMOVAL, key)) => {
+                let (addr, domain) = split_key(key)?;
+                db.remove(key)?;
+                by_domain.remove(reverse_key(addr, &domain))?;
+            }
+            _ => anyhow::bail!("malformed key in a state database batch: {:?}", key),
+        }
+    }
+    return Ok(());
+}
+
+#[cfg(feature = "state-db")]
+impl Batch<'_> {
+    /// Records that `addr` maps to the registrable domain `domain` through
+    /// `host`, and returns
+    /// whether the pair is new: neither in the database nor seen before by
+    /// this batch.
+    pub fn see(&self, addr: Ipv4Addr, domain: &str, host: &str) -> anyhow::Result<bool> {
+        let state = self.state;
+        let key = key(addr, domain);
+        let pair = staged(STAGED_PAIR, &key);
+        let entry = staged(STAGED_HOSTS, &reverse_key(addr, domain));
+        // A pair is staged along with its hosts the first time the batch
+        // sees it, and only its hosts change after that.
+        let (new, staging, old_hosts) = match self.tree.get(&pair)? {
+            Some(_) => (false, false, self.tree.get(&entry)?),
+            None => {
+                let first = state.db.get(&key)?.and_then(|v| field(&v, 0));
+                let old_hosts = match first {
+                    Some(_) => state.by_domain.get(&entry[1..])?,
+                    None => None,
+                };
+                let value = value(first.unwrap_or(state.now), state.now, state.run);
+                self.tree.insert(pair, &value)?;
+                (first.is_none(), true, old_hosts)
+            }
+        };
+        let mut hosts = old_hosts.as_deref().unwrap_or(&[]).to_vec();
+        let known = hosts.split(|&b| b == b'\n').any(|h| h == host.as_bytes());
+        let full = hosts.split(|&b| b == b'\n').count() >= MAX_HOSTS;
+        if !known && !full {
+            if !hosts.is_empty() {
+                hosts.push(b'\n');
+            }
+            hosts.extend_from_slice(host.as_bytes());
+        } else if !staging {
+            return Ok(new);
+        }
+        self.tree.insert(entry, hosts)?;
+        return Ok(new);
+    }
+
+    /// Stages the removal of the pairs that this batch did not see or, with
+    /// `max_age`, that have not been seen for longer than that, passing
+    /// each to `emit` first, and returns how many there were.
+    pub fn sweep<F>(&self, max_age: Option<Duration>, mut emit: F) -> anyhow::Result<u64>
+    where
+        F: FnMut(Ipv4Addr, &str) -> io::Result<()>,
+    {
+        let state = self.state;
+        let cutoff = max_age.map(|age| state.now.saturating_sub(age.as_secs()));
+        let mut removed = 0;
+        for entry in state.db.iter() {
+            let (key, value) = entry?;
+            // Seen by this batch, and so just now.
+            if self.tree.contains_key(staged(STAGED_PAIR, &key))? {
+                continue;
+            }
+            let stale = match cutoff {
+                Some(cutoff) => field(&value, 1).unwrap_or(0) < cutoff,
+                None => field(&value, 2).unwrap_or(0) != state.run,
+            };
+            if !stale {
+                continue;
+            }
+            let (addr, domain) = split_key(&key)?;
+            emit(addr, &domain)?;
+            self.tree.insert(staged(STAGED_REMOVAL, &key), &[])?;
+            removed += 1;
+        }
+        return Ok(removed);
+    }
+
+    /// Writes what the batch staged to the database, once the output of
+    /// its run is complete and in place.
+    pub fn commit(mut self) -> anyhow::Result<()> {
+        let state = self.state;
+        state.runs.insert(COMMITTING, self.tree.name())?;
+        state.db.flush()?;
+        apply(&state.db, &state.by_domain, &self.tree)?;
+        state.runs.remove(COMMITTING)?;
+        state.db.drop_tree(self.tree.name())?;
+        state.db.flush()?;
+        self.committed = true;
+        return Ok(());
+    }
+}
+
+#[cfg(feature = "state-db")]
+impl Drop for Batch<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        if let Err(e) = self.state.db.drop_tree(self.tree.name()) {
+            log::warn!("cannot discard a state database batch: {}", e);
+        }
+    }
+}
+
+#[cfg(not(feature = "state-db"))]
+impl StateDb {
+    pub fn open(_path: &Path, _now: u64) -> anyhow::Result<StateDb> {
+        anyhow::bail!("--state-db needs a build with the state-db feature");
+    }
+
+    pub fn batch(&self) -> anyhow::Result<Batch> {
+        match *self {}
+    }
+
+    pub fn addrs(&self, _domain: &str) -> anyhow::Result<Vec<(Ipv4Addr, Vec<String>)>> {
+        match *self {}
+    }
+
+    pub fn domains(&self, _addr: Ipv4Addr) -> anyhow::Result<Vec<String>> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "state-db"))]
+impl Batch {
+    pub fn see(&self, _addr: Ipv4Addr, _domain: &str, _host: &str) -> anyhow::Result<bool> {
+        match *self {}
+    }
+
+    pub fn sweep<F>(&self, _max_age: Option<Duration>, _emit: F) -> anyhow::Result<u64>
+    where
+        F: FnMut(Ipv4Addr, &str) -> io::Result<()>,
+    {
+        match *self {}
+    }
+
+    pub fn commit(self) -> anyhow::Result<()> {
+        match self {}
+    }
+}
+
+#[cfg(all(test, feature = "state-db"))]
+impl StateDb {
+    /// Opens the database as [`StateDb::open`] does, once a handle just
+    /// dropped has let go of its lock, which sled does from a thread of
+    /// its own some time after.
+    pub fn open_when_free(path: &Path, now: u64) -> anyhow::Result<StateDb> {
+        for _ in 0..100 {
+            match StateDb::open(path, now) {
+                // The lock error wraps the EWOULDBLOCK of flock(2) in an
+                // I/O error of another kind.
+                Err(e) if matches!(e.downcast_ref::<sled::Error>(), Some(sled::Error::Io(_))) => {
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                result => return result,
+            }
+        }
+        return StateDb::open(path, now);
+    }
+}
+
+#[cfg(all(test, feature = "state-db"))]
+mod tests {
+    use super::StateDb;
+    use std::net::Ipv4Addr;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    const A: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const B: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+    const DAY: u64 = 86400;
+
+    /// A database directory of its own for each test, removed when done.
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> TempDb {
+            let path = std::env::temp_dir().join(format!(
+                ".{}-state-test.{}.{}",
+                crate::PROG,
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            return TempDb(path);
+        }
+
+        /// Runs `f` as one committed run at `now`.
+        fn run<F>(&self, now: u64, f: F)
+        where
+            F: FnOnce(&super::Batch),
+        {
+            let state = StateDb::open_when_free(&self.0, now).unwrap();
+            let batch = state.batch().unwrap();
+            f(&batch);
+            batch.commit().unwrap();
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn new_pairs() {
+        let db = TempDb::new("new");
+        db.run(DAY, |batch| {
+            assert!(batch.see(A, "example.com", "www.example.com").unwrap());
+            assert!(!batch.see(A, "example.com", "mail.example.com").unwrap());
+            assert!(batch.see(B, "example.com", "example.com").unwrap());
+        });
+        db.run(2 * DAY, |batch| {
+            assert!(!batch.see(A, "example.com", "www.example.com").unwrap());
+            // The same label under another suffix is another pair.
+            assert!(batch.see(A, "example.net", "www.example.net").unwrap());
+        });
+        let state = StateDb::open_when_free(&db.0, 3 * DAY).unwrap();
+        assert_eq!(state.domains(A).unwrap(), ["example.com", "example.net"]);
+        let addrs = state.addrs("example.com").unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0].0, A);
+        assert_eq!(addrs[0].1, ["www.example.com", "mail.example.com"]);
+        assert!(state.addrs("example").unwrap().is_empty());
+    }
+
+    #[test]
+    fn uncommitted_batches_are_discarded() {
+        let db = TempDb::new("uncommitted");
+        {
+            let state = StateDb::open_when_free(&db.0, DAY).unwrap();
+            let batch = state.batch().unwrap();
+            assert!(batch.see(A, "example.com", "example.com").unwrap());
+        }
+        db.run(2 * DAY, |batch| {
+            assert!(batch.see(A, "example.com", "example.com").unwrap());
+        });
+    }
+
+    #[test]
+    fn sweep_removes_unseen_pairs() {
+        let db = TempDb::new("sweep");
+        db.run(DAY, |batch| {
+            batch.see(A, "example.com", "example.com").unwrap();
+            batch.see(B, "example.net", "example.net").unwrap();
+        });
+        db.run(2 * DAY, |batch| {
+            batch.see(A, "example.com", "example.com").unwrap();
+            let mut removed = Vec::new();
+            let n = batch
+                .sweep(None, |addr, domain| {
+                    removed.push((addr, domain.to_string()));
+                    return Ok(());
+                })
+                .unwrap();
+            assert_eq!(n, 1);
+            assert_eq!(removed, [(B, "example.net".to_string())]);
+        });
+        let state = StateDb::open_when_free(&db.0, 3 * DAY).unwrap();
+        assert!(state.domains(B).unwrap().is_empty());
+        assert!(state.addrs("example.net").unwrap().is_empty());
+        assert_eq!(state.domains(A).unwrap(), ["example.com"]);
+    }
+
+    #[test]
+    fn sweep_expires_old_pairs() {
+        let db = TempDb::new("expire");
+        db.run(DAY, |batch| {
+            batch.see(A, "example.com", "example.com").unwrap();
+        });
+        db.run(5 * DAY, |batch| {
+            batch.see(B, "example.net", "example.net").unwrap();
+        });
+        // Seen 9 and 5 days before; only the first is older than a week.
+        db.run(10 * DAY, |batch| {
+            let n = batch
+                .sweep(Some(Duration::from_secs(7 * DAY)), |_, _| Ok(()))
+                .unwrap();
+            assert_eq!(n, 1);
+        });
+        let state = StateDb::open_when_free(&db.0, 11 * DAY).unwrap();
+        assert!(state.domains(A).unwrap().is_empty());
+        assert_eq!(state.domains(B).unwrap(), ["example.net"]);
+    }
+
+    #[test]
+    fn label_keyed_databases_are_refused() {
+        let db = TempDb::new("old");
+        {
+            let old = sled::open(&db.0).unwrap();
+            old.insert(b"\xc0\x00\x02\x01example", &[0u8; 24][..])
+                .unwrap();
+            old.flush().unwrap();
+        }
+        let e = StateDb::open_when_free(&db.0, DAY).err().unwrap();
+        assert!(e.to_string().contains("remove it"), "{}", e);
+    }
+}
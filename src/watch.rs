@@ -0,0 +1,184 @@
+//! `--watch` mode: process dataset files as they land in a directory.
+//!
+//! A file is picked up when it is closed after writing or renamed into the
+//! directory.  Uploaders should either write under a name starting with `.`
+//! and rename the file into place, or (with `--done-suffix`) create a marker
+//! file `NAME<SUFFIX>` once `NAME` is complete.  For each processed input,
+//! `NAME.csv` and `NAME.rejected` are written to the processed directory and
+//! the input is moved there as well.
+
+use crate::{check_distinct_paths, process_file, report, Cli};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::time::Instant;
+use vfb_tldextract::Psl;
+
+/// Size of the fixed part of `struct inotify_event`.
+const EVENT_HEADER_LEN: usize = 16;
+
+struct Inotify {
+    file: File,
+}
+
+impl Inotify {
+    /// Watches `dir` for files closed after writing or moved into it.
+    fn new(dir: &Path) -> io::Result<Inotify> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Take ownership right away so the descriptor is closed on error.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        let wd = unsafe {
+            libc::inotify_add_watch(fd, path.as_ptr(), libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO)
+        };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(Inotify { file });
+    }
+
+    /// Blocks until at least one event is available and returns the names
+    /// of the files the events refer to.
+    fn read_names(&mut self) -> io::Result<Vec<OsString>> {
+        let mut buf = [0u8; 4096];
+        let n = self.file.read(&mut buf)?;
+        let mut names = Vec::new();
+        let mut pos = 0;
+        while pos + EVENT_HEADER_LEN <= n {
+            let len =
+                u32::from_ne_bytes([buf[pos + 12], buf[pos + 13], buf[pos + 14], buf[pos + 15]])
+                    as usize;
+            let name = &buf[pos + EVENT_HEADER_LEN..pos + EVENT_HEADER_LEN + len];
+            // The name is padded with NULs to an alignment boundary.
+            let end = name.iter().position(|&b| b == 0).unwrap_or(len);
+            if end > 0 {
+                names.push(OsStr::from_bytes(&name[..end]).to_os_string());
+            }
+            pos += EVENT_HEADER_LEN + len;
+        }
+        return Ok(names);
+    }
+}
+
+/// Returns the name of the input that the arrival of `name` makes ready, if
+/// any.
+fn ready_input(name: &OsStr, done_suffix: Option<&str>) -> Option<OsString> {
+    let bytes = name.as_bytes();
+    if bytes.starts_with(b".") {
+        return None;
+    }
+    match done_suffix {
+        Some(suffix) => {
+            let stem = bytes.strip_suffix(suffix.as_bytes())?;
+            if stem.is_empty() {
+                return None;
+            }
+            return Some(OsString::from_vec(stem.to_vec()));
+        }
+        None => return Some(name.to_os_string()),
+    }
+}
+
+fn with_suffix(name: &OsStr, suffix: &str) -> OsString {
+    let mut s = name.to_os_string();
+    s.push(suffix);
+    return s;
+}
+
+/// Processes `dir/name` and moves it into `processed_dir`.  Failures are
+/// reported but do not stop the watch; a failed input stays where it is and
+/// is retried on the next start or the next event for it.
+fn handle(dir: &Path, processed_dir: &Path, name: &OsStr, tld_set: &Psl, args: &Cli) {
+    let input_name = match ready_input(name, args.done_suffix.as_deref()) {
+        Some(input_name) => input_name,
+        None => return,
+    };
+    let input = dir.join(&input_name);
+    if !input.is_file() {
+        return;
+    }
+    let output = processed_dir.join(with_suffix(&input_name, ".csv"));
+    let rejected = processed_dir.join(with_suffix(&input_name, ".rejected"));
+
+    let t0 = Instant::now();
+    let result = (|| -> anyhow::Result<()> {
+        check_distinct_paths(&[
+            ("input", &input),
+            ("output", &output),
+            ("rejected", &rejected),
+        ])?;
+        let batch = match &args.state {
+            Some(state) => Some(state.batch()?),
+            None => None,
+        };
+        let stats = process_file(
+            &[input.as_path()],
+            Some(&output),
+            &rejected,
+            tld_set,
+            None,
+            None,
+            batch.as_ref(),
+            args,
+        )?;
+        // Left in place to be processed again once there is room.
+        if let Some(why) = stats.stopped_early() {
+            anyhow::bail!("stopped {}; the output is incomplete", why);
+        }
+        if let Some(batch) = batch {
+            batch.commit()?;
+        }
+        fs::rename(&input, processed_dir.join(&input_name))?;
+        if let Some(suffix) = &args.done_suffix {
+            fs::remove_file(dir.join(with_suffix(&input_name, suffix)))?;
+        }
+        report(Some(&input), &stats, t0);
+        return Ok(());
+    })();
+    if let Err(e) = result {
+        log::error!("{}: {:#}", input.display(), e);
+    }
+}
+
+pub fn watch(dir: &Path, tld_set: &Psl, args: &Cli) -> anyhow::Result<()> {
+    let processed_dir = match &args.processed_dir {
+        Some(processed_dir) => processed_dir.clone(),
+        None => dir.join("processed"),
+    };
+    fs::create_dir_all(&processed_dir)?;
+    if crate::resolve_path(&processed_dir)? == crate::resolve_path(dir)? {
+        anyhow::bail!(
+            "processed directory must differ from the watched directory {:?}",
+            dir
+        );
+    }
+
+    // Start watching before scanning, so that files arriving during the
+    // scan are not missed.
+    let mut inotify = Inotify::new(dir)?;
+
+    // Files that landed while we were not running.
+    let mut names: Vec<OsString> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            names.push(entry.file_name());
+        }
+    }
+    names.sort();
+    for name in &names {
+        handle(dir, &processed_dir, name, tld_set, args);
+    }
+
+    loop {
+        for name in inotify.read_names()? {
+            handle(dir, &processed_dir, &name, tld_set, args);
+        }
+    }
+}